@@ -0,0 +1,240 @@
+use crate::log;
+use crate::source::{BlockSource, Height, SourceBlock, SourceError};
+use bitcoin::block::Header;
+use bitcoin::consensus::deserialize;
+use bitcoin::constants::genesis_block;
+use bitcoin::{Block, BlockHash, Network, Work};
+use futures::stream::{BoxStream, StreamExt};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::task;
+
+// Every block record in a blk file is prefixed by the network magic and the block size
+const RECORD_HEADER_SIZE: u64 = 8;
+const BLOCK_HEADER_SIZE: usize = 80;
+
+#[derive(Clone, Copy)]
+struct BlockPos {
+    hash: BlockHash,
+    file_index: usize,
+    offset: u64,
+    size: u32,
+}
+
+struct BlkFiles {
+    paths: Vec<PathBuf>,
+    // bitcoin-core 28+ obfuscates blk files with the key of xor.dat, older ones have none
+    xor_key: [u8; 8],
+}
+
+impl BlkFiles {
+    fn read_raw_at(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+
+    fn unmask(&self, offset: u64, buf: &mut [u8]) {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte ^= self.xor_key[((offset + i as u64) % 8) as usize];
+        }
+    }
+
+    fn read_at(&self, file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        Self::read_raw_at(file, offset, buf)?;
+        self.unmask(offset, buf);
+        Ok(())
+    }
+
+    fn read_block(&self, pos: &BlockPos) -> io::Result<Block> {
+        let mut file = File::open(&self.paths[pos.file_index])?;
+        let mut buf = vec![0u8; pos.size as usize];
+        self.read_at(&mut file, pos.offset, &mut buf)?;
+        deserialize(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn read_chain_block(
+        &self,
+        chain: &[BlockPos],
+        height: Height,
+    ) -> Result<SourceBlock, SourceError> {
+        let pos = chain
+            .get(height as usize)
+            .ok_or(SourceError::MissingBlock(height))?;
+        let block = self.read_block(pos)?;
+        Ok(SourceBlock {
+            height,
+            hash: pos.hash,
+            block,
+        })
+    }
+
+    // Headers of all blocks in the file, blocks are stored in the order they were received
+    fn scan_file(&self, file_index: usize, magic: [u8; 4]) -> io::Result<Vec<(Header, BlockPos)>> {
+        let mut file = File::open(&self.paths[file_index])?;
+        let file_size = file.metadata()?.len();
+        let mut headers = Vec::new();
+        let mut offset = 0;
+        while offset + RECORD_HEADER_SIZE + BLOCK_HEADER_SIZE as u64 <= file_size {
+            let mut record_header = [0u8; RECORD_HEADER_SIZE as usize];
+            Self::read_raw_at(&mut file, offset, &mut record_header)?;
+            // files are preallocated, the zeroed tail has no blocks yet and is not obfuscated
+            if record_header[..4] == [0u8; 4] {
+                break;
+            }
+            self.unmask(offset, &mut record_header);
+            if record_header[..4] != magic {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Invalid magic in {} @ {}",
+                        self.paths[file_index].display(),
+                        offset
+                    ),
+                ));
+            }
+            let size = u32::from_le_bytes(record_header[4..].try_into().unwrap());
+            let mut header_bytes = [0u8; BLOCK_HEADER_SIZE];
+            self.read_at(&mut file, offset + RECORD_HEADER_SIZE, &mut header_bytes)?;
+            let header: Header = deserialize(&header_bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let pos = BlockPos {
+                hash: header.block_hash(),
+                file_index,
+                offset: offset + RECORD_HEADER_SIZE,
+                size,
+            };
+            headers.push((header, pos));
+            offset += RECORD_HEADER_SIZE + size as u64;
+        }
+        Ok(headers)
+    }
+}
+
+// Reads blocks straight from the blk*.dat files of bitcoin-core, which need not be running
+pub struct BlkReader {
+    files: Arc<BlkFiles>,
+    chain: Arc<Vec<BlockPos>>,
+}
+
+impl BlkReader {
+    pub fn open(blocks_dir: &str, network: Network) -> io::Result<Self> {
+        let dir = Path::new(blocks_dir);
+        let xor_path = dir.join("xor.dat");
+        let xor_key = if xor_path.exists() {
+            fs::read(&xor_path)?.try_into().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "xor.dat is not 8 bytes long")
+            })?
+        } else {
+            [0u8; 8]
+        };
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<_>>()?;
+        paths.retain(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("blk") && name.ends_with(".dat"))
+        });
+        paths.sort();
+        let files = BlkFiles { paths, xor_key };
+        log!("Scanning {} blk files in {}", files.paths.len(), blocks_dir);
+
+        let magic = network.magic().to_bytes();
+        let mut blocks: HashMap<BlockHash, (BlockPos, Work)> = HashMap::new();
+        let mut children: HashMap<BlockHash, Vec<BlockHash>> = HashMap::new();
+        for file_index in 0..files.paths.len() {
+            for (header, pos) in files.scan_file(file_index, magic)? {
+                children
+                    .entry(header.prev_blockhash)
+                    .or_default()
+                    .push(pos.hash);
+                blocks.insert(pos.hash, (pos, header.work()));
+            }
+            if (file_index + 1) % 100 == 0 {
+                log!("Scanned {} blk files", file_index + 1);
+            }
+        }
+        let chain = Self::best_chain(genesis_block(network).block_hash(), &blocks, &children);
+        log!(
+            "Found {} blocks, {} of them on the best chain",
+            blocks.len(),
+            chain.len()
+        );
+        Ok(BlkReader {
+            files: Arc::new(files),
+            chain: Arc::new(chain),
+        })
+    }
+
+    // Blocks are out of order in the files and include stale ones, the branch from genesis with the
+    // most cumulative work wins as it does for bitcoin-core
+    fn best_chain(
+        genesis_hash: BlockHash,
+        blocks: &HashMap<BlockHash, (BlockPos, Work)>,
+        children: &HashMap<BlockHash, Vec<BlockHash>>,
+    ) -> Vec<BlockPos> {
+        let Some((_, genesis_work)) = blocks.get(&genesis_hash) else {
+            return Vec::new();
+        };
+        let mut parents: HashMap<BlockHash, BlockHash> = HashMap::new();
+        let mut tip = (*genesis_work, genesis_hash);
+        let mut stack = vec![(*genesis_work, genesis_hash)];
+        while let Some((chainwork, hash)) = stack.pop() {
+            if chainwork > tip.0 {
+                tip = (chainwork, hash);
+            }
+            for child in children.get(&hash).into_iter().flatten() {
+                parents.insert(*child, hash);
+                stack.push((chainwork + blocks[child].1, *child));
+            }
+        }
+        let mut chain = Vec::new();
+        let mut hash = tip.1;
+        loop {
+            chain.push(blocks[&hash].0);
+            match parents.get(&hash) {
+                Some(parent) => hash = *parent,
+                None => break,
+            }
+        }
+        chain.reverse();
+        chain
+    }
+}
+
+impl BlockSource for BlkReader {
+    fn get_block_count(&self) -> Result<Height, SourceError> {
+        Ok(self.chain.len().saturating_sub(1) as Height)
+    }
+
+    fn get_block_hash(&self, height: Height) -> Result<BlockHash, SourceError> {
+        self.chain
+            .get(height as usize)
+            .map(|pos| pos.hash)
+            .ok_or(SourceError::MissingBlock(height))
+    }
+
+    fn get_block(&self, height: Height) -> Result<SourceBlock, SourceError> {
+        self.files.read_chain_block(&self.chain, height)
+    }
+
+    fn fetch_blocks(
+        &self,
+        start_height: Height,
+        end_height: Height,
+    ) -> BoxStream<'_, Result<SourceBlock, SourceError>> {
+        let heights = start_height..=end_height;
+        tokio_stream::iter(heights)
+            .map(move |height| {
+                let files = self.files.clone();
+                let chain = self.chain.clone();
+                task::spawn_blocking(move || files.read_chain_block(&chain, height))
+            })
+            .buffered(128)
+            .map(|result| result.map_err(SourceError::from).and_then(|result| result))
+            .boxed()
+    }
+}
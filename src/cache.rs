@@ -0,0 +1,201 @@
+use crate::model::MAX_REORG_DEPTH;
+use std::collections::{HashMap, HashSet};
+
+// Rough bookkeeping overhead of a hash map entry, on top of its key and value
+const ENTRY_OVERHEAD: usize = 64;
+
+// Outputs created and spent since the last flush, held in memory in front of the cache column family
+// like the dbcache of bitcoind, outputs spent before being flushed never reach the db
+pub struct UtxoCache {
+    // outputs created since the last flush, by outpoint key
+    added: HashMap<Vec<u8>, Vec<u8>>,
+    // outpoint keys of flushed outputs spent since the last flush
+    spent: HashSet<Vec<u8>>,
+    size: usize,
+    max_size: usize,
+    flush_interval: u64,
+    flushed_height: u64,
+}
+
+impl UtxoCache {
+    // A max size of 0 flushes at every commit, writing outputs through to the db
+    pub fn new(max_size: usize, flush_interval: u64) -> Self {
+        UtxoCache {
+            added: HashMap::new(),
+            spent: HashSet::new(),
+            size: 0,
+            max_size,
+            flush_interval,
+            flushed_height: 0,
+        }
+    }
+
+    // Changes of a commit are staged against the cache and applied once the commit succeeded
+    pub fn stage(&self) -> StagedCache<'_> {
+        StagedCache {
+            cache: self,
+            changes: CacheChanges::default(),
+        }
+    }
+
+    pub fn apply(&mut self, changes: CacheChanges) {
+        for key in changes.spent {
+            self.spend(&key);
+        }
+        for (key, utxo) in changes.added {
+            self.add(key, utxo);
+        }
+    }
+
+    pub fn add(&mut self, key: Vec<u8>, utxo: Vec<u8>) {
+        let key_size = key.len() + ENTRY_OVERHEAD;
+        // a duplicate txid of before bip30 can recreate a spent output
+        if self.spent.remove(&key) {
+            self.size -= key_size;
+        }
+        self.size += key_size + utxo.len();
+        if let Some(replaced) = self.added.insert(key, utxo) {
+            self.size -= key_size + replaced.len();
+        }
+    }
+
+    // The output if it was created since the last flush, otherwise it is read from the db and recorded as spent
+    pub fn spend(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.added.remove(key) {
+            Some(utxo) => {
+                self.size -= key.len() + utxo.len() + ENTRY_OVERHEAD;
+                Some(utxo)
+            }
+            None => {
+                self.size += key.len() + ENTRY_OVERHEAD;
+                self.spent.insert(key.to_vec());
+                None
+            }
+        }
+    }
+
+    // The output if it was created since the last flush, otherwise the db holds it unless spent since
+    pub fn get(&self, key: &[u8]) -> Option<&Vec<u8>> {
+        self.added.get(key)
+    }
+
+    pub fn is_spent(&self, key: &[u8]) -> bool {
+        self.spent.contains(key)
+    }
+
+    pub fn added(&self) -> impl Iterator<Item = (&Vec<u8>, &Vec<u8>)> {
+        self.added.iter()
+    }
+
+    pub fn spent(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.spent.iter()
+    }
+
+    pub fn needs_flush(&self, height: u64) -> bool {
+        self.size >= self.max_size || height >= self.flushed_height + self.flush_interval
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // Height of the last block whose outputs are all in the db
+    pub fn flushed_height(&self) -> u64 {
+        self.flushed_height
+    }
+
+    // Empties the cache once its outputs were committed along with the height
+    pub fn flushed(&mut self, height: u64) {
+        self.added.clear();
+        self.spent.clear();
+        self.size = 0;
+        self.flushed_height = height;
+    }
+
+    // Undo records leaving the reorg window once flushed at the height, unflushed blocks keep theirs
+    // so that they can be undone when the process dies before the next flush
+    pub fn expired_undo_heights(&self, height: u64) -> impl Iterator<Item = u64> {
+        (self.flushed_height + 1..=height)
+            .filter(|undo_height| *undo_height > MAX_REORG_DEPTH)
+            .map(|undo_height| undo_height - MAX_REORG_DEPTH)
+    }
+}
+
+// Outputs a commit created, and outputs it spent that were created before it
+#[derive(Default)]
+pub struct CacheChanges {
+    added: HashMap<Vec<u8>, Vec<u8>>,
+    spent: HashSet<Vec<u8>>,
+}
+
+// The cache as a commit sees it, left unchanged until the commit succeeded
+pub struct StagedCache<'a> {
+    cache: &'a UtxoCache,
+    changes: CacheChanges,
+}
+
+impl StagedCache<'_> {
+    pub fn add(&mut self, key: Vec<u8>, utxo: Vec<u8>) {
+        self.changes.added.insert(key, utxo);
+    }
+
+    // The output if it was created since the last flush, otherwise it is read from the db
+    pub fn spend(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        if let Some(utxo) = self.changes.added.remove(key) {
+            // an output recreated by a duplicate txid replaced the one cached before the commit
+            if self.cache.added.contains_key(key) {
+                self.changes.spent.insert(key.to_vec());
+            }
+            return Some(utxo);
+        }
+        self.changes.spent.insert(key.to_vec());
+        self.cache.added.get(key).cloned()
+    }
+
+    // Outputs created since the last flush and not spent, as flushed with the commit
+    pub fn added(&self) -> impl Iterator<Item = (&Vec<u8>, &Vec<u8>)> {
+        self.cache
+            .added
+            .iter()
+            .filter(|(key, _)| !self.changes.spent.contains(*key))
+            .chain(self.changes.added.iter())
+    }
+
+    // Flushed outputs spent since the last flush and not created again
+    pub fn spent(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.cache
+            .spent
+            .iter()
+            .chain(
+                self.changes
+                    .spent
+                    .iter()
+                    .filter(|key| !self.cache.added.contains_key(*key)),
+            )
+            .filter(|key| !self.changes.added.contains_key(*key))
+    }
+
+    // Whether the cache needs a flush once the changes are applied
+    pub fn needs_flush(&self, height: u64) -> bool {
+        let mut size = self.cache.size;
+        for key in &self.changes.spent {
+            match self.cache.added.get(key) {
+                Some(utxo) => size -= key.len() + utxo.len() + ENTRY_OVERHEAD,
+                None => size += key.len() + ENTRY_OVERHEAD,
+            }
+        }
+        for (key, utxo) in &self.changes.added {
+            size += key.len() + utxo.len() + ENTRY_OVERHEAD;
+        }
+        size >= self.cache.max_size
+            || height >= self.cache.flushed_height + self.cache.flush_interval
+    }
+
+    pub fn expired_undo_heights(&self, height: u64) -> impl Iterator<Item = u64> {
+        self.cache.expired_undo_heights(height)
+    }
+
+    pub fn into_changes(self) -> CacheChanges {
+        self.changes
+    }
+}
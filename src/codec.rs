@@ -0,0 +1,725 @@
+use crate::model::{
+    AddressFlow, AddressStats, Flow, FlowValue, HeaderRecord, IndexedTxid, ScriptHash, Spend,
+    TxLocation, UndoRecord, Utxo, UtxoParseError,
+};
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::hashes::Hash;
+use bitcoin::pow::Work;
+use bitcoin::{Network, Txid};
+use std::str::FromStr;
+
+// Bumped whenever the on-disk layout of keys, values or metadata changes, `index_btc migrate` upgrades older dbs
+pub const SCHEMA_VERSION: u32 = 13;
+pub const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+// Pipe-delimited strings, converted into a new db by `index_btc migrate`
+pub const LEGACY_SCHEMA_VERSION: u32 = 0;
+// Binary codec with a decimal last height, upgraded in place by `index_btc migrate`
+pub const CODEC_SCHEMA_VERSION: u32 = 1;
+// Big-endian last height without a network record, only mainnet could be indexed then
+pub const META_SCHEMA_VERSION: u32 = 2;
+// Network recorded but no maintained address balances, `index_btc migrate` sums them up from the flow rows
+pub const NETWORK_SCHEMA_VERSION: u32 = 3;
+// Maintained address balances but no address stats, `index_btc migrate` sums them up from the flow rows
+pub const BALANCE_SCHEMA_VERSION: u32 = 4;
+// Flow rows without height and block time, older rows keep lacking them after `index_btc migrate`
+pub const STATS_SCHEMA_VERSION: u32 = 5;
+// Block hashes without headers or a hash to height index, `index_btc migrate` maps the hashes to
+// heights, headers of blocks indexed before stay unknown
+pub const FLOW_HEIGHT_SCHEMA_VERSION: u32 = 6;
+// Headers kept but no txid index, transactions of blocks indexed before stay unknown to it
+pub const HEADER_SCHEMA_VERSION: u32 = 7;
+// Txid index but no script hashes, `index_btc migrate` maps those of the indexed addresses to them
+pub const TX_SCHEMA_VERSION: u32 = 8;
+// Cached outputs without the script hash of their script, those stay unknown until spent
+pub const SCRIPT_HASH_SCHEMA_VERSION: u32 = 9;
+// Flow rows without the position of their transaction nor a height ordered history, `index_btc
+// migrate` builds it from the flow rows, flows of a block indexed before keep no order among them
+pub const HISTORY_SCHEMA_VERSION: u32 = 10;
+// No history per script hash, flow rows do not record their script so `index_btc migrate` cannot
+// build it and script queries are refused until the db is reindexed
+pub const SCRIPT_HISTORY_SCHEMA_VERSION: u32 = 11;
+// Address stats without a transaction count, it stays unknown for the addresses of a migrated db
+pub const TX_COUNT_SCHEMA_VERSION: u32 = 12;
+
+pub fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn write_bytes(bytes: &mut Vec<u8>, value: &[u8]) {
+    write_varint(bytes, value.len() as u64);
+    bytes.extend_from_slice(value);
+}
+
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Decoder { bytes, pos: 0 }
+    }
+
+    fn invalid(&self, what: &str) -> UtxoParseError {
+        UtxoParseError::InvalidFormat(format!("Invalid {} @ byte {}", what, self.pos))
+    }
+
+    pub fn read_slice(&mut self, len: usize) -> Result<&'a [u8], UtxoParseError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| self.invalid("length"))?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, UtxoParseError> {
+        Ok(self.read_slice(1)?[0])
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, UtxoParseError> {
+        Ok(u32::from_be_bytes(self.read_slice(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, UtxoParseError> {
+        Ok(u64::from_be_bytes(self.read_slice(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_varint(&mut self) -> Result<u64, UtxoParseError> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_u8()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(self.invalid("varint"))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], UtxoParseError> {
+        let len = self.read_varint()? as usize;
+        self.read_slice(len)
+    }
+
+    pub fn read_string(&mut self) -> Result<String, UtxoParseError> {
+        String::from_utf8(self.read_bytes()?.to_vec()).map_err(UtxoParseError::DecodingError)
+    }
+
+    pub fn read_txid(&mut self) -> Result<Txid, UtxoParseError> {
+        let bytes: [u8; 32] = self.read_slice(32)?.try_into().unwrap();
+        Ok(Txid::from_byte_array(bytes))
+    }
+
+    pub fn finish(&self) -> Result<(), UtxoParseError> {
+        if self.pos == self.bytes.len() {
+            Ok(())
+        } else {
+            Err(self.invalid("trailing bytes"))
+        }
+    }
+}
+
+// Keys of all flows of an address start with it, the length prefix makes it exact
+pub fn address_prefix(address: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(address.len() + 1);
+    write_bytes(&mut bytes, address.as_bytes());
+    bytes
+}
+
+pub fn encode_value(value: u64) -> [u8; 8] {
+    value.to_be_bytes()
+}
+
+pub fn decode_value(bytes: &[u8]) -> Result<u64, UtxoParseError> {
+    let mut decoder = Decoder::new(bytes);
+    let value = decoder.read_u64()?;
+    decoder.finish()?;
+    Ok(value)
+}
+
+pub fn encode_schema_version(version: u32) -> [u8; 4] {
+    version.to_be_bytes()
+}
+
+pub fn decode_schema_version(bytes: &[u8]) -> Result<u32, UtxoParseError> {
+    let mut decoder = Decoder::new(bytes);
+    let version = u32::from_be_bytes(decoder.read_slice(4)?.try_into().unwrap());
+    decoder.finish()?;
+    Ok(version)
+}
+
+// Heights are big-endian everywhere, as keys and as the last height record alike
+pub fn decode_height(bytes: &[u8]) -> Result<u64, UtxoParseError> {
+    decode_value(bytes)
+}
+
+pub fn encode_network(network: Network) -> Vec<u8> {
+    network.to_string().into_bytes()
+}
+
+pub fn decode_network(bytes: &[u8]) -> Result<Network, UtxoParseError> {
+    let network = String::from_utf8(bytes.to_vec()).map_err(UtxoParseError::DecodingError)?;
+    Network::from_str(&network)
+        .map_err(|e| UtxoParseError::InvalidFormat(format!("Invalid network : {}", e)))
+}
+
+// value | varint height | varint block time | varint position of the funding transaction
+pub fn encode_output_value(value: u64, height: u64, time: u32, position: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(18);
+    bytes.extend_from_slice(&encode_value(value));
+    write_varint(&mut bytes, height);
+    write_varint(&mut bytes, time as u64);
+    write_varint(&mut bytes, position as u64);
+    bytes
+}
+
+// value | spending txid | varint vin | varint height | varint block time | varint position of the
+// spending transaction
+pub fn encode_input_value(value: u64, spend: &Spend, time: u32, position: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(54);
+    bytes.extend_from_slice(&encode_value(value));
+    bytes.extend_from_slice(spend.tx_id.as_byte_array());
+    write_varint(&mut bytes, spend.vin as u64);
+    write_varint(&mut bytes, spend.height);
+    write_varint(&mut bytes, time as u64);
+    write_varint(&mut bytes, position as u64);
+    bytes
+}
+
+// Flow rows written before spends were recorded hold just the value, those written before
+// block times were recorded end after the spend, and those written before positions after the time
+pub fn decode_flow_value(flow: &Flow, bytes: &[u8]) -> Result<FlowValue, UtxoParseError> {
+    let mut decoder = Decoder::new(bytes);
+    let mut flow_value = FlowValue {
+        value: decoder.read_u64()?,
+        spent_by: None,
+        height: None,
+        time: None,
+        position: None,
+    };
+    if decoder.pos == bytes.len() {
+        return Ok(flow_value);
+    }
+    if let Flow::I = flow {
+        let tx_id = decoder.read_txid()?;
+        let vin = decoder.read_varint()? as usize;
+        let height = decoder.read_varint()?;
+        flow_value.spent_by = Some(Spend { tx_id, vin, height });
+        flow_value.height = Some(height);
+        if decoder.pos == bytes.len() {
+            return Ok(flow_value);
+        }
+    } else {
+        flow_value.height = Some(decoder.read_varint()?);
+    }
+    flow_value.time = Some(decoder.read_varint()? as u32);
+    if decoder.pos < bytes.len() {
+        flow_value.position = Some(decoder.read_varint()? as usize);
+    }
+    decoder.finish()?;
+    Ok(flow_value)
+}
+
+impl Flow {
+    fn to_byte(&self) -> u8 {
+        match self {
+            Flow::I => b'I',
+            Flow::O => b'O',
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self, UtxoParseError> {
+        match byte {
+            b'I' => Ok(Flow::I),
+            b'O' => Ok(Flow::O),
+            _ => Err(UtxoParseError::InvalidFormat(format!(
+                "Invalid flow : {}",
+                byte
+            ))),
+        }
+    }
+}
+
+// address prefix | flow | txid | varint index
+pub fn address_key(address: &str, flow: &Flow, tx_id: &Txid, utxo_index: usize) -> Vec<u8> {
+    let mut bytes = address_prefix(address);
+    bytes.push(flow.to_byte());
+    bytes.extend_from_slice(tx_id.as_byte_array());
+    write_varint(&mut bytes, utxo_index as u64);
+    bytes
+}
+
+impl AddressFlow {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        address_key(&self.address, &self.flow, &self.tx_id, self.utxo_index)
+    }
+}
+
+impl TryFrom<&[u8]> for AddressFlow {
+    type Error = UtxoParseError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut decoder = Decoder::new(bytes);
+        let address = decoder.read_string()?;
+        let flow = Flow::from_byte(decoder.read_u8()?)?;
+        let tx_id = decoder.read_txid()?;
+        let utxo_index = decoder.read_varint()? as usize;
+        decoder.finish()?;
+        Ok(AddressFlow {
+            address,
+            flow,
+            tx_id,
+            utxo_index,
+            spent_by: None,
+            height: None,
+            time: None,
+            position: None,
+        })
+    }
+}
+
+impl Flow {
+    // Outputs of a transaction come before its inputs in the history
+    fn history_order(&self) -> u8 {
+        match self {
+            Flow::O => 0,
+            Flow::I => 1,
+        }
+    }
+}
+
+// Start of the history rows under the prefix from the height on
+pub fn history_bound(prefix: &[u8], height: u64) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(prefix.len() + 8);
+    bytes.extend_from_slice(prefix);
+    bytes.extend_from_slice(&height.to_be_bytes());
+    bytes
+}
+
+// Start of the history rows under the prefix from the transaction at the height and position on
+pub fn history_position_bound(prefix: &[u8], height: u64, position: usize) -> Vec<u8> {
+    let mut bytes = history_bound(prefix, height);
+    bytes.extend_from_slice(&(position as u32).to_be_bytes());
+    bytes
+}
+
+// prefix | height | u32 position | flow, outputs first | txid | varint index, the txid and index
+// are those of the flow row
+pub fn history_key(
+    prefix: &[u8],
+    height: u64,
+    position: usize,
+    flow: &Flow,
+    tx_id: &Txid,
+    utxo_index: usize,
+) -> Vec<u8> {
+    let mut bytes = history_position_bound(prefix, height, position);
+    bytes.push(flow.history_order());
+    bytes.extend_from_slice(tx_id.as_byte_array());
+    write_varint(&mut bytes, utxo_index as u64);
+    bytes
+}
+
+// The address history row of a flow row, holding the same value, flows indexed before heights or
+// positions were recorded sort as 0
+pub fn flow_history_key(address_key: &[u8], value: &[u8]) -> Result<Vec<u8>, UtxoParseError> {
+    let flow = AddressFlow::try_from(address_key)?;
+    let flow_value = decode_flow_value(&flow.flow, value)?;
+    Ok(history_key(
+        &address_prefix(&flow.address),
+        flow_value.height.unwrap_or(0),
+        flow_value.position.unwrap_or(0),
+        &flow.flow,
+        &flow.tx_id,
+        flow.utxo_index,
+    ))
+}
+
+// The flow of an address history row with what its value records
+pub fn decode_history_row(key: &[u8], value: &[u8]) -> Result<(AddressFlow, u64), UtxoParseError> {
+    let mut decoder = Decoder::new(key);
+    let address = decoder.read_string()?;
+    decode_history_flow(decoder, address, value)
+}
+
+// The flow of a script history row, as a flow of the address the script is indexed under
+pub fn decode_script_history_row(
+    address: &str,
+    key: &[u8],
+    value: &[u8],
+) -> Result<(AddressFlow, u64), UtxoParseError> {
+    let mut decoder = Decoder::new(key);
+    decoder.read_slice(32)?;
+    decode_history_flow(decoder, address.to_string(), value)
+}
+
+// Decodes the history key past its prefix
+fn decode_history_flow(
+    mut decoder: Decoder,
+    address: String,
+    value: &[u8],
+) -> Result<(AddressFlow, u64), UtxoParseError> {
+    decoder.read_u64()?;
+    decoder.read_u32()?;
+    let flow = match decoder.read_u8()? {
+        0 => Flow::O,
+        1 => Flow::I,
+        _ => return Err(decoder.invalid("flow")),
+    };
+    let tx_id = decoder.read_txid()?;
+    let utxo_index = decoder.read_varint()? as usize;
+    decoder.finish()?;
+    let flow_value = decode_flow_value(&flow, value)?;
+    let address_flow = AddressFlow {
+        address,
+        flow,
+        tx_id,
+        utxo_index,
+        spent_by: flow_value.spent_by,
+        height: flow_value.height,
+        time: flow_value.time,
+        position: flow_value.position,
+    };
+    Ok((address_flow, flow_value.value))
+}
+
+// txid | varint index
+pub fn outpoint_key(tx_id: &Txid, index: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(34);
+    bytes.extend_from_slice(tx_id.as_byte_array());
+    write_varint(&mut bytes, index as u64);
+    bytes
+}
+
+impl IndexedTxid {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        outpoint_key(&self.tx_id, self.index)
+    }
+}
+
+impl TryFrom<&[u8]> for IndexedTxid {
+    type Error = UtxoParseError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut decoder = Decoder::new(bytes);
+        let tx_id = decoder.read_txid()?;
+        let index = decoder.read_varint()? as usize;
+        decoder.finish()?;
+        Ok(IndexedTxid { tx_id, index })
+    }
+}
+
+// varint index | address | value | script hash, which older rows lack
+impl Utxo {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.address.len() + 44);
+        write_varint(&mut bytes, self.index as u64);
+        write_bytes(&mut bytes, self.address.as_bytes());
+        bytes.extend_from_slice(&encode_value(self.value));
+        if let Some(script_hash) = &self.script_hash {
+            bytes.extend_from_slice(&script_hash.0);
+        }
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for Utxo {
+    type Error = UtxoParseError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut decoder = Decoder::new(bytes);
+        let index = decoder.read_varint()? as usize;
+        let address = decoder.read_string()?;
+        let value = decoder.read_u64()?;
+        let script_hash = if decoder.pos < bytes.len() {
+            Some(ScriptHash(decoder.read_slice(32)?.try_into().unwrap()))
+        } else {
+            None
+        };
+        decoder.finish()?;
+        Ok(Utxo {
+            index,
+            address,
+            value,
+            script_hash,
+        })
+    }
+}
+
+// varint funded count | varint spent count | received | sent | varint first seen + 1 | varint last seen + 1
+// | varint tx count + 1 with 0 for an unknown height or count
+impl AddressStats {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(32);
+        write_varint(&mut bytes, self.funded_count);
+        write_varint(&mut bytes, self.spent_count);
+        bytes.extend_from_slice(&encode_value(self.received));
+        bytes.extend_from_slice(&encode_value(self.sent));
+        write_varint(&mut bytes, self.first_seen.map_or(0, |height| height + 1));
+        write_varint(&mut bytes, self.last_seen.map_or(0, |height| height + 1));
+        write_varint(&mut bytes, self.tx_count.map_or(0, |tx_count| tx_count + 1));
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for AddressStats {
+    type Error = UtxoParseError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut decoder = Decoder::new(bytes);
+        let funded_count = decoder.read_varint()?;
+        let spent_count = decoder.read_varint()?;
+        let received = decoder.read_u64()?;
+        let sent = decoder.read_u64()?;
+        let first_seen = decoder.read_varint()?.checked_sub(1);
+        let last_seen = decoder.read_varint()?.checked_sub(1);
+        // rows written before transactions were counted end here
+        let tx_count = if decoder.pos < bytes.len() {
+            decoder.read_varint()?.checked_sub(1)
+        } else {
+            None
+        };
+        decoder.finish()?;
+        Ok(AddressStats {
+            funded_count,
+            spent_count,
+            received,
+            sent,
+            tx_count,
+            first_seen,
+            last_seen,
+        })
+    }
+}
+
+// varint height | varint position
+impl TxLocation {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8);
+        write_varint(&mut bytes, self.height);
+        write_varint(&mut bytes, self.position as u64);
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for TxLocation {
+    type Error = UtxoParseError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut decoder = Decoder::new(bytes);
+        let height = decoder.read_varint()?;
+        let position = decoder.read_varint()? as usize;
+        decoder.finish()?;
+        Ok(TxLocation { height, position })
+    }
+}
+
+// consensus encoded header | varint median time past + 1, 0 when unknown | chainwork if known
+impl HeaderRecord {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = serialize(&self.header);
+        write_varint(
+            &mut bytes,
+            self.median_time
+                .map_or(0, |median_time| median_time as u64 + 1),
+        );
+        if let Some(chainwork) = self.chainwork {
+            bytes.extend_from_slice(&chainwork.to_be_bytes());
+        }
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for HeaderRecord {
+    type Error = UtxoParseError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut decoder = Decoder::new(bytes);
+        let header = deserialize(decoder.read_slice(80)?)
+            .map_err(|e| UtxoParseError::InvalidFormat(format!("Invalid header : {}", e)))?;
+        let median_time = decoder
+            .read_varint()?
+            .checked_sub(1)
+            .map(|median_time| median_time as u32);
+        let chainwork = if decoder.pos < bytes.len() {
+            Some(Work::from_be_bytes(
+                decoder.read_slice(32)?.try_into().unwrap(),
+            ))
+        } else {
+            None
+        };
+        decoder.finish()?;
+        Ok(HeaderRecord {
+            header,
+            median_time,
+            chainwork,
+        })
+    }
+}
+
+impl UndoRecord {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, self.address_keys.len() as u64);
+        for key in &self.address_keys {
+            write_bytes(&mut bytes, key);
+        }
+        write_varint(&mut bytes, self.cache_keys.len() as u64);
+        for key in &self.cache_keys {
+            write_bytes(&mut bytes, key);
+        }
+        write_varint(&mut bytes, self.spent_utxos.len() as u64);
+        for (key, value) in &self.spent_utxos {
+            write_bytes(&mut bytes, key);
+            write_bytes(&mut bytes, value);
+        }
+        write_varint(&mut bytes, self.address_stats.len() as u64);
+        for (address, stats) in &self.address_stats {
+            write_bytes(&mut bytes, address.as_bytes());
+            // stats are never empty, so no bytes stand for no row
+            write_bytes(
+                &mut bytes,
+                &stats.as_ref().map_or(vec![], AddressStats::to_bytes),
+            );
+        }
+        write_varint(&mut bytes, self.txids.len() as u64);
+        for txid in &self.txids {
+            bytes.extend_from_slice(txid.as_byte_array());
+        }
+        write_varint(&mut bytes, self.replaced_txs.len() as u64);
+        for (txid, location) in &self.replaced_txs {
+            bytes.extend_from_slice(txid.as_byte_array());
+            write_bytes(&mut bytes, &location.to_bytes());
+        }
+        write_varint(&mut bytes, self.script_history_keys.len() as u64);
+        for key in &self.script_history_keys {
+            write_bytes(&mut bytes, key);
+        }
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for UndoRecord {
+    type Error = UtxoParseError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut decoder = Decoder::new(bytes);
+        let mut undo = UndoRecord::default();
+        for _ in 0..decoder.read_varint()? {
+            undo.address_keys.push(decoder.read_bytes()?.to_vec());
+        }
+        for _ in 0..decoder.read_varint()? {
+            undo.cache_keys.push(decoder.read_bytes()?.to_vec());
+        }
+        for _ in 0..decoder.read_varint()? {
+            let key = decoder.read_bytes()?.to_vec();
+            let value = decoder.read_bytes()?.to_vec();
+            undo.spent_utxos.push((key, value));
+        }
+        // records written before address stats were kept end here
+        if decoder.pos < bytes.len() {
+            for _ in 0..decoder.read_varint()? {
+                let address = decoder.read_string()?;
+                let stats = match decoder.read_bytes()? {
+                    [] => None,
+                    stats => Some(AddressStats::try_from(stats)?),
+                };
+                undo.address_stats.push((address, stats));
+            }
+        }
+        // and those written before the txid index here
+        if decoder.pos < bytes.len() {
+            for _ in 0..decoder.read_varint()? {
+                undo.txids.push(decoder.read_txid()?);
+            }
+        }
+        // and those written before replaced txids were kept here
+        if decoder.pos < bytes.len() {
+            for _ in 0..decoder.read_varint()? {
+                let txid = decoder.read_txid()?;
+                let location = TxLocation::try_from(decoder.read_bytes()?)?;
+                undo.replaced_txs.push((txid, location));
+            }
+        }
+        // and those written before scripts had a history here
+        if decoder.pos < bytes.len() {
+            for _ in 0..decoder.read_varint()? {
+                undo.script_history_keys
+                    .push(decoder.read_bytes()?.to_vec());
+            }
+        }
+        decoder.finish()?;
+        Ok(undo)
+    }
+}
+
+// Conversion of rows written in the pipe-delimited string layout preceding this codec
+pub mod legacy {
+    use super::*;
+
+    // Single byte codec version the dbs of `CODEC_SCHEMA_VERSION` were tagged with
+    pub const CODEC_VERSION_KEY: &[u8] = b"codec_version";
+
+    // Legacy and codec dbs stored the last height as a decimal string
+    pub fn convert_last_height(bytes: &[u8]) -> Result<[u8; 8], UtxoParseError> {
+        let height = parse::<u64>(bytes)?;
+        Ok(height.to_be_bytes())
+    }
+
+    fn parse<T: std::str::FromStr>(bytes: &[u8]) -> Result<T, UtxoParseError>
+    where
+        UtxoParseError: From<T::Err>,
+    {
+        Ok(String::from_utf8(bytes.to_vec())
+            .map_err(UtxoParseError::DecodingError)?
+            .parse()?)
+    }
+
+    pub fn convert_address_key(key: &[u8]) -> Result<Vec<u8>, UtxoParseError> {
+        Ok(parse::<AddressFlow>(key)?.to_bytes())
+    }
+
+    pub fn convert_cache_key(key: &[u8]) -> Result<Vec<u8>, UtxoParseError> {
+        Ok(parse::<IndexedTxid>(key)?.to_bytes())
+    }
+
+    pub fn convert_cache_value(value: &[u8]) -> Result<Vec<u8>, UtxoParseError> {
+        Ok(parse::<Utxo>(value)?.to_bytes())
+    }
+
+    // Legacy undo records hold u32 big-endian counts and lengths
+    fn read_len(decoder: &mut Decoder) -> Result<usize, UtxoParseError> {
+        Ok(u32::from_be_bytes(decoder.read_slice(4)?.try_into().unwrap()) as usize)
+    }
+
+    pub fn convert_undo_record(bytes: &[u8]) -> Result<Vec<u8>, UtxoParseError> {
+        let mut decoder = Decoder::new(bytes);
+        let mut undo = UndoRecord::default();
+        for _ in 0..read_len(&mut decoder)? {
+            let len = read_len(&mut decoder)?;
+            undo.address_keys
+                .push(convert_address_key(decoder.read_slice(len)?)?);
+        }
+        for _ in 0..read_len(&mut decoder)? {
+            let len = read_len(&mut decoder)?;
+            undo.cache_keys
+                .push(convert_cache_key(decoder.read_slice(len)?)?);
+        }
+        for _ in 0..read_len(&mut decoder)? {
+            let len = read_len(&mut decoder)?;
+            let key = convert_cache_key(decoder.read_slice(len)?)?;
+            let len = read_len(&mut decoder)?;
+            let value = convert_cache_value(decoder.read_slice(len)?)?;
+            undo.spent_utxos.push((key, value));
+        }
+        decoder.finish()?;
+        Ok(undo.to_bytes())
+    }
+}
@@ -0,0 +1,511 @@
+use crate::indexer::{blocking_query, Indexer, IndexerError};
+use crate::log;
+use crate::model::{AddressFlow, Flow, ScriptHash};
+use crate::source::BlockSource;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::consensus::serialize;
+use bitcoin::{BlockHash, Network, Txid};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+// Protocol version spoken, the oldest one with all the methods served
+pub const PROTOCOL_VERSION: &str = "1.4";
+const SERVER_SOFTWARE: &str = concat!("index_btc ", env!("CARGO_PKG_VERSION"));
+
+// How often the indexed tip is checked for a new block to notify subscribers about
+const TIP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn invalid_params(message: String) -> Self {
+        RpcError {
+            code: INVALID_PARAMS,
+            message,
+        }
+    }
+
+    fn internal(message: String) -> Self {
+        RpcError {
+            code: INTERNAL_ERROR,
+            message,
+        }
+    }
+}
+
+impl From<IndexerError> for RpcError {
+    fn from(error: IndexerError) -> Self {
+        RpcError::internal(format!("{:?}", error))
+    }
+}
+
+// Last indexed block, a reorg to the same height changes its hash
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Tip {
+    height: u64,
+    hash: Option<BlockHash>,
+}
+
+fn current_tip<I: Indexer>(indexer: &I) -> Result<Tip, IndexerError> {
+    let height = indexer.get_last_height();
+    Ok(Tip {
+        height,
+        hash: indexer.get_block_hash(height)?,
+    })
+}
+
+// Serves the Electrum protocol over tcp until the shutdown future resolves, wallets query scripts
+// by their script hash and are notified of new blocks and changes to the scripts they subscribed to,
+// transactions are read from the block source
+pub async fn serve<I>(
+    indexer: I,
+    source: Option<Arc<dyn BlockSource>>,
+    network: Network,
+    electrum_addr: String,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), io::Error>
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(&electrum_addr).await?;
+    log!("Serving electrum protocol at : {}", electrum_addr);
+    let tip = {
+        let indexer = indexer.clone();
+        blocking_query(move || current_tip(&indexer))
+            .await
+            .map_err(io::Error::other)?
+            .map_err(|e| io::Error::other(format!("{:?}", e)))?
+    };
+    let (tip_sender, tip_receiver) = watch::channel(tip);
+    let (closing_sender, closing_receiver) = watch::channel(false);
+    tokio::spawn(watch_tip(indexer.clone(), tip_sender));
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let connection = handle_connection(
+                        indexer.clone(),
+                        source.clone(),
+                        network,
+                        stream,
+                        tip_receiver.clone(),
+                        closing_receiver.clone(),
+                    );
+                    tokio::spawn(async move {
+                        if let Err(e) = connection.await {
+                            log!("Electrum connection of {} failed : {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => log!("Accepting electrum connection failed : {}", e),
+            },
+            _ = &mut shutdown => break,
+        }
+    }
+    let _ = closing_sender.send(true);
+    Ok(())
+}
+
+// Polls the indexed tip until no connection or listener is left to tell about it
+async fn watch_tip<I>(indexer: I, sender: watch::Sender<Tip>)
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    while !sender.is_closed() {
+        tokio::time::sleep(TIP_POLL_INTERVAL).await;
+        let indexer = indexer.clone();
+        match blocking_query(move || current_tip(&indexer)).await {
+            Ok(Ok(tip)) => {
+                sender.send_if_modified(|current| std::mem::replace(current, tip) != tip);
+            }
+            Ok(Err(e)) => log!("Reading the indexed tip failed : {:?}", e),
+            Err(e) => log!("Reading the indexed tip failed : {}", e),
+        }
+    }
+}
+
+// Subscriptions of a connection
+#[derive(Default)]
+struct Session {
+    headers: bool,
+    // status of each subscribed script as last sent to the client
+    scripts: HashMap<ScriptHash, Option<String>>,
+}
+
+// Requests and notifications are json objects, one per line
+async fn handle_connection<I>(
+    indexer: I,
+    source: Option<Arc<dyn BlockSource>>,
+    network: Network,
+    stream: TcpStream,
+    mut tip: watch::Receiver<Tip>,
+    mut closing: watch::Receiver<bool>,
+) -> Result<(), io::Error>
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut session = Session::default();
+    tip.mark_unchanged();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let Some(line) = line? else {
+                    break;
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let (indexer, source) = (indexer.clone(), source.clone());
+                let response;
+                (response, session) = blocking_query(move || {
+                    let response =
+                        handle_line(&indexer, source.as_deref(), network, &mut session, &line);
+                    (response, session)
+                })
+                .await
+                .map_err(io::Error::other)?;
+                send(&mut writer, &response).await?;
+            }
+            changed = tip.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                let tip = *tip.borrow_and_update();
+                let indexer = indexer.clone();
+                let notifications;
+                (notifications, session) = blocking_query(move || {
+                    let notifications = session.notifications(&indexer, tip);
+                    (notifications, session)
+                })
+                .await
+                .map_err(io::Error::other)?;
+                for notification in notifications {
+                    send(&mut writer, &notification).await?;
+                }
+            }
+            // the flag is only ever raised, once the server shuts down
+            _ = closing.changed() => break,
+        }
+    }
+    Ok(())
+}
+
+async fn send(writer: &mut OwnedWriteHalf, message: &Value) -> Result<(), io::Error> {
+    let mut line = message.to_string();
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await
+}
+
+// A single request or a batch of them
+fn handle_line<I: Indexer>(
+    indexer: &I,
+    source: Option<&dyn BlockSource>,
+    network: Network,
+    session: &mut Session,
+    line: &str,
+) -> Value {
+    match serde_json::from_str::<Value>(line) {
+        Ok(Value::Array(requests)) => Value::Array(
+            requests
+                .iter()
+                .map(|request| handle_request(indexer, source, network, session, request))
+                .collect(),
+        ),
+        Ok(request) => handle_request(indexer, source, network, session, &request),
+        Err(e) => response(
+            Value::Null,
+            Err(RpcError {
+                code: PARSE_ERROR,
+                message: e.to_string(),
+            }),
+        ),
+    }
+}
+
+fn handle_request<I: Indexer>(
+    indexer: &I,
+    source: Option<&dyn BlockSource>,
+    network: Network,
+    session: &mut Session,
+    request: &Value,
+) -> Value {
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let params = match request.get("params") {
+        Some(Value::Array(params)) => params.as_slice(),
+        _ => &[],
+    };
+    let result = match request.get("method").and_then(Value::as_str) {
+        Some(method) => call(indexer, source, network, session, method, params),
+        None => Err(RpcError::invalid_params("Missing method".to_string())),
+    };
+    response(id, result)
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": error.code, "message": error.message },
+        }),
+    }
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+fn call<I: Indexer>(
+    indexer: &I,
+    source: Option<&dyn BlockSource>,
+    network: Network,
+    session: &mut Session,
+    method: &str,
+    params: &[Value],
+) -> Result<Value, RpcError> {
+    match method {
+        "server.version" => Ok(json!([SERVER_SOFTWARE, PROTOCOL_VERSION])),
+        "server.ping" => Ok(Value::Null),
+        "server.features" => Ok(json!({
+            "genesis_hash": genesis_block(network).block_hash().to_string(),
+            "hosts": {},
+            "protocol_min": PROTOCOL_VERSION,
+            "protocol_max": PROTOCOL_VERSION,
+            "pruning": null,
+            "server_version": SERVER_SOFTWARE,
+            "hash_function": "sha256",
+        })),
+        "blockchain.headers.subscribe" => {
+            session.headers = true;
+            header(indexer, indexer.get_last_height())
+        }
+        "blockchain.block.header" => {
+            let height = params
+                .first()
+                .and_then(Value::as_u64)
+                .ok_or_else(|| RpcError::invalid_params("Missing height".to_string()))?;
+            // proofs up to a checkpoint need the merkle branches of the headers, which are not kept
+            if params.get(1).and_then(Value::as_u64).unwrap_or(0) != 0 {
+                return Err(RpcError::invalid_params(
+                    "Checkpoint heights are not supported".to_string(),
+                ));
+            }
+            let record = indexer.get_header(height)?.ok_or_else(|| {
+                RpcError::invalid_params(format!("Header @ {} is not indexed", height))
+            })?;
+            Ok(json!(base16::encode_lower(&serialize(&record.header))))
+        }
+        "blockchain.transaction.get" => {
+            let txid = params
+                .first()
+                .and_then(Value::as_str)
+                .ok_or_else(|| RpcError::invalid_params("Missing txid".to_string()))?;
+            let txid = Txid::from_str(txid)
+                .map_err(|e| RpcError::invalid_params(format!("Invalid txid : {}", e)))?;
+            if params.get(1).and_then(Value::as_bool).unwrap_or(false) {
+                return Err(RpcError::invalid_params(
+                    "Verbose transactions are not supported".to_string(),
+                ));
+            }
+            let source = source.ok_or_else(|| {
+                RpcError::internal(
+                    "No block source, transactions need bitcoin rpc credentials".to_string(),
+                )
+            })?;
+            match indexer.get_transaction(source, &txid)? {
+                Some((_, tx)) => Ok(json!(base16::encode_lower(&serialize(&tx)))),
+                None => Err(RpcError::invalid_params(format!(
+                    "Transaction {} not found",
+                    txid
+                ))),
+            }
+        }
+        "blockchain.scripthash.get_balance" => {
+            let balance: u64 = indexer
+                .get_script_utxos(&script_hash_param(params)?)?
+                .iter()
+                .map(|(_, value)| value)
+                .sum();
+            Ok(json!({ "confirmed": balance, "unconfirmed": 0 }))
+        }
+        "blockchain.scripthash.get_history" => {
+            let history: Vec<Value> = script_history(indexer, &script_hash_param(params)?)?
+                .into_iter()
+                .map(|(height, txid)| json!({ "tx_hash": txid.to_string(), "height": height }))
+                .collect();
+            Ok(json!(history))
+        }
+        "blockchain.scripthash.listunspent" => {
+            let unspent: Vec<Value> = script_unspent(indexer, &script_hash_param(params)?)?
+                .into_iter()
+                .map(|(height, flow, value)| {
+                    json!({
+                        "tx_hash": flow.tx_id.to_string(),
+                        "tx_pos": flow.utxo_index,
+                        "height": height,
+                        "value": value,
+                    })
+                })
+                .collect();
+            Ok(json!(unspent))
+        }
+        "blockchain.scripthash.subscribe" => {
+            let script_hash = script_hash_param(params)?;
+            let status = script_status(indexer, &script_hash)?;
+            session.scripts.insert(script_hash, status.clone());
+            Ok(json!(status))
+        }
+        _ => Err(RpcError {
+            code: METHOD_NOT_FOUND,
+            message: format!("Unknown method {}", method),
+        }),
+    }
+}
+
+fn script_hash_param(params: &[Value]) -> Result<ScriptHash, RpcError> {
+    params
+        .first()
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::invalid_params("Missing script hash".to_string()))?
+        .parse()
+        .map_err(|e| RpcError::invalid_params(format!("{:?}", e)))
+}
+
+fn header<I: Indexer>(indexer: &I, height: u64) -> Result<Value, RpcError> {
+    let record = indexer
+        .get_header(height)?
+        .ok_or_else(|| RpcError::internal(format!("Header @ {} is not indexed", height)))?;
+    Ok(json!({
+        "height": height,
+        "hex": base16::encode_lower(&serialize(&record.header)),
+    }))
+}
+
+// Height of the block the flow happened in, output flows indexed before heights were recorded
+// fall back to the txid index
+fn flow_height<I: Indexer>(indexer: &I, flow: &AddressFlow) -> Result<u64, RpcError> {
+    if let Some(spend) = &flow.spent_by {
+        return Ok(spend.height);
+    }
+    if let (Flow::O, Some(height)) = (&flow.flow, flow.height) {
+        return Ok(height);
+    }
+    match (&flow.flow, indexer.get_tx_location(&flow.tx_id)?) {
+        (Flow::O, Some(location)) => Ok(location.height),
+        _ => Err(RpcError::internal(format!(
+            "Flow {} was indexed without its height, reindex to serve it",
+            flow
+        ))),
+    }
+}
+
+// Transactions funding or spending exactly the script with their heights, in the order of their
+// blocks and of their positions within them
+fn script_history<I: Indexer>(
+    indexer: &I,
+    script_hash: &ScriptHash,
+) -> Result<Vec<(u64, Txid)>, RpcError> {
+    let mut history: Vec<(u64, Txid)> = Vec::new();
+    for (flow, _) in indexer.get_script_history(script_hash)? {
+        let height = flow_height(indexer, &flow)?;
+        let txid = match &flow.spent_by {
+            Some(spend) => spend.tx_id,
+            None => flow.tx_id,
+        };
+        // the flows of a transaction are next to each other
+        if history.last() != Some(&(height, txid)) {
+            history.push((height, txid));
+        }
+    }
+    Ok(history)
+}
+
+// Outputs of the script not spent yet with their heights and values
+fn script_unspent<I: Indexer>(
+    indexer: &I,
+    script_hash: &ScriptHash,
+) -> Result<Vec<(u64, AddressFlow, u64)>, RpcError> {
+    let history = indexer.get_script_history(script_hash)?;
+    let spent: HashSet<(Txid, usize)> = history
+        .iter()
+        .filter(|(flow, _)| matches!(flow.flow, Flow::I))
+        .map(|(flow, _)| (flow.tx_id, flow.utxo_index))
+        .collect();
+    let mut unspent = Vec::new();
+    for (flow, value) in history {
+        if matches!(flow.flow, Flow::O) && !spent.contains(&(flow.tx_id, flow.utxo_index)) {
+            unspent.push((flow_height(indexer, &flow)?, flow, value));
+        }
+    }
+    Ok(unspent)
+}
+
+// Sha256 of the history as `txid:height:` strings, none for a script without history
+fn script_status<I: Indexer>(
+    indexer: &I,
+    script_hash: &ScriptHash,
+) -> Result<Option<String>, RpcError> {
+    let history = script_history(indexer, script_hash)?;
+    if history.is_empty() {
+        return Ok(None);
+    }
+    let mut hasher = Sha256::default();
+    for (height, txid) in history {
+        hasher.update(format!("{}:{}:", txid, height).as_bytes());
+    }
+    Ok(Some(base16::encode_lower(&hasher.finalize())))
+}
+
+impl Session {
+    // Notifies the new tip and the scripts whose status it changed
+    fn notifications<I: Indexer>(&mut self, indexer: &I, tip: Tip) -> Vec<Value> {
+        let mut notifications = Vec::new();
+        if self.headers {
+            match header(indexer, tip.height) {
+                Ok(header) => notifications.push(notification(
+                    "blockchain.headers.subscribe",
+                    json!([header]),
+                )),
+                Err(e) => log!("Notifying header @ {} failed : {}", tip.height, e.message),
+            }
+        }
+        for (script_hash, status) in self.scripts.iter_mut() {
+            match script_status(indexer, script_hash) {
+                Ok(new_status) if new_status != *status => {
+                    *status = new_status;
+                    notifications.push(notification(
+                        "blockchain.scripthash.subscribe",
+                        json!([script_hash.to_string(), status]),
+                    ));
+                }
+                Ok(_) => {}
+                Err(e) => log!("Notifying script {} failed : {}", script_hash, e.message),
+            }
+        }
+        notifications
+    }
+}
@@ -0,0 +1,519 @@
+use crate::indexer::{blocking_query, Indexer, IndexerError};
+use crate::model::{Flow, IndexedTxid, SumTx, TxLocation, OP_RETURN};
+use crate::source::{BlockSource, SourceBlock, SourceError};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use bitcoin::consensus::serialize;
+use bitcoin::{Address, BlockHash, Network, Script, Transaction, TxOut, Txid};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+
+// Esplora pages confirmed transactions of an address by this many, newest first
+const CHAIN_TXS_PER_PAGE: usize = 25;
+
+// Esplora answers errors as plain text
+struct EsploraError(StatusCode, String);
+
+type EsploraResult = Result<Json<Value>, EsploraError>;
+
+impl EsploraError {
+    fn bad_request(message: &str) -> Self {
+        EsploraError(StatusCode::BAD_REQUEST, message.to_string())
+    }
+
+    fn not_found(message: &str) -> Self {
+        EsploraError(StatusCode::NOT_FOUND, message.to_string())
+    }
+}
+
+impl IntoResponse for EsploraError {
+    fn into_response(self) -> Response {
+        (self.0, self.1).into_response()
+    }
+}
+
+impl From<IndexerError> for EsploraError {
+    fn from(error: IndexerError) -> Self {
+        EsploraError(StatusCode::INTERNAL_SERVER_ERROR, format!("{:?}", error))
+    }
+}
+
+impl From<SourceError> for EsploraError {
+    fn from(error: SourceError) -> Self {
+        EsploraError(StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+    }
+}
+
+#[derive(Clone)]
+struct EsploraState<I> {
+    indexer: I,
+    // transactions and block sizes are read from it, the index only locates them
+    source: Option<Arc<dyn BlockSource>>,
+    network: Network,
+}
+
+// The subset of the Esplora REST api that frontends speak, served from the same column families
+// as the rest of the api with transactions read from the block source
+pub fn router<I>(indexer: I, source: Option<Arc<dyn BlockSource>>, network: Network) -> Router
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/address/:address", get(get_address::<I>))
+        .route("/address/:address/txs", get(get_address_txs::<I>))
+        .route(
+            "/address/:address/txs/chain/:last_seen_txid",
+            get(get_address_txs_chain::<I>),
+        )
+        .route("/address/:address/utxo", get(get_address_utxo::<I>))
+        .route("/tx/:txid", get(get_tx::<I>))
+        .route("/tx/:txid/outspends", get(get_tx_outspends::<I>))
+        .route("/block/:hash", get(get_block::<I>))
+        .route("/blocks/tip/height", get(get_tip_height::<I>))
+        .with_state(EsploraState {
+            indexer,
+            source,
+            network,
+        })
+}
+
+// Runs the query with the state of the api
+async fn query<I, T, F>(state: EsploraState<I>, f: F) -> Result<T, EsploraError>
+where
+    I: Indexer + Send + 'static,
+    T: Send + 'static,
+    F: FnOnce(&EsploraState<I>) -> Result<T, EsploraError> + Send + 'static,
+{
+    blocking_query(move || f(&state))
+        .await
+        .map_err(|e| EsploraError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+}
+
+fn parse_address(address: &str, network: Network) -> Result<(), EsploraError> {
+    Address::from_str(address)
+        .ok()
+        .filter(|address| address.is_valid_for_network(network))
+        .map(|_| ())
+        .ok_or_else(|| EsploraError::bad_request("Invalid Bitcoin address"))
+}
+
+fn parse_txid(txid: &str) -> Result<Txid, EsploraError> {
+    Txid::from_str(txid).map_err(|_| EsploraError::bad_request("Invalid hex string"))
+}
+
+// Blocks read while answering a request, the inputs of a transaction often spend the same blocks
+struct Blocks<'a, I> {
+    indexer: &'a I,
+    source: &'a dyn BlockSource,
+    blocks: HashMap<u64, SourceBlock>,
+}
+
+impl<'a, I: Indexer> Blocks<'a, I> {
+    fn new(state: &'a EsploraState<I>) -> Result<Self, EsploraError> {
+        let source = state.source.as_deref().ok_or_else(|| {
+            EsploraError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "No block source, transactions need bitcoin rpc credentials".to_string(),
+            )
+        })?;
+        Ok(Blocks {
+            indexer: &state.indexer,
+            source,
+            blocks: HashMap::new(),
+        })
+    }
+
+    // The block at the height unless the source has another one there than the index
+    fn block(&mut self, height: u64) -> Result<Option<&SourceBlock>, EsploraError> {
+        if !self.blocks.contains_key(&height) {
+            let block = self.source.get_block(height)?;
+            if self.indexer.get_block_hash(height)? != Some(block.hash) {
+                return Ok(None);
+            }
+            self.blocks.insert(height, block);
+        }
+        Ok(self.blocks.get(&height))
+    }
+
+    fn transaction(
+        &mut self,
+        txid: &Txid,
+    ) -> Result<Option<(TxLocation, Transaction)>, EsploraError> {
+        let Some(location) = self.indexer.get_tx_location(txid)? else {
+            return Ok(None);
+        };
+        let tx = self.block(location.height)?.and_then(|block| {
+            block
+                .block
+                .txdata
+                .get(location.position)
+                .filter(|tx| tx.compute_txid() == *txid)
+                .cloned()
+        });
+        Ok(tx.map(|tx| (location, tx)))
+    }
+}
+
+// Confirmation of whatever is in the block at the height
+fn status<I: Indexer>(indexer: &I, height: u64) -> Result<Value, EsploraError> {
+    let hash = indexer.get_block_hash(height)?;
+    let time = indexer.get_header(height)?.map(|record| record.header.time);
+    Ok(json!({
+        "confirmed": true,
+        "block_height": height,
+        "block_hash": hash.map(|hash| hash.to_string()),
+        "block_time": time,
+    }))
+}
+
+// Esplora tells scripts starting with an invalid opcode apart, which rust-bitcoin deprecated
+#[allow(deprecated)]
+fn script_type(script: &Script) -> &'static str {
+    if script.is_empty() {
+        "empty"
+    } else if script.is_op_return() {
+        "op_return"
+    } else if script.is_p2pk() {
+        "p2pk"
+    } else if script.is_p2pkh() {
+        "p2pkh"
+    } else if script.is_p2sh() {
+        "p2sh"
+    } else if script.is_p2wpkh() {
+        "v0_p2wpkh"
+    } else if script.is_p2wsh() {
+        "v0_p2wsh"
+    } else if script.is_p2tr() {
+        "v1_p2tr"
+    } else if script.is_provably_unspendable() {
+        "provably_unspendable"
+    } else {
+        "unknown"
+    }
+}
+
+fn txout_value(txout: &TxOut, network: Network) -> Value {
+    let script = txout.script_pubkey.as_script();
+    let mut value = json!({
+        "scriptpubkey": base16::encode_lower(script.as_bytes()),
+        "scriptpubkey_asm": script.to_asm_string(),
+        "scriptpubkey_type": script_type(script),
+        "value": txout.value.to_sat(),
+    });
+    if let Ok(address) = Address::from_script(script, network) {
+        value["scriptpubkey_address"] = json!(address.to_string());
+    }
+    value
+}
+
+// A transaction in the format of Esplora, with the outputs its inputs spend
+fn tx_value<I: Indexer>(
+    blocks: &mut Blocks<I>,
+    location: &TxLocation,
+    tx: &Transaction,
+    network: Network,
+) -> Result<Value, EsploraError> {
+    let mut vin = Vec::with_capacity(tx.input.len());
+    let mut prevout_sum = Some(0);
+    let is_coinbase = tx.is_coinbase();
+    for input in &tx.input {
+        let prevout = if is_coinbase {
+            None
+        } else {
+            blocks
+                .transaction(&input.previous_output.txid)?
+                .and_then(|(_, prev_tx)| {
+                    prev_tx
+                        .output
+                        .get(input.previous_output.vout as usize)
+                        .cloned()
+                })
+        };
+        prevout_sum = prevout_sum
+            .zip(prevout.as_ref())
+            .map(|(sum, prevout)| sum + prevout.value.to_sat());
+        let mut value = json!({
+            "txid": input.previous_output.txid.to_string(),
+            "vout": input.previous_output.vout,
+            "prevout": prevout.map(|prevout| txout_value(&prevout, network)),
+            "scriptsig": base16::encode_lower(input.script_sig.as_bytes()),
+            "scriptsig_asm": input.script_sig.to_asm_string(),
+            "is_coinbase": is_coinbase,
+            "sequence": input.sequence.0,
+        });
+        if !input.witness.is_empty() {
+            value["witness"] = json!(input
+                .witness
+                .iter()
+                .map(base16::encode_lower)
+                .collect::<Vec<String>>());
+        }
+        vin.push(value);
+    }
+    let output_sum: u64 = tx.output.iter().map(|txout| txout.value.to_sat()).sum();
+    let fee = match is_coinbase {
+        true => Some(0),
+        false => prevout_sum.map(|sum: u64| sum.saturating_sub(output_sum)),
+    };
+    Ok(json!({
+        "txid": tx.compute_txid().to_string(),
+        "version": tx.version.0,
+        "locktime": tx.lock_time.to_consensus_u32(),
+        "vin": vin,
+        "vout": tx
+            .output
+            .iter()
+            .map(|txout| txout_value(txout, network))
+            .collect::<Vec<Value>>(),
+        "size": tx.total_size(),
+        "weight": tx.weight().to_wu(),
+        "fee": fee,
+        "status": status(blocks.indexer, location.height)?,
+    }))
+}
+
+// Transactions funding or spending the address below the height and position, newest first,
+// spends indexed before their transaction was recorded cannot be listed
+fn address_txids<I: Indexer>(
+    indexer: &I,
+    address: &str,
+    before: Option<(u64, usize)>,
+    tx_count: usize,
+) -> Result<Vec<Txid>, EsploraError> {
+    let mut seen = HashSet::new();
+    Ok(indexer
+        .get_history_page(address, before, tx_count)?
+        .iter()
+        .filter_map(|(flow, _)| flow.txid())
+        .filter(|txid| seen.insert(*txid))
+        .collect())
+}
+
+fn address_txs<I: Indexer>(
+    state: &EsploraState<I>,
+    address: &str,
+    last_seen_txid: Option<Txid>,
+) -> EsploraResult {
+    let before = match last_seen_txid {
+        Some(last_seen_txid) => match state.indexer.get_tx_location(&last_seen_txid)? {
+            Some(location) => Some((location.height, location.position)),
+            None => return Ok(Json(json!([]))),
+        },
+        None => None,
+    };
+    let txids = address_txids(&state.indexer, address, before, CHAIN_TXS_PER_PAGE)?;
+    let mut blocks = Blocks::new(state)?;
+    let mut txs = Vec::new();
+    for txid in &txids {
+        if let Some((location, tx)) = blocks.transaction(txid)? {
+            txs.push(tx_value(&mut blocks, &location, &tx, state.network)?);
+        }
+    }
+    Ok(Json(json!(txs)))
+}
+
+async fn get_address<I>(
+    State(state): State<EsploraState<I>>,
+    Path(address): Path<String>,
+) -> EsploraResult
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    parse_address(&address, state.network)?;
+    query(state, move |state| {
+        let stats = state
+            .indexer
+            .get_address_stats(&address)?
+            .unwrap_or_default();
+        // addresses of a migrated db have their transactions counted from their history
+        let tx_count = match stats.tx_count {
+            Some(tx_count) => tx_count,
+            None if stats.funded_count == 0 => 0,
+            None => address_txids(&state.indexer, &address, None, usize::MAX)?.len() as u64,
+        };
+        Ok(Json(json!({
+            "address": address,
+            "chain_stats": {
+                "funded_txo_count": stats.funded_count,
+                "funded_txo_sum": stats.received,
+                "spent_txo_count": stats.spent_count,
+                "spent_txo_sum": stats.sent,
+                "tx_count": tx_count,
+            },
+            "mempool_stats": {
+                "funded_txo_count": 0,
+                "funded_txo_sum": 0,
+                "spent_txo_count": 0,
+                "spent_txo_sum": 0,
+                "tx_count": 0,
+            },
+        })))
+    })
+    .await
+}
+
+async fn get_address_txs<I>(
+    State(state): State<EsploraState<I>>,
+    Path(address): Path<String>,
+) -> EsploraResult
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    parse_address(&address, state.network)?;
+    query(state, move |state| address_txs(state, &address, None)).await
+}
+
+async fn get_address_txs_chain<I>(
+    State(state): State<EsploraState<I>>,
+    Path((address, last_seen_txid)): Path<(String, String)>,
+) -> EsploraResult
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    parse_address(&address, state.network)?;
+    let last_seen_txid = parse_txid(&last_seen_txid)?;
+    query(state, move |state| {
+        address_txs(state, &address, Some(last_seen_txid))
+    })
+    .await
+}
+
+async fn get_address_utxo<I>(
+    State(state): State<EsploraState<I>>,
+    Path(address): Path<String>,
+) -> EsploraResult
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    parse_address(&address, state.network)?;
+    query(state, move |state| {
+        let indexer = &state.indexer;
+        let mut utxos = Vec::new();
+        for (utxo, value) in indexer.get_utxos(&address)? {
+            let status = match indexer.get_tx_location(&utxo.tx_id)? {
+                Some(location) => status(indexer, location.height)?,
+                None => json!({ "confirmed": true }),
+            };
+            utxos.push(json!({
+                "txid": utxo.tx_id.to_string(),
+                "vout": utxo.index,
+                "status": status,
+                "value": value,
+            }));
+        }
+        Ok(Json(json!(utxos)))
+    })
+    .await
+}
+
+async fn get_tx<I>(State(state): State<EsploraState<I>>, Path(txid): Path<String>) -> EsploraResult
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    let txid = parse_txid(&txid)?;
+    query(state, move |state| {
+        let mut blocks = Blocks::new(state)?;
+        let (location, tx) = blocks
+            .transaction(&txid)?
+            .ok_or_else(|| EsploraError::not_found("Transaction not found"))?;
+        Ok(Json(tx_value(&mut blocks, &location, &tx, state.network)?))
+    })
+    .await
+}
+
+// Spends are the input flow rows of the address each output was indexed under
+async fn get_tx_outspends<I>(
+    State(state): State<EsploraState<I>>,
+    Path(txid): Path<String>,
+) -> EsploraResult
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    let txid = parse_txid(&txid)?;
+    query(state, move |state| {
+        let indexer = &state.indexer;
+        let (_, tx) = Blocks::new(state)?
+            .transaction(&txid)?
+            .ok_or_else(|| EsploraError::not_found("Transaction not found"))?;
+        let sum_tx = SumTx::new(tx, state.network);
+        let mut outspends = Vec::with_capacity(sum_tx.outs.len());
+        for utxo in &sum_tx.outs {
+            if utxo.address == OP_RETURN {
+                outspends.push(json!({ "spent": false }));
+                continue;
+            }
+            let outpoint = IndexedTxid {
+                tx_id: txid,
+                index: utxo.index,
+            };
+            let spend = indexer.get_flow(&utxo.address, &Flow::I, &outpoint)?;
+            outspends.push(match spend {
+                Some(flow_value) => match &flow_value.spent_by {
+                    Some(spend) => json!({
+                        "spent": true,
+                        "txid": spend.tx_id.to_string(),
+                        "vin": spend.vin,
+                        "status": status(indexer, spend.height)?,
+                    }),
+                    None => json!({ "spent": true }),
+                },
+                None => json!({ "spent": false }),
+            });
+        }
+        Ok(Json(json!(outspends)))
+    })
+    .await
+}
+
+async fn get_block<I>(
+    State(state): State<EsploraState<I>>,
+    Path(hash): Path<String>,
+) -> EsploraResult
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    let hash =
+        BlockHash::from_str(&hash).map_err(|_| EsploraError::bad_request("Invalid hex string"))?;
+    query(state, move |state| {
+        let not_found = || EsploraError::not_found("Block not found");
+        let height = state
+            .indexer
+            .get_block_height(&hash)?
+            .ok_or_else(not_found)?;
+        let record = state.indexer.get_header(height)?;
+        let mut blocks = Blocks::new(state)?;
+        let block = &blocks.block(height)?.ok_or_else(not_found)?.block;
+        let header = &block.header;
+        Ok(Json(json!({
+            "id": hash.to_string(),
+            "height": height,
+            "version": header.version.to_consensus(),
+            "timestamp": header.time,
+            "tx_count": block.txdata.len(),
+            "size": serialize(block).len(),
+            "weight": block.weight().to_wu(),
+            "merkle_root": header.merkle_root.to_string(),
+            "previousblockhash": (height > 0).then(|| header.prev_blockhash.to_string()),
+            "mediantime": record.and_then(|record| record.median_time),
+            "nonce": header.nonce,
+            "bits": header.bits.to_consensus(),
+            "difficulty": header.difficulty_float(),
+        })))
+    })
+    .await
+}
+
+async fn get_tip_height<I>(State(state): State<EsploraState<I>>) -> Result<String, EsploraError>
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    query(state, |state| {
+        Ok(state.indexer.get_last_height().to_string())
+    })
+    .await
+}
@@ -0,0 +1,98 @@
+use crate::codec::decode_history_row;
+use crate::indexer::IndexerError;
+use crate::model::{AddressFlow, BalanceDeltas};
+use bitcoin::BlockHash;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+// Events a subscriber may fall behind by, a subscriber further behind misses some of them
+const EVENT_CAPACITY: usize = 1024;
+
+// What a commit or a rollback changed, published once it is durable
+#[derive(Debug)]
+pub struct IndexEvent {
+    // one more than that of the previous event
+    pub sequence: u64,
+    // tip after the commit or the rollback
+    pub height: u64,
+    pub hash: Option<BlockHash>,
+    // a rollback undid the blocks above the height, its deltas take back what they changed
+    pub rollback: bool,
+    // flow rows a commit wrote, in the order of its blocks and transactions
+    pub flows: Vec<(AddressFlow, u64)>,
+    // net change of the balance of each address whose balance changed
+    pub deltas: HashMap<String, i64>,
+}
+
+// Publishes the events of an engine to its subscribers, shared by the clones of the engine
+#[derive(Clone)]
+pub struct EventPublisher {
+    sender: broadcast::Sender<Arc<IndexEvent>>,
+    sequence: Arc<AtomicU64>,
+}
+
+impl Default for EventPublisher {
+    fn default() -> Self {
+        EventPublisher {
+            sender: broadcast::channel(EVENT_CAPACITY).0,
+            sequence: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl EventPublisher {
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<IndexEvent>> {
+        self.sender.subscribe()
+    }
+
+    // Engines only collect the flow rows of a commit while someone listens
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    // Sequence of the last event published, read under the db lock a balance is read under it
+    // tells which events the balance includes
+    pub fn sequence(&self) -> u64 {
+        self.sequence.load(Ordering::SeqCst)
+    }
+
+    // Publishes the flows and balance deltas of a commit or a rollback, with the db write lock still
+    // held so that no read sees its rows without the sequence of its event
+    pub fn publish(
+        &self,
+        height: u64,
+        hash: Option<BlockHash>,
+        rollback: bool,
+        flows: Vec<(AddressFlow, u64)>,
+        balances: &BalanceDeltas,
+    ) {
+        let sequence = self.sequence.fetch_add(1, Ordering::SeqCst) + 1;
+        let event = IndexEvent {
+            sequence,
+            height,
+            hash,
+            rollback,
+            flows,
+            deltas: balances
+                .iter()
+                .map(|(address, delta)| (address.clone(), delta))
+                .collect(),
+        };
+        // there may be no subscriber left
+        let _ = self.sender.send(Arc::new(event));
+    }
+}
+
+// Flows of the address history rows a commit wrote, decoded before the commit so that publishing
+// it cannot fail
+pub fn decode_flows(
+    history_rows: &[(Vec<u8>, Vec<u8>)],
+) -> Result<Vec<(AddressFlow, u64)>, IndexerError> {
+    history_rows
+        .iter()
+        .map(|(key, value)| decode_history_row(key, value))
+        .collect::<Result<_, _>>()
+        .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))
+}
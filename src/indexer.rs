@@ -1,4 +1,16 @@
-use crate::model::SumTx;
+use crate::cache::UtxoCache;
+use crate::events::IndexEvent;
+use crate::model::{
+    AddressFlow, AddressStats, Flow, FlowValue, HeaderRecord, HistoryFilter, IndexedBlock,
+    IndexedTxid, ScriptHash, SumTx, TxLocation, Utxo,
+};
+use crate::source::{BlockSource, SourceError};
+use bitcoin::block::Header;
+use bitcoin::{BlockHash, Network, Transaction, Txid};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::task::{self, JoinError};
 
 // define new module indexer
 
@@ -6,6 +18,31 @@ use crate::model::SumTx;
 pub enum IndexerError {
     RocksDbError(String),
     SledError(String),
+    ParseError(String),
+    RollbackError(String),
+    CodecError(String),
+    NetworkError(String),
+    SourceError(String),
+    // an input of the block at the height spends an output the utxo set does not hold
+    MissingUtxo {
+        height: u64,
+        outpoint: IndexedTxid,
+    },
+    InvalidUtxo {
+        height: u64,
+        outpoint: IndexedTxid,
+        error: String,
+    },
+    // a commit or rollback would take the stored balance of the address below zero
+    InvalidBalance {
+        address: String,
+        balance: u64,
+        delta: i64,
+    },
+    // the db was migrated at the height without the history of scripts, reindex to query them
+    MissingScriptHistory {
+        height: u64,
+    },
 }
 
 impl From<rocksdb::Error> for IndexerError {
@@ -14,16 +51,228 @@ impl From<rocksdb::Error> for IndexerError {
     }
 }
 
+impl From<SourceError> for IndexerError {
+    fn from(error: SourceError) -> Self {
+        IndexerError::SourceError(error.to_string())
+    }
+}
+
 impl From<std::io::Error> for IndexerError {
     fn from(error: std::io::Error) -> Self {
         IndexerError::RocksDbError(error.to_string())
     }
 }
 
+// Runs a query of the servers off the async runtime, db reads may take a while and would stall
+// the sync stream and the other connections sharing its threads
+pub async fn blocking_query<T, F>(query: F) -> Result<T, JoinError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    task::spawn_blocking(query).await
+}
+
 pub trait Indexer {
-    fn update_balance(&mut self, height: u64, sum_txs: &Vec<SumTx>) -> Result<(), IndexerError>;
+    fn update_balance(
+        &mut self,
+        height: u64,
+        header: &Header,
+        sum_txs: &Vec<SumTx>,
+    ) -> Result<(), IndexerError> {
+        self.update_blocks(&[IndexedBlock {
+            height,
+            header: *header,
+            sum_txs: sum_txs.clone(),
+        }])
+    }
+
+    // Indexes consecutive blocks in a single commit, the last height becomes that of the last block
+    fn update_blocks(&mut self, blocks: &[IndexedBlock]) -> Result<(), IndexerError>;
+
     fn get_last_height(&self) -> u64;
-    fn new(num_cores: i32, db_path: &str) -> Result<Self, IndexerError>
+
+    // Height of the block to index next, genesis on an empty db
+    fn get_next_height(&self) -> u64;
+
+    // Hash of the block indexed at the height, if any
+    fn get_block_hash(&self, height: u64) -> Result<Option<BlockHash>, IndexerError>;
+
+    // Header of the block indexed at the height, none for blocks indexed before headers were kept
+    fn get_header(&self, height: u64) -> Result<Option<HeaderRecord>, IndexerError>;
+
+    // Height of the indexed block with the hash, none for blocks off the indexed chain
+    fn get_block_height(&self, block_hash: &BlockHash) -> Result<Option<u64>, IndexerError>;
+
+    // Height of the last block whose median time past is at or before the time, median time past
+    // never decreases along the chain so that the headers can be bisected, none when a header
+    // on the way is unknown
+    fn get_height_at_time(&self, time: u32) -> Result<Option<u64>, IndexerError> {
+        let (mut low, mut high) = (0, self.get_last_height() + 1);
+        while low < high {
+            let height = low + (high - low) / 2;
+            match self
+                .get_header(height)?
+                .and_then(|record| record.median_time)
+            {
+                Some(median_time) if median_time <= time => low = height + 1,
+                Some(_) => high = height,
+                None => return Ok(None),
+            }
+        }
+        Ok(low.checked_sub(1))
+    }
+
+    // Block height and position of the transaction, none for transactions not indexed or indexed
+    // before the txid index was kept
+    fn get_tx_location(&self, txid: &Txid) -> Result<Option<TxLocation>, IndexerError>;
+
+    // Transaction with its location, taken out of the block read from the source, none when the
+    // block of the source at that height is not the indexed one
+    fn get_transaction<S: BlockSource + ?Sized>(
+        &self,
+        source: &S,
+        txid: &Txid,
+    ) -> Result<Option<(TxLocation, Transaction)>, IndexerError> {
+        let Some(location) = self.get_tx_location(txid)? else {
+            return Ok(None);
+        };
+        let source_block = source.get_block(location.height)?;
+        if self.get_block_hash(location.height)? != Some(source_block.hash) {
+            return Ok(None);
+        }
+        match source_block.block.txdata.into_iter().nth(location.position) {
+            Some(tx) if tx.compute_txid() == *txid => Ok(Some((location, tx))),
+            _ => Err(IndexerError::SourceError(format!(
+                "Transaction {} not in block @ {}",
+                txid, location.height
+            ))),
+        }
+    }
+
+    // Undoes all blocks above the height using their undo records, newest first
+    fn rollback(&mut self, height: u64) -> Result<(), IndexerError>;
+
+    // Writes out the utxo cache and makes all committed blocks durable, before the process exits
+    fn flush(&self) -> Result<(), IndexerError>;
+
+    // All flow rows of the address with their values, as stored under the `address|` prefix
+    fn get_history(&self, address: &str) -> Result<Vec<(AddressFlow, u64)>, IndexerError>;
+
+    // The flow row of the address for the output, an input row says what spent it
+    fn get_flow(
+        &self,
+        address: &str,
+        flow: &Flow,
+        outpoint: &IndexedTxid,
+    ) -> Result<Option<FlowValue>, IndexerError>;
+
+    // Flows of the address matching the filter in the order of their blocks and transactions,
+    // outputs before the inputs of a transaction, seeking the history to the start height. Flows
+    // indexed before heights were recorded come first, those indexed before positions come first
+    // in their block
+    fn get_history_filtered(
+        &self,
+        address: &str,
+        filter: &HistoryFilter,
+    ) -> Result<Vec<(AddressFlow, u64)>, IndexerError>;
+
+    // Flows of the last transactions of the address below the height and position, newest first,
+    // reading the history backwards until that many transactions are found. Flows indexed before
+    // heights or positions were recorded come last
+    fn get_history_page(
+        &self,
+        address: &str,
+        before: Option<(u64, usize)>,
+        tx_count: usize,
+    ) -> Result<Vec<(AddressFlow, u64)>, IndexerError>;
+
+    // Outputs of the address that have no matching input flow yet
+    fn get_utxos(&self, address: &str) -> Result<Vec<(IndexedTxid, u64)>, IndexerError> {
+        let history = self.get_history(address)?;
+        let spent: HashSet<(Txid, usize)> = history
+            .iter()
+            .filter(|(flow, _)| matches!(flow.flow, Flow::I))
+            .map(|(flow, _)| (flow.tx_id, flow.utxo_index))
+            .collect();
+        let utxos = history
+            .iter()
+            .filter(|(flow, _)| matches!(flow.flow, Flow::O))
+            .filter(|(flow, _)| !spent.contains(&(flow.tx_id, flow.utxo_index)))
+            .map(|(flow, value)| {
+                (
+                    IndexedTxid {
+                        tx_id: flow.tx_id,
+                        index: flow.utxo_index,
+                    },
+                    *value,
+                )
+            })
+            .collect();
+        Ok(utxos)
+    }
+
+    // Output at the outpoint while it is unspent, outputs not flushed yet are read from the cache
+    fn get_utxo(&self, outpoint: &IndexedTxid) -> Result<Option<Utxo>, IndexerError>;
+
+    // Sum of all output flows netted against all input flows of the address, kept up to date as
+    // blocks are indexed and rolled back
+    fn get_balance(&self, address: &str) -> Result<u64, IndexerError>;
+
+    // Events of the commits and rollbacks from now on
+    fn subscribe(&self) -> broadcast::Receiver<Arc<IndexEvent>>;
+
+    // Balance of the address with the sequence of the last event it includes
+    fn get_balance_at_event(&self, address: &str) -> Result<(u64, u64), IndexerError>;
+
+    // Activity of the address as a single row, none for an address never seen
+    fn get_address_stats(&self, address: &str) -> Result<Option<AddressStats>, IndexerError>;
+
+    // Address the outputs of the script were indexed under, none for a script never funded,
+    // p2pk outputs share the one of their p2pkh address
+    fn get_script_address(&self, script_hash: &ScriptHash) -> Result<Option<String>, IndexerError>;
+
+    // Flows of exactly the script as flows of the address it is indexed under, ordered by block and
+    // position within it, outputs of a transaction before its inputs
+    fn get_script_history(
+        &self,
+        script_hash: &ScriptHash,
+    ) -> Result<Vec<(AddressFlow, u64)>, IndexerError>;
+
+    // Unspent outputs of exactly the script, the outputs of its history no input of it spends
+    fn get_script_utxos(
+        &self,
+        script_hash: &ScriptHash,
+    ) -> Result<Vec<(IndexedTxid, u64)>, IndexerError> {
+        let history = self.get_script_history(script_hash)?;
+        let spent: HashSet<(Txid, usize)> = history
+            .iter()
+            .filter(|(flow, _)| matches!(flow.flow, Flow::I))
+            .map(|(flow, _)| (flow.tx_id, flow.utxo_index))
+            .collect();
+        Ok(history
+            .into_iter()
+            .filter(|(flow, _)| {
+                matches!(flow.flow, Flow::O) && !spent.contains(&(flow.tx_id, flow.utxo_index))
+            })
+            .map(|(flow, value)| {
+                let outpoint = IndexedTxid {
+                    tx_id: flow.tx_id,
+                    index: flow.utxo_index,
+                };
+                (outpoint, value)
+            })
+            .collect())
+    }
+
+    // Refuses a db built for another network than the one addresses are derived for, blocks
+    // indexed after the last flush of the utxo cache are undone so that syncing resumes from it
+    fn new(
+        num_cores: i32,
+        db_path: &str,
+        network: Network,
+        utxo_cache: UtxoCache,
+    ) -> Result<Self, IndexerError>
     where
         Self: Sized;
 }
@@ -1,3 +1,16 @@
+pub mod blk;
+pub mod cache;
+pub mod codec;
+pub mod electrum;
+pub mod esplora;
+pub mod events;
 pub mod indexer;
 pub mod logger;
 pub mod model;
+pub mod process;
+pub mod rocksdb;
+pub mod sleddb;
+pub mod source;
+pub mod sync;
+pub mod websocket;
+pub mod zmq;
@@ -1,15 +1,25 @@
+use bitcoin::Network;
 use core::panic;
-use futures::stream::StreamExt;
+use index_btc::blk::BlkReader;
+use index_btc::cache::UtxoCache;
+use index_btc::codec::LEGACY_SCHEMA_VERSION;
+use index_btc::electrum;
 use index_btc::indexer::Indexer;
-use rocksdb::RocksDbIndexer;
-use sleddb::SledDbIndexer;
+use index_btc::rocksdb::RocksDbIndexer;
+use index_btc::sleddb::SledDbIndexer;
+use index_btc::source::BlockSource;
+use index_btc::sync::{self, SyncError, SyncSettings, SyncStats};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::{env, ops::Deref};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task;
 
 mod logger;
-mod process;
-mod rocksdb;
 mod rpc;
-mod sleddb;
+mod server;
 
 use clap::{Arg, ArgAction, Command};
 
@@ -35,6 +45,12 @@ fn cli() -> Command {
                 .num_args(1)
                 .default_value("http://127.0.0.1:8332")
                 .help("Url of local bitcoin-core"),
+            Arg::new("blocks-dir")
+                .long("blocks-dir")
+                .action(ArgAction::Set)
+                .require_equals(true)
+                .num_args(1)
+                .help("Blocks directory of bitcoin-core, initial sync reads its blk*.dat files instead of rpc"),
             Arg::new("db-engine")
                 .long("db-engine")
                 .action(ArgAction::Set)
@@ -42,8 +58,135 @@ fn cli() -> Command {
                 .allow_hyphen_values(true)
                 .num_args(1)
                 .default_value("rocks-db")
+                .value_parser(["rocks-db", "sled-db"])
                 .help("rocks-db or sled-db"),
+            Arg::new("network")
+                .long("network")
+                .action(ArgAction::Set)
+                .require_equals(true)
+                .num_args(1)
+                .default_value("bitcoin")
+                .value_parser(["bitcoin", "testnet", "testnet4", "signet", "regtest"])
+                .help("Network of bitcoin-core, addresses are derived for it"),
+            Arg::new("http-addr")
+                .long("http-addr")
+                .action(ArgAction::Set)
+                .require_equals(true)
+                .num_args(1)
+                .help("Address to serve the http api at, like 127.0.0.1:3000"),
+            Arg::new("electrum-addr")
+                .long("electrum-addr")
+                .action(ArgAction::Set)
+                .require_equals(true)
+                .num_args(1)
+                .help("Address to serve the electrum protocol at, like 127.0.0.1:50001"),
+            Arg::new("start-height")
+                .long("start-height")
+                .action(ArgAction::Set)
+                .require_equals(true)
+                .num_args(1)
+                .value_parser(clap::value_parser!(u64))
+                .help("Height to start syncing from, at most the last indexed height + 1, lower heights roll the index back"),
+            Arg::new("end-height")
+                .long("end-height")
+                .action(ArgAction::Set)
+                .require_equals(true)
+                .num_args(1)
+                .value_parser(clap::value_parser!(u64))
+                .help("Height to stop syncing at, otherwise new blocks are followed at the tip"),
+            Arg::new("commit-blocks")
+                .long("commit-blocks")
+                .action(ArgAction::Set)
+                .require_equals(true)
+                .num_args(1)
+                .default_value("100")
+                .value_parser(clap::value_parser!(usize))
+                .help("Blocks per db commit far from the tip, 1 commits each block"),
+            Arg::new("commit-mib")
+                .long("commit-mib")
+                .action(ArgAction::Set)
+                .require_equals(true)
+                .num_args(1)
+                .default_value("32")
+                .value_parser(clap::value_parser!(usize))
+                .help("Rows in MiB a db commit holds at most, whatever its block count"),
+            Arg::new("dbcache")
+                .long("dbcache")
+                .action(ArgAction::Set)
+                .require_equals(true)
+                .num_args(1)
+                .default_value("450")
+                .value_parser(clap::value_parser!(usize))
+                .help("Utxo cache size in MiB, 0 writes outputs through to the db"),
+            Arg::new("flush-blocks")
+                .long("flush-blocks")
+                .action(ArgAction::Set)
+                .require_equals(true)
+                .num_args(1)
+                .default_value("2000")
+                .value_parser(clap::value_parser!(u64))
+                .help("Most blocks between utxo cache flushes"),
+            Arg::new("zmq-url")
+                .long("zmq-url")
+                .action(ArgAction::Set)
+                .require_equals(true)
+                .num_args(1)
+                .help(
+                    "Zmq endpoint of bitcoin-core block notifications, like tcp://127.0.0.1:28332",
+                ),
         ])
+        .subcommand(
+            Command::new("migrate")
+                .about("Upgrades a db written by an older version to the current schema")
+                .long_about(
+                    "Upgrades a db written by an older version to the current schema. Rocks-db dbs \
+                     of the first versions never had their address rows written, there is nothing \
+                     to migrate and they must be synced again into a new db",
+                ),
+        )
+}
+
+struct Settings {
+    bitcoin_url: String,
+    http_addr: Option<String>,
+    electrum_addr: Option<String>,
+    start_height: Option<u64>,
+    blocks_dir: Option<String>,
+    sync: SyncSettings,
+}
+
+// The first signal lets blocks in flight be committed, a second one exits right away
+fn listen_for_shutdown() -> watch::Receiver<bool> {
+    let (sender, receiver) = watch::channel(false);
+    tokio::spawn(async move {
+        let (mut sigint, mut sigterm) = match (
+            signal(SignalKind::interrupt()),
+            signal(SignalKind::terminate()),
+        ) {
+            (Ok(sigint), Ok(sigterm)) => (sigint, sigterm),
+            (Err(e), _) | (_, Err(e)) => {
+                log!(
+                    "Signal handlers unavailable, shutdown is not graceful : {}",
+                    e
+                );
+                // keeps the sender alive, a closed channel would read as a shutdown request
+                return std::future::pending::<()>().await;
+            }
+        };
+        loop {
+            tokio::select! {
+                _ = sigint.recv() => {}
+                _ = sigterm.recv() => {}
+            }
+            if *sender.borrow() {
+                log!("Shutdown requested again, exiting");
+                std::process::exit(1);
+            }
+            log!("Shutdown requested, committing blocks in flight");
+            let _ = sender.send(true);
+        }
+    });
+    receiver
 }
 
 #[tokio::main]
@@ -64,66 +207,207 @@ async fn main() -> Result<(), std::io::Error> {
         .map(|s| s.deref())
         .unwrap();
     log!("Using db engine : {}", db_engine);
+    let network = matches
+        .get_one::<String>("network")
+        .map(|s| Network::from_str(s).unwrap())
+        .unwrap();
+    log!("Using network : {}", network);
     let full_db_path = format!("{}/{}", db_path, db_engine);
-    let mut indexer: Box<dyn Indexer> = match db_engine {
-        "rocks-db" => Box::new(RocksDbIndexer::new(num_cores as i32, &full_db_path).unwrap()),
-        "sled-db" => Box::new(SledDbIndexer::new(num_cores as i32, &full_db_path).unwrap()),
-        x => panic!("Error: db-engine {} not supported", x),
+    if let Some(("migrate", _)) = matches.subcommand() {
+        exit_on_error(migrate_db(db_engine, &full_db_path, num_cores as i32));
+        return Ok(());
+    }
+    let settings = Settings {
+        bitcoin_url: bitcoin_url.clone(),
+        http_addr: matches.get_one::<String>("http-addr").cloned(),
+        electrum_addr: matches.get_one::<String>("electrum-addr").cloned(),
+        start_height: matches.get_one::<u64>("start-height").copied(),
+        blocks_dir: matches.get_one::<String>("blocks-dir").cloned(),
+        sync: SyncSettings {
+            parallelism: num_cores / 2,
+            network,
+            zmq_url: matches.get_one::<String>("zmq-url").cloned(),
+            end_height: matches.get_one::<u64>("end-height").copied(),
+            group_commit_blocks: *matches.get_one::<usize>("commit-blocks").unwrap(),
+            group_commit_bytes: *matches.get_one::<usize>("commit-mib").unwrap() * 1024 * 1024,
+            shutdown: listen_for_shutdown(),
+        },
     };
+    let dbcache = *matches.get_one::<usize>("dbcache").unwrap();
+    let flush_interval = *matches.get_one::<u64>("flush-blocks").unwrap();
+    log!(
+        "Using utxo cache of {} MiB, flushed every {} blocks",
+        dbcache,
+        flush_interval
+    );
+    let utxo_cache = UtxoCache::new(dbcache * 1024 * 1024, flush_interval);
+    match db_engine {
+        "rocks-db" => {
+            let indexer = exit_on_error(
+                RocksDbIndexer::new(num_cores as i32, &full_db_path, network, utxo_cache)
+                    .map_err(SyncError::Db),
+            );
+            exit_on_error(run(indexer, settings).await)
+        }
+        "sled-db" => {
+            let indexer = exit_on_error(
+                SledDbIndexer::new(num_cores as i32, &full_db_path, network, utxo_cache)
+                    .map_err(SyncError::Db),
+            );
+            exit_on_error(run(indexer, settings).await)
+        }
+        x => exit_on_error(Err(unsupported_db_engine(x))),
+    }
+    Ok(())
+}
+
+// The index is left consistent at the last indexed block, so a failed sync exits without unwinding
+fn exit_on_error<T>(result: Result<T, SyncError>) -> T {
+    result.unwrap_or_else(|e| {
+        log!("Error: {}", e);
+        std::process::exit(1)
+    })
+}
+
+fn unsupported_db_engine(db_engine: &str) -> SyncError {
+    SyncError::Config(format!("db-engine {} not supported", db_engine))
+}
 
-    let (username, password) = match (
+// Legacy sled dbs are converted into a new db next to the `.legacy` original, newer ones are upgraded in place.
+// Legacy rocks dbs have no address rows to convert, migrating refuses them
+fn migrate_db(db_engine: &str, db_path: &str, num_cores: i32) -> Result<(), SyncError> {
+    match db_engine {
+        "rocks-db" => return RocksDbIndexer::migrate(num_cores, db_path).map_err(SyncError::Db),
+        "sled-db" => {}
+        x => return Err(unsupported_db_engine(x)),
+    }
+    let schema_version = |path: &str| -> Result<Option<u32>, SyncError> {
+        if !Path::new(path).exists() {
+            return Ok(None);
+        }
+        SledDbIndexer::schema_version(num_cores, path).map_err(SyncError::Db)
+    };
+    let legacy_db_path = format!("{}.legacy", db_path);
+    if schema_version(db_path)? == Some(LEGACY_SCHEMA_VERSION) {
+        std::fs::rename(db_path, &legacy_db_path)?;
+    }
+    // schema version is written at the very end, without it the conversion was interrupted
+    if Path::new(&legacy_db_path).exists() && schema_version(db_path)?.is_none() {
+        if Path::new(db_path).exists() {
+            std::fs::remove_dir_all(db_path)?;
+        }
+        log!("Converting {} into {}", legacy_db_path, db_path);
+        SledDbIndexer::convert_legacy(num_cores, db_path, &legacy_db_path)
+            .map_err(SyncError::Db)?;
+    }
+    SledDbIndexer::migrate(num_cores, db_path).map_err(SyncError::Db)
+}
+
+async fn run<I>(mut indexer: I, settings: Settings) -> Result<(), SyncError>
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    let rpc_client = match (
         env::var("BITCOIN_RPC_USERNAME"),
         env::var("BITCOIN_RPC_PASSWORD"),
     ) {
-        (Ok(user), Ok(pass)) => (user, pass),
-        _ => {
-            panic!("Error: Bitcoin RPC BITCOIN_RPC_PASSWORD or BITCOIN_RPC_USERNAME environment variable not set");
-        }
+        (Ok(username), Ok(password)) => Some(Arc::new(rpc::RpcClient::new(
+            settings.bitcoin_url.clone(),
+            username,
+            password,
+        ))),
+        _ => None,
     };
+    let server = settings.http_addr.clone().map(|http_addr| {
+        tokio::spawn(server::serve(
+            indexer.clone(),
+            rpc_client
+                .clone()
+                .map(|rpc_client| rpc_client as Arc<dyn BlockSource>),
+            settings.sync.network,
+            http_addr,
+            settings.sync.shutdown_requested(),
+        ))
+    });
+    let electrum_server = settings.electrum_addr.clone().map(|electrum_addr| {
+        tokio::spawn(electrum::serve(
+            indexer.clone(),
+            rpc_client
+                .clone()
+                .map(|rpc_client| rpc_client as Arc<dyn BlockSource>),
+            settings.sync.network,
+            electrum_addr,
+            settings.sync.shutdown_requested(),
+        ))
+    });
 
-    let rpc_client = rpc::RpcClient::new(bitcoin_url.clone(), username, password);
+    let mut stats = SyncStats::new();
+    let mut from_height = sync::start_height(&mut indexer, settings.start_height)?;
 
-    let from_height: u64 = indexer.get_last_height() + 1;
-    let end_height: u64 = 844566;
-    let start_time = std::time::Instant::now();
-    let parallelism = num_cores / 2;
-    log!(
-        "Initiating syncing from {} to {} with parallelism {}",
-        from_height,
-        end_height,
-        parallelism
-    );
-    let blocks_count = rpc_client
-        .fetch_blocks(from_height, end_height)
-        .map(|result| async move {
-            match result {
-                Ok((height, block)) => {
-                    let sum_txs = process::process_txs(parallelism, block.txdata).await;
-                    Ok((height, sum_txs))
-                }
-                Err(e) => Err(e.to_string()),
-            }
-        })
-        .buffered(128)
-        .map(|result| match result {
-            Ok((height, sum_txs)) => {
-                indexer.update_balance(height, &sum_txs).unwrap();
-                (height, sum_txs.len())
-            }
-            Err(e) => {
-                panic!("Error: {}", e);
-            }
-        })
-        .fold(0 as u64, |total_tx_count, (height, tx_count)| async move {
-            if height % 1000 == 0 {
-                let total_time = start_time.elapsed().as_secs();
-                let txs_per_sec = format!("{:.1}", total_tx_count as f64 / total_time as f64);
-                log!("Indexing Speed: {} txs/sec", txs_per_sec);
-            }
-            total_tx_count + tx_count as u64
-        })
-        .await;
+    // initial sync reads the blk files of bitcoin-core, rpc is only needed to follow the tip afterwards
+    if let Some(blocks_dir) = &settings.blocks_dir {
+        // scanning the headers of every blk file takes a while, the servers keep running meanwhile
+        let (blocks_dir, network) = (blocks_dir.clone(), settings.sync.network);
+        let blk_reader = task::spawn_blocking(move || BlkReader::open(&blocks_dir, network))
+            .await
+            .map_err(std::io::Error::other)??;
+        let blk_reader = Arc::new(blk_reader);
+        let blk_height = blk_reader
+            .get_block_count()
+            .map_err(|error| SyncError::Source {
+                height: from_height,
+                error,
+            })?;
+        let to_height = settings
+            .sync
+            .end_height
+            .map_or(blk_height, |end_height| end_height.min(blk_height));
+        from_height = sync::sync_blocks(
+            &blk_reader,
+            &mut indexer,
+            &settings.sync,
+            from_height,
+            to_height,
+            &mut stats,
+        )
+        .await?;
+    }
+
+    match rpc_client {
+        Some(rpc_client) => {
+            sync::follow_tip(
+                &rpc_client,
+                &mut indexer,
+                &settings.sync,
+                from_height,
+                &mut stats,
+            )
+            .await?;
+        }
+        None if settings.blocks_dir.is_some() => {
+            log!("Bitcoin RPC credentials not set, stopping at the tip of blk files");
+        }
+        None => {
+            return Err(SyncError::Config(
+                "Bitcoin RPC BITCOIN_RPC_PASSWORD or BITCOIN_RPC_USERNAME environment variable not set"
+                    .to_string(),
+            ));
+        }
+    }
 
-    log!("Processed {} blocks", blocks_count);
+    log!("Processed {} txs", stats.total_tx_count);
+    let last_height = indexer.get_last_height();
+    let next_height = indexer.get_next_height();
+    indexer.flush().map_err(|error| SyncError::Indexer {
+        height: last_height,
+        error,
+    })?;
+    if let Some(server) = server {
+        server.await.map_err(std::io::Error::other)??;
+    }
+    if let Some(electrum_server) = electrum_server {
+        electrum_server.await.map_err(std::io::Error::other)??;
+    }
+    log!("Index flushed, resuming from height {}", next_height);
     return Ok(());
 }
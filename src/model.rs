@@ -1,5 +1,10 @@
-use bitcoin::{Address, Network, Transaction};
+use crate::codec::decode_flow_value;
+use crate::indexer::IndexerError;
+use bitcoin::block::Header;
+use bitcoin::pow::Work;
+use bitcoin::{Address, Network, Script, Transaction, Txid};
 use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::num::ParseIntError;
 use std::str::FromStr;
 use std::string::FromUtf8Error;
@@ -7,10 +12,40 @@ use std::{fmt, str};
 
 pub const OP_RETURN: &str = "OP_RETURN";
 pub const LAST_HEIGHT_KEY: &[u8] = b"last_height";
+pub const NETWORK_KEY: &[u8] = b"network";
+// Height up to which the utxo cache was flushed, blocks above it are undone when reopening the db
+pub const UTXO_FLUSH_HEIGHT_KEY: &[u8] = b"utxo_flush_height";
+// Height a db was migrated at without script history, script queries are refused on it
+pub const SCRIPT_HISTORY_HEIGHT_KEY: &[u8] = b"script_history_height";
 
 pub const ADDRESS_CF: &str = "ADDRESS_CF";
 pub const CACHE_CF: &str = "CACHE_CF";
 pub const META_CF: &str = "META_CF";
+pub const UNDO_CF: &str = "UNDO_CF";
+pub const BLOCK_HASH_CF: &str = "BLOCK_HASH_CF";
+// Balance of each address keyed by the address, addresses with nothing left have no row
+pub const ADDRESS_BALANCE_CF: &str = "ADDRESS_BALANCE_CF";
+// Activity stats of each address keyed by the address
+pub const ADDRESS_STATS_CF: &str = "ADDRESS_STATS_CF";
+// Header of each indexed block keyed by height, with its median time past and chainwork
+pub const HEADER_CF: &str = "HEADER_CF";
+// Height of each indexed block keyed by its hash
+pub const BLOCK_HEIGHT_CF: &str = "BLOCK_HEIGHT_CF";
+// Block height and position within the block of each indexed transaction keyed by its txid
+pub const TX_CF: &str = "TX_CF";
+// Address each output script was indexed under keyed by its Electrum script hash
+pub const SCRIPT_HASH_CF: &str = "SCRIPT_HASH_CF";
+// Flow rows of each address again, keyed in the order of their blocks and transactions
+pub const HISTORY_CF: &str = "HISTORY_CF";
+// Flow rows of each output script keyed by its script hash in the order of their blocks and
+// transactions, p2pk scripts are kept apart from their p2pkh address here
+pub const SCRIPT_HISTORY_CF: &str = "SCRIPT_HISTORY_CF";
+
+// Blocks the median time past is taken over, the block itself and the ones below it
+pub const MEDIAN_TIME_SPAN: u64 = 11;
+
+// Undo records older than this many blocks are pruned, deeper reorgs cannot be rolled back
+pub const MAX_REORG_DEPTH: u64 = 100;
 
 #[derive(Debug)]
 pub enum Flow {
@@ -22,8 +57,68 @@ pub enum Flow {
 pub struct AddressFlow {
     pub address: String,
     pub flow: Flow,
-    pub tx_id: String,
+    pub tx_id: Txid,
     pub utxo_index: usize,
+    // Input flows link to the funding outpoint above and say which transaction spent it
+    pub spent_by: Option<Spend>,
+    // Block the output was created or spent in, unknown for flows indexed before they were recorded
+    pub height: Option<u64>,
+    pub time: Option<u32>,
+    // Position in its block of the transaction that created or spent the output
+    pub position: Option<usize>,
+}
+
+// Value of a flow row with what it records beyond the amount
+#[derive(Debug)]
+pub struct FlowValue {
+    pub value: u64,
+    pub spent_by: Option<Spend>,
+    pub height: Option<u64>,
+    pub time: Option<u32>,
+    pub position: Option<usize>,
+}
+
+// Inclusive height and block time bounds of a history query, bounds not set are open
+#[derive(Debug, Default, Clone)]
+pub struct HistoryFilter {
+    pub start_height: Option<u64>,
+    pub end_height: Option<u64>,
+    pub start_time: Option<u32>,
+    pub end_time: Option<u32>,
+}
+
+impl HistoryFilter {
+    // Flows without a height or time only match while no bound of that kind is set
+    pub fn matches(&self, flow: &AddressFlow) -> bool {
+        fn within<T: PartialOrd>(value: Option<T>, start: Option<T>, end: Option<T>) -> bool {
+            match value {
+                Some(value) => {
+                    start.is_none_or(|start| value >= start) && end.is_none_or(|end| value <= end)
+                }
+                None => start.is_none() && end.is_none(),
+            }
+        }
+        within(flow.height, self.start_height, self.end_height)
+            && within(flow.time, self.start_time, self.end_time)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Spend {
+    pub tx_id: Txid,
+    pub vin: usize,
+    pub height: u64,
+}
+
+impl AddressFlow {
+    // Transaction that created or spent the output, none for spends indexed before their
+    // transaction was recorded
+    pub fn txid(&self) -> Option<Txid> {
+        match (&self.flow, &self.spent_by) {
+            (Flow::O, _) => Some(self.tx_id),
+            (Flow::I, spent_by) => spent_by.as_ref().map(|spend| spend.tx_id),
+        }
+    }
 }
 
 impl fmt::Display for AddressFlow {
@@ -68,14 +163,9 @@ pub enum UtxoParseError {
     InvalidFormat(String),
 }
 
-impl TryFrom<Vec<u8>> for AddressFlow {
-    type Error = UtxoParseError;
-
-    fn try_from(utxo_str: Vec<u8>) -> Result<Self, Self::Error> {
-        let utxo = String::from_utf8(utxo_str)
-            .map_err(|err| UtxoParseError::DecodingError(err))?
-            .parse()?;
-        Ok(utxo)
+impl From<ParseIntError> for UtxoParseError {
+    fn from(error: ParseIntError) -> Self {
+        UtxoParseError::ParseInt(error)
     }
 }
 
@@ -93,7 +183,7 @@ impl FromStr for AddressFlow {
 
         let address = parts[0].to_string();
         let flow: Flow = parts[1].parse()?;
-        let tx_id = parts[2].to_string();
+        let tx_id = parse_txid(parts[2])?;
         let utxo_index = parts[3]
             .parse::<usize>()
             .map_err(|err| UtxoParseError::ParseInt(err))?;
@@ -103,6 +193,10 @@ impl FromStr for AddressFlow {
             flow,
             tx_id,
             utxo_index,
+            spent_by: None,
+            height: None,
+            time: None,
+            position: None,
         })
     }
 }
@@ -110,22 +204,23 @@ impl FromStr for AddressFlow {
 #[derive(Debug, Clone)]
 pub struct SumTx {
     pub is_coinbase: bool,
-    pub txid: String,
+    pub txid: Txid,
     pub ins: Vec<IndexedTxid>,
     pub outs: Vec<Utxo>,
 }
 
-impl From<Transaction> for SumTx {
-    fn from(tx: Transaction) -> Self {
+impl SumTx {
+    // Addresses are derived for the network, so the same script renders differently on testnets
+    pub fn new(tx: Transaction, network: Network) -> Self {
         SumTx {
             is_coinbase: tx.is_coinbase(),
-            txid: tx.compute_txid().to_string(),
+            txid: tx.compute_txid(),
             ins: tx
                 .input
                 .iter()
                 .map(|input| IndexedTxid {
                     index: input.previous_output.vout as usize,
-                    tx_id: input.previous_output.txid.to_string(),
+                    tx_id: input.previous_output.txid,
                 })
                 .collect(),
             outs: tx
@@ -134,12 +229,11 @@ impl From<Transaction> for SumTx {
                 .enumerate()
                 .map(|(out_index, out)| {
                     let address = if let Ok(address) =
-                        Address::from_script(out.script_pubkey.as_script(), Network::Bitcoin)
+                        Address::from_script(out.script_pubkey.as_script(), network)
                     {
                         address.to_string()
                     } else if let Some(pk) = out.script_pubkey.p2pk_public_key() {
-                        bitcoin::Address::p2pkh(pk.pubkey_hash(), bitcoin::Network::Bitcoin)
-                            .to_string()
+                        bitcoin::Address::p2pkh(pk.pubkey_hash(), network).to_string()
                     } else if out.script_pubkey.is_op_return() {
                         OP_RETURN.to_string()
                     } else {
@@ -151,6 +245,7 @@ impl From<Transaction> for SumTx {
                         index: out_index,
                         address: address.to_string(),
                         value: out.value.to_sat(),
+                        script_hash: Some(ScriptHash::new(&out.script_pubkey)),
                     }
                 })
                 .collect(),
@@ -158,9 +253,47 @@ impl From<Transaction> for SumTx {
     }
 }
 
+// Sha256 of an output script in the reversed byte order Electrum displays it in, the key wallets
+// query a script by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ScriptHash(pub [u8; 32]);
+
+impl ScriptHash {
+    pub fn new(script: &Script) -> Self {
+        let mut hash: [u8; 32] = Sha256::digest(script.as_bytes()).into();
+        hash.reverse();
+        ScriptHash(hash)
+    }
+
+    // Script hash of the script an address stands for, none for strings that are no address
+    pub fn from_address(address: &str) -> Option<Self> {
+        Address::from_str(address)
+            .ok()
+            .map(|address| ScriptHash::new(&address.assume_checked().script_pubkey()))
+    }
+}
+
+impl fmt::Display for ScriptHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", base16::encode_lower(&self.0))
+    }
+}
+
+impl FromStr for ScriptHash {
+    type Err = UtxoParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        base16::decode(s)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(ScriptHash)
+            .ok_or_else(|| UtxoParseError::InvalidFormat(format!("Invalid script hash : {}", s)))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct IndexedTxid {
-    pub tx_id: String,
+    pub tx_id: Txid,
     pub index: usize,
 }
 
@@ -171,25 +304,38 @@ impl fmt::Display for IndexedTxid {
 }
 
 impl FromStr for IndexedTxid {
-    type Err = &'static str;
+    type Err = UtxoParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let parts: Vec<&str> = s.split('|').collect();
         if parts.len() != 2 {
-            return Err("Invalid format");
+            return Err(UtxoParseError::InvalidFormat(format!(
+                "Invalid IndexedTxid : {}",
+                s
+            )));
         }
 
-        let index = parts[0].parse::<usize>().map_err(|_| "Invalid value")?;
-        let tx_id = parts[1].to_string();
+        let tx_id = parse_txid(parts[0])?;
+        let index = parts[1]
+            .parse::<usize>()
+            .map_err(|err| UtxoParseError::ParseInt(err))?;
         Ok(IndexedTxid { tx_id, index })
     }
 }
 
+fn parse_txid(s: &str) -> Result<Txid, UtxoParseError> {
+    Txid::from_str(s)
+        .map_err(|err| UtxoParseError::InvalidFormat(format!("Invalid txid : {}", err)))
+}
+
 #[derive(Debug, Clone)]
 pub struct Utxo {
     pub index: usize,
     pub address: String,
     pub value: u64,
+    // Canonical key of the output script, also for scripts whose address is a digest of their asm,
+    // unknown for outputs cached before it was recorded
+    pub script_hash: Option<ScriptHash>,
 }
 
 impl fmt::Display for Utxo {
@@ -198,17 +344,6 @@ impl fmt::Display for Utxo {
     }
 }
 
-impl TryFrom<Vec<u8>> for Utxo {
-    type Error = UtxoParseError;
-
-    fn try_from(utxo_str: Vec<u8>) -> Result<Self, Self::Error> {
-        let utxo = String::from_utf8(utxo_str)
-            .map_err(|err| UtxoParseError::DecodingError(err))?
-            .parse()?;
-        Ok(utxo)
-    }
-}
-
 impl FromStr for Utxo {
     type Err = UtxoParseError;
 
@@ -233,6 +368,255 @@ impl FromStr for Utxo {
             index: utxo_index,
             address,
             value,
+            script_hash: None,
         })
     }
 }
+
+// Where an indexed transaction is, the block itself is read from the block source
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TxLocation {
+    pub height: u64,
+    pub position: usize,
+}
+
+// Header of an indexed block with what the chain up to it adds, unknown when blocks below it
+// were indexed without headers
+#[derive(Debug, Clone)]
+pub struct HeaderRecord {
+    pub header: Header,
+    pub median_time: Option<u32>,
+    pub chainwork: Option<Work>,
+}
+
+impl HeaderRecord {
+    // Derives the record of the block at the height from the records of the blocks below it,
+    // nearest last
+    pub fn new(header: Header, height: u64, below: &[Option<HeaderRecord>]) -> Self {
+        let span = height.min(MEDIAN_TIME_SPAN - 1) as usize;
+        let median_time = below[below.len().saturating_sub(span)..]
+            .iter()
+            .map(|record| record.as_ref().map(|record| record.header.time))
+            .collect::<Option<Vec<u32>>>()
+            .filter(|times| times.len() == span)
+            .map(|mut times| {
+                times.push(header.time);
+                times.sort_unstable();
+                times[times.len() / 2]
+            });
+        let chainwork = if height == 0 {
+            Some(header.work())
+        } else {
+            below
+                .last()
+                .and_then(|record| record.as_ref()?.chainwork)
+                .map(|chainwork| chainwork + header.work())
+        };
+        HeaderRecord {
+            header,
+            median_time,
+            chainwork,
+        }
+    }
+}
+
+// A block with its transactions summed up, ready to be written to the index
+#[derive(Debug, Clone)]
+pub struct IndexedBlock {
+    pub height: u64,
+    pub header: Header,
+    pub sum_txs: Vec<SumTx>,
+}
+
+// Bytes of the rows written per output, input and transaction besides the addresses they hold, at
+// current mainnet heights and block times. An output writes its flow row (53 bytes) and undo key
+// (36), its history row (65), its script history row (96) and undo key (79), its cache row (75) and
+// undo key (34) and its script hash row (32). An input writes its flow row (86), history row (98)
+// and script history row (129) and keeps its flow key (36), script history key (79) and the spent
+// cache row (77) for undo. A transaction writes its location row (37) and undo txid (32)
+const OUTPUT_WRITE_SIZE: usize = 470;
+const INPUT_WRITE_SIZE: usize = 505;
+const TX_WRITE_SIZE: usize = 69;
+// Rows above holding the address, the address of an input is only known once its output is read
+const OUTPUT_ADDRESS_COPIES: usize = 5;
+const INPUT_ADDRESS_COPIES: usize = 4;
+const TYPICAL_ADDRESS_LEN: usize = 42;
+
+impl IndexedBlock {
+    // Rough size of the rows the block writes, to size group commits
+    pub fn write_size(&self) -> usize {
+        self.sum_txs
+            .iter()
+            .map(|sum_tx| {
+                let outputs = sum_tx
+                    .outs
+                    .iter()
+                    .map(|utxo| OUTPUT_WRITE_SIZE + OUTPUT_ADDRESS_COPIES * utxo.address.len())
+                    .sum::<usize>();
+                let inputs = sum_tx.ins.len()
+                    * (INPUT_WRITE_SIZE + INPUT_ADDRESS_COPIES * TYPICAL_ADDRESS_LEN);
+                TX_WRITE_SIZE + outputs + inputs
+            })
+            .sum()
+    }
+}
+
+// Net change of address balances over the blocks of a commit or a rollback
+#[derive(Debug, Default)]
+pub struct BalanceDeltas {
+    deltas: HashMap<String, i64>,
+}
+
+impl BalanceDeltas {
+    pub fn credit(&mut self, address: &str, value: u64) {
+        *self.deltas.entry(address.to_string()).or_default() += value as i64;
+    }
+
+    pub fn debit(&mut self, address: &str, value: u64) {
+        *self.deltas.entry(address.to_string()).or_default() -= value as i64;
+    }
+
+    // Reverts the flow row about to be deleted, an output is debited back and an input credited back
+    pub fn revert_flow(&mut self, key: &[u8], value: &[u8]) -> Result<(), UtxoParseError> {
+        let flow = AddressFlow::try_from(key)?;
+        let FlowValue { value, .. } = decode_flow_value(&flow.flow, value)?;
+        match flow.flow {
+            Flow::O => self.debit(&flow.address, value),
+            Flow::I => self.credit(&flow.address, value),
+        }
+        Ok(())
+    }
+
+    // Addresses whose balance changed, with their delta
+    pub fn iter(&self) -> impl Iterator<Item = (&String, i64)> {
+        self.deltas
+            .iter()
+            .filter(|(_, delta)| **delta != 0)
+            .map(|(address, delta)| (address, *delta))
+    }
+
+    // The new balance, none when nothing is left so that the row is removed
+    pub fn apply(address: &str, balance: u64, delta: i64) -> Result<Option<u64>, IndexerError> {
+        balance
+            .checked_add_signed(delta)
+            .map(|balance| Some(balance).filter(|balance| *balance > 0))
+            .ok_or_else(|| IndexerError::InvalidBalance {
+                address: address.to_string(),
+                balance,
+                delta,
+            })
+    }
+}
+
+// Funding and spending events of an address with their totals and the heights it was active at
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AddressStats {
+    pub funded_count: u64,
+    pub spent_count: u64,
+    pub received: u64,
+    pub sent: u64,
+    // transactions funding or spending the address, unknown for addresses active before it was counted
+    pub tx_count: Option<u64>,
+    // unknown for addresses summed up by `index_btc migrate`, output flow rows carried no height then
+    pub first_seen: Option<u64>,
+    pub last_seen: Option<u64>,
+}
+
+impl AddressStats {
+    pub fn fund(&mut self, value: u64) {
+        self.funded_count += 1;
+        self.received += value;
+    }
+
+    pub fn spend(&mut self, value: u64) {
+        self.spent_count += 1;
+        self.sent += value;
+    }
+
+    pub fn balance(&self) -> u64 {
+        self.received.saturating_sub(self.sent)
+    }
+
+    // Adds the events of a block at the height
+    pub fn merge(&mut self, activity: &AddressStats, height: u64) {
+        if self.funded_count + self.spent_count == 0 {
+            self.first_seen = Some(height);
+            self.tx_count = Some(0);
+        }
+        self.tx_count = self
+            .tx_count
+            .zip(activity.tx_count)
+            .map(|(tx_count, block_tx_count)| tx_count + block_tx_count);
+        self.funded_count += activity.funded_count;
+        self.spent_count += activity.spent_count;
+        self.received += activity.received;
+        self.sent += activity.sent;
+        self.last_seen = Some(height);
+    }
+}
+
+// Funding and spending events of each address within a block
+#[derive(Debug, Default)]
+pub struct BlockActivity {
+    addresses: HashMap<String, AddressStats>,
+    // transactions already counted for the address
+    txs: HashSet<(String, Txid)>,
+}
+
+impl BlockActivity {
+    pub fn fund(&mut self, address: &str, txid: &Txid, value: u64) {
+        self.address_stats(address, txid).fund(value);
+    }
+
+    pub fn spend(&mut self, address: &str, txid: &Txid, value: u64) {
+        self.address_stats(address, txid).spend(value);
+    }
+
+    // Stats of the address counting the transaction once
+    fn address_stats(&mut self, address: &str, txid: &Txid) -> &mut AddressStats {
+        let new_tx = self.txs.insert((address.to_string(), *txid));
+        let stats = self.addresses.entry(address.to_string()).or_default();
+        let tx_count = stats.tx_count.unwrap_or(0);
+        stats.tx_count = Some(if new_tx { tx_count + 1 } else { tx_count });
+        stats
+    }
+
+    // Adds the events to the stats and balance deltas of a commit, stats of addresses it has not
+    // touched yet are loaded, returns them as they were before the block for its undo record
+    pub fn apply<E>(
+        &self,
+        height: u64,
+        stats: &mut HashMap<String, AddressStats>,
+        balances: &mut BalanceDeltas,
+        mut load: impl FnMut(&str) -> Result<Option<AddressStats>, E>,
+    ) -> Result<Vec<(String, Option<AddressStats>)>, E> {
+        let mut previous_stats = Vec::with_capacity(self.addresses.len());
+        for (address, activity) in &self.addresses {
+            balances.credit(address, activity.received);
+            balances.debit(address, activity.sent);
+            let previous = match stats.get(address) {
+                Some(address_stats) => Some(address_stats.clone()),
+                None => load(address)?,
+            };
+            let mut address_stats = previous.clone().unwrap_or_default();
+            address_stats.merge(activity, height);
+            stats.insert(address.clone(), address_stats);
+            previous_stats.push((address.clone(), previous));
+        }
+        Ok(previous_stats)
+    }
+}
+
+// Everything a block wrote, so that it can be rolled back on chain reorganization
+#[derive(Debug, Default)]
+pub struct UndoRecord {
+    pub address_keys: Vec<Vec<u8>>,
+    pub cache_keys: Vec<Vec<u8>>,
+    pub spent_utxos: Vec<(Vec<u8>, Vec<u8>)>,
+    // stats of the addresses the block touched as they were before it, none when it created them
+    pub address_stats: Vec<(String, Option<AddressStats>)>,
+    pub txids: Vec<Txid>,
+    // locations of earlier transactions whose txid a coinbase of the block repeated before bip30
+    pub replaced_txs: Vec<(Txid, TxLocation)>,
+    pub script_history_keys: Vec<Vec<u8>>,
+}
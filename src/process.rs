@@ -1,10 +1,14 @@
-use bitcoin::Transaction;
-use index_btc::model;
+use crate::model;
+use bitcoin::{Network, Transaction};
 use tokio::{sync::Semaphore, task};
 
 use std::sync::Arc;
 
-pub async fn process_txs(parallelism: usize, txs: Vec<Transaction>) -> Vec<model::SumTx> {
+pub async fn process_txs(
+    parallelism: usize,
+    network: Network,
+    txs: Vec<Transaction>,
+) -> Vec<model::SumTx> {
     let batch_size = 100;
     let sem = Arc::new(Semaphore::new(parallelism)); // Limit to 4 concurrent batches
 
@@ -18,7 +22,7 @@ pub async fn process_txs(parallelism: usize, txs: Vec<Transaction>) -> Vec<model
                 task::spawn_blocking(move || {
                     chunk
                         .into_iter()
-                        .map(|tx| model::SumTx::from(tx))
+                        .map(|tx| model::SumTx::new(tx, network))
                         .collect::<Vec<_>>()
                 })
                 .await
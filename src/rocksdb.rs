@@ -1,11 +1,40 @@
-use index_btc::indexer::{Indexer, IndexerError};
-use index_btc::model::{SumTx, Utxo, ADDRESS_CF, CACHE_CF, LAST_HEIGHT_KEY};
-use rocksdb::{MultiThreaded, Options, TransactionDB, TransactionDBOptions};
+use crate::cache::{StagedCache, UtxoCache};
+use crate::codec::{
+    self, address_key, address_prefix, decode_flow_value, decode_height, decode_history_row,
+    decode_network, decode_schema_version, decode_script_history_row, decode_value,
+    encode_input_value, encode_network, encode_output_value, encode_schema_version, encode_value,
+    flow_history_key, history_bound, history_key, history_position_bound, outpoint_key,
+    CODEC_SCHEMA_VERSION, FLOW_HEIGHT_SCHEMA_VERSION, HISTORY_SCHEMA_VERSION,
+    LEGACY_SCHEMA_VERSION, META_SCHEMA_VERSION, NETWORK_SCHEMA_VERSION, SCHEMA_VERSION,
+    SCHEMA_VERSION_KEY, SCRIPT_HISTORY_SCHEMA_VERSION, STATS_SCHEMA_VERSION, TX_SCHEMA_VERSION,
+};
+use crate::events::{self, EventPublisher, IndexEvent};
+use crate::indexer::{Indexer, IndexerError};
+use crate::log;
+use crate::model::{
+    AddressFlow, AddressStats, BalanceDeltas, BlockActivity, Flow, FlowValue, HeaderRecord,
+    HistoryFilter, IndexedBlock, IndexedTxid, ScriptHash, Spend, SumTx, TxLocation, UndoRecord,
+    Utxo, ADDRESS_BALANCE_CF, ADDRESS_CF, ADDRESS_STATS_CF, BLOCK_HASH_CF, BLOCK_HEIGHT_CF,
+    CACHE_CF, HEADER_CF, HISTORY_CF, LAST_HEIGHT_KEY, MEDIAN_TIME_SPAN, META_CF, NETWORK_KEY,
+    OP_RETURN, SCRIPT_HASH_CF, SCRIPT_HISTORY_CF, SCRIPT_HISTORY_HEIGHT_KEY, TX_CF, UNDO_CF,
+    UTXO_FLUSH_HEIGHT_KEY,
+};
+use bitcoin::hashes::Hash;
+use bitcoin::{BlockHash, Network, Txid};
+use rocksdb::{
+    Direction, IteratorMode, MultiThreaded, Options, TransactionDB, TransactionDBOptions,
+    WriteBatchWithTransaction, WriteOptions, DB,
+};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::str;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::broadcast;
 
 pub struct RocksDbIndexer {
     db: Arc<RwLock<TransactionDB<MultiThreaded>>>,
+    utxo_cache: Arc<Mutex<UtxoCache>>,
+    events: EventPublisher,
 }
 
 // Derive Clone for AddressIndexer
@@ -13,86 +42,612 @@ impl Clone for RocksDbIndexer {
     fn clone(&self) -> RocksDbIndexer {
         RocksDbIndexer {
             db: Arc::clone(&self.db),
+            utxo_cache: Arc::clone(&self.utxo_cache),
+            events: self.events.clone(),
         }
     }
 }
 
 impl RocksDbIndexer {
     // Method to process the outputs of a transaction
+    #[allow(clippy::too_many_arguments)]
     fn process_outputs(
         &self,
         sum_tx: &SumTx,
-        db_tx: &rocksdb::Transaction<TransactionDB<MultiThreaded>>,
+        position: usize,
+        block: &IndexedBlock,
+        utxo_cache: &mut StagedCache,
         batch: &mut rocksdb::WriteBatchWithTransaction<true>,
         address_cf: &Arc<rocksdb::BoundColumnFamily>,
-        cache_cf: &Arc<rocksdb::BoundColumnFamily>,
-    ) -> Result<(), rocksdb::Error> {
+        history_cf: &Arc<rocksdb::BoundColumnFamily>,
+        script_history_cf: &Arc<rocksdb::BoundColumnFamily>,
+        activity: &mut BlockActivity,
+        undo: &mut UndoRecord,
+        history_rows: &mut Option<Vec<(Vec<u8>, Vec<u8>)>>,
+    ) {
         for utxo in sum_tx.outs.iter() {
-            let tx_id_with_index = format!("{}|{}", &sum_tx.txid, utxo.index);
-            let utxo_bytes = utxo.to_string().into_bytes();
-            db_tx.put_cf(cache_cf, tx_id_with_index, utxo_bytes)?;
-            let address_key = format!("{}|{}|{}|{}", utxo.address, "O", &sum_tx.txid, utxo.index);
-            batch.put_cf(address_cf, address_key, utxo.value.to_ne_bytes());
+            let cache_key = outpoint_key(&sum_tx.txid, utxo.index);
+            utxo_cache.add(cache_key.clone(), utxo.to_bytes());
+            activity.fund(&utxo.address, &sum_tx.txid, utxo.value);
+            let address_key = address_key(&utxo.address, &Flow::O, &sum_tx.txid, utxo.index);
+            let value = encode_output_value(utxo.value, block.height, block.header.time, position);
+            let address_history_key = history_key(
+                &address_prefix(&utxo.address),
+                block.height,
+                position,
+                &Flow::O,
+                &sum_tx.txid,
+                utxo.index,
+            );
+            if let Some(history_rows) = history_rows {
+                history_rows.push((address_history_key.clone(), value.clone()));
+            }
+            batch.put_cf(history_cf, address_history_key, &value);
+            // op_return outputs are never queried by their script
+            if let Some(script_hash) = utxo.script_hash.filter(|_| utxo.address != OP_RETURN) {
+                let script_history_key = history_key(
+                    &script_hash.0,
+                    block.height,
+                    position,
+                    &Flow::O,
+                    &sum_tx.txid,
+                    utxo.index,
+                );
+                batch.put_cf(script_history_cf, &script_history_key, &value);
+                undo.script_history_keys.push(script_history_key);
+            }
+            batch.put_cf(address_cf, &address_key, value);
+            undo.cache_keys.push(cache_key);
+            undo.address_keys.push(address_key);
         }
-        Ok(())
     }
 
     // Method to process the inputs of a transaction
+    #[allow(clippy::too_many_arguments)]
     fn process_inputs(
         &self,
         sum_tx: &SumTx,
+        position: usize,
+        block: &IndexedBlock,
         db_tx: &rocksdb::Transaction<TransactionDB<MultiThreaded>>,
+        utxo_cache: &mut StagedCache,
         batch: &mut rocksdb::WriteBatchWithTransaction<true>,
         address_cf: &Arc<rocksdb::BoundColumnFamily>,
+        history_cf: &Arc<rocksdb::BoundColumnFamily>,
+        script_history_cf: &Arc<rocksdb::BoundColumnFamily>,
         cache_cf: &Arc<rocksdb::BoundColumnFamily>,
-    ) -> Result<(), rocksdb::Error> {
-        for indexed_txid in &sum_tx.ins {
-            let tx_cache_key = indexed_txid.to_string();
-            let utxo_str = db_tx.get_cf(cache_cf, tx_cache_key)?.unwrap();
-            let utxo: Utxo = Utxo::try_from(utxo_str).unwrap();
-            let address_key = format!(
-                "{}|{}|{}|{}",
-                utxo.address, "I", indexed_txid.tx_id, indexed_txid.index
+        activity: &mut BlockActivity,
+        undo: &mut UndoRecord,
+        history_rows: &mut Option<Vec<(Vec<u8>, Vec<u8>)>>,
+    ) -> Result<(), IndexerError> {
+        for (vin, indexed_txid) in sum_tx.ins.iter().enumerate() {
+            let cache_key = indexed_txid.to_bytes();
+            let utxo_bytes = match utxo_cache.spend(&cache_key) {
+                Some(utxo_bytes) => utxo_bytes,
+                None => db_tx.get_cf(cache_cf, &cache_key)?.ok_or_else(|| {
+                    IndexerError::MissingUtxo {
+                        height: block.height,
+                        outpoint: indexed_txid.clone(),
+                    }
+                })?,
+            };
+            let utxo =
+                Utxo::try_from(utxo_bytes.as_slice()).map_err(|e| IndexerError::InvalidUtxo {
+                    height: block.height,
+                    outpoint: indexed_txid.clone(),
+                    error: format!("{:?}", e),
+                })?;
+            activity.spend(&utxo.address, &sum_tx.txid, utxo.value);
+            let address_key = address_key(
+                &utxo.address,
+                &Flow::I,
+                &indexed_txid.tx_id,
+                indexed_txid.index,
             );
-            batch.put_cf(address_cf, address_key, utxo.value.to_ne_bytes());
+            let spend = Spend {
+                tx_id: sum_tx.txid,
+                vin,
+                height: block.height,
+            };
+            let value = encode_input_value(utxo.value, &spend, block.header.time, position);
+            let address_history_key = history_key(
+                &address_prefix(&utxo.address),
+                block.height,
+                position,
+                &Flow::I,
+                &indexed_txid.tx_id,
+                indexed_txid.index,
+            );
+            if let Some(history_rows) = history_rows {
+                history_rows.push((address_history_key.clone(), value.clone()));
+            }
+            batch.put_cf(history_cf, address_history_key, &value);
+            // outputs cached before script hashes were recorded leave no script history
+            if let Some(script_hash) = utxo.script_hash {
+                let script_history_key = history_key(
+                    &script_hash.0,
+                    block.height,
+                    position,
+                    &Flow::I,
+                    &indexed_txid.tx_id,
+                    indexed_txid.index,
+                );
+                batch.put_cf(script_history_cf, &script_history_key, &value);
+                undo.script_history_keys.push(script_history_key);
+            }
+            batch.put_cf(address_cf, &address_key, value);
+            undo.spent_utxos.push((cache_key, utxo_bytes));
+            undo.address_keys.push(address_key);
         }
         Ok(())
     }
-}
 
-impl Indexer for RocksDbIndexer {
-    fn get_last_height(&self) -> u64 {
-        return self
-            .db
-            .clone()
-            .read()
-            .unwrap()
-            .get(LAST_HEIGHT_KEY)
-            .unwrap()
-            .map_or(0, |height| {
-                String::from_utf8(height).unwrap().parse::<u64>().unwrap()
-            });
+    // Writes the utxo cache to the db in the transaction, recording the height it was flushed at
+    fn write_utxo_cache(
+        utxo_cache: &StagedCache,
+        height: u64,
+        db_tx: &rocksdb::Transaction<TransactionDB<MultiThreaded>>,
+        cache_cf: &Arc<rocksdb::BoundColumnFamily>,
+        undo_cf: &Arc<rocksdb::BoundColumnFamily>,
+        meta_cf: &Arc<rocksdb::BoundColumnFamily>,
+    ) -> Result<(), rocksdb::Error> {
+        for (cache_key, utxo_bytes) in utxo_cache.added() {
+            db_tx.put_cf(cache_cf, cache_key, utxo_bytes)?;
+        }
+        // spent outputs leave the cache so that it holds exactly the utxo set
+        for cache_key in utxo_cache.spent() {
+            db_tx.delete_cf(cache_cf, cache_key)?;
+        }
+        for undo_height in utxo_cache.expired_undo_heights(height) {
+            db_tx.delete_cf(undo_cf, undo_height.to_be_bytes())?;
+        }
+        db_tx.put_cf(meta_cf, UTXO_FLUSH_HEIGHT_KEY, height.to_be_bytes())?;
+        Ok(())
     }
 
-    fn update_balance(&mut self, height: u64, sum_txs: &Vec<SumTx>) -> Result<(), IndexerError> {
-        let db_arc = self.db.clone();
-        let db = db_arc.write().unwrap();
-        let db_tx = db.transaction();
+    // Applies the balance deltas to the stored balances in the transaction
+    fn write_balances(
+        balances: &BalanceDeltas,
+        db_tx: &rocksdb::Transaction<TransactionDB<MultiThreaded>>,
+        balance_cf: &Arc<rocksdb::BoundColumnFamily>,
+    ) -> Result<(), IndexerError> {
+        for (address, delta) in balances.iter() {
+            let balance = db_tx
+                .get_cf(balance_cf, address)?
+                .map(|balance| decode_value(&balance))
+                .transpose()
+                .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))?
+                .unwrap_or(0);
+            match BalanceDeltas::apply(address, balance, delta)? {
+                Some(balance) => db_tx.put_cf(balance_cf, address, encode_value(balance))?,
+                None => db_tx.delete_cf(balance_cf, address)?,
+            }
+        }
+        Ok(())
+    }
+
+    // Deletes the flow rows of an undo record along with their address and script history rows,
+    // reverting their effect on balances, and puts back the stats of its addresses
+    fn undo_address_rows(
+        undo: &UndoRecord,
+        db_tx: &rocksdb::Transaction<TransactionDB<MultiThreaded>>,
+        address_cf: &Arc<rocksdb::BoundColumnFamily>,
+        history_cf: &Arc<rocksdb::BoundColumnFamily>,
+        script_history_cf: &Arc<rocksdb::BoundColumnFamily>,
+        stats_cf: &Arc<rocksdb::BoundColumnFamily>,
+        balances: &mut BalanceDeltas,
+    ) -> Result<(), IndexerError> {
+        for address_key in &undo.address_keys {
+            if let Some(value) = db_tx.get_cf(address_cf, address_key)? {
+                balances
+                    .revert_flow(address_key, &value)
+                    .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?;
+                let history_key = flow_history_key(address_key, &value)
+                    .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?;
+                db_tx.delete_cf(history_cf, history_key)?;
+            }
+            db_tx.delete_cf(address_cf, address_key)?;
+        }
+        for script_history_key in &undo.script_history_keys {
+            db_tx.delete_cf(script_history_cf, script_history_key)?;
+        }
+        for (address, stats) in &undo.address_stats {
+            match stats {
+                Some(stats) => db_tx.put_cf(stats_cf, address, stats.to_bytes())?,
+                None => db_tx.delete_cf(stats_cf, address)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn read_stats(
+        db_tx: &rocksdb::Transaction<TransactionDB<MultiThreaded>>,
+        stats_cf: &Arc<rocksdb::BoundColumnFamily>,
+        address: &str,
+    ) -> Result<Option<AddressStats>, IndexerError> {
+        db_tx
+            .get_cf(stats_cf, address)?
+            .map(|stats| AddressStats::try_from(stats.as_slice()))
+            .transpose()
+            .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))
+    }
+
+    // Sums up the balance and stats of every address from its flow rows, which are contiguous by
+    // address, heights are unknown since output flow rows carry none
+    fn sum_up_addresses(db: &TransactionDB<MultiThreaded>) -> Result<(), IndexerError> {
         let address_cf = db.cf_handle(ADDRESS_CF).unwrap();
-        let cache_cf = db.cf_handle(CACHE_CF).unwrap();
-        let mut batch = db_tx.get_writebatch();
-        for sum_tx in sum_txs {
-            self.process_outputs(&sum_tx, &db_tx, &mut batch, &address_cf, &cache_cf)?;
-            if !sum_tx.is_coinbase {
-                self.process_inputs(sum_tx, &db_tx, &mut batch, &address_cf, &cache_cf)?;
+        let balance_cf = db.cf_handle(ADDRESS_BALANCE_CF).unwrap();
+        let stats_cf = db.cf_handle(ADDRESS_STATS_CF).unwrap();
+        let mut batch = WriteBatchWithTransaction::<true>::default();
+        let mut current: Option<(String, AddressStats)> = None;
+        let mut count = 0u64;
+        let put_address = |batch: &mut WriteBatchWithTransaction<true>,
+                           (address, stats): (String, AddressStats)| {
+            if stats.balance() > 0 {
+                batch.put_cf(&balance_cf, &address, encode_value(stats.balance()));
+            }
+            batch.put_cf(&stats_cf, &address, stats.to_bytes());
+        };
+        for item in db.iterator_cf(&address_cf, IteratorMode::Start) {
+            let (key, value) = item?;
+            let flow = AddressFlow::try_from(key.as_ref())
+                .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?;
+            let FlowValue { value, .. } = decode_flow_value(&flow.flow, &value)
+                .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?;
+            if current
+                .as_ref()
+                .is_none_or(|(address, _)| *address != flow.address)
+            {
+                if let Some(previous) = current.replace((flow.address, AddressStats::default())) {
+                    put_address(&mut batch, previous);
+                    count += 1;
+                    if count.is_multiple_of(1_000_000) {
+                        db.write(std::mem::take(&mut batch))?;
+                        log!("Summed up {} addresses", count);
+                    }
+                }
+            }
+            let (_, stats) = current.as_mut().unwrap();
+            match flow.flow {
+                Flow::O => stats.fund(value),
+                Flow::I => stats.spend(value),
+            }
+        }
+        if let Some(last) = current {
+            put_address(&mut batch, last);
+            count += 1;
+        }
+        db.write(batch)?;
+        log!("Summed up {} addresses", count);
+        Ok(())
+    }
+
+    // Maps the script hashes of the indexed addresses to them, scripts indexed under a hash of
+    // their asm have no address to derive theirs from
+    fn index_script_hashes(db: &TransactionDB<MultiThreaded>) -> Result<(), IndexerError> {
+        let stats_cf = db.cf_handle(ADDRESS_STATS_CF).unwrap();
+        let script_hash_cf = db.cf_handle(SCRIPT_HASH_CF).unwrap();
+        let mut batch = WriteBatchWithTransaction::<true>::default();
+        let mut count = 0u64;
+        for item in db.iterator_cf(&stats_cf, IteratorMode::Start) {
+            let (address, _) = item?;
+            let address = str::from_utf8(&address)
+                .map_err(|e| IndexerError::ParseError(format!("Invalid address : {}", e)))?;
+            if let Some(script_hash) = ScriptHash::from_address(address) {
+                batch.put_cf(&script_hash_cf, script_hash.0, address);
+                count += 1;
+                if count.is_multiple_of(1_000_000) {
+                    db.write(std::mem::take(&mut batch))?;
+                    log!("Indexed the script hashes of {} addresses", count);
+                }
             }
         }
-        db_tx.put(LAST_HEIGHT_KEY, height.to_string().as_bytes())?;
+        db.write(batch)?;
+        log!("Indexed the script hashes of {} addresses", count);
+        Ok(())
+    }
+
+    // Copies the flow rows into the history, ordered by height only as their positions are unknown
+    fn index_history(db: &TransactionDB<MultiThreaded>) -> Result<(), IndexerError> {
+        let address_cf = db.cf_handle(ADDRESS_CF).unwrap();
+        let history_cf = db.cf_handle(HISTORY_CF).unwrap();
+        let mut batch = WriteBatchWithTransaction::<true>::default();
+        let mut count = 0u64;
+        for item in db.iterator_cf(&address_cf, IteratorMode::Start) {
+            let (key, value) = item?;
+            let history_key = flow_history_key(&key, &value)
+                .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?;
+            batch.put_cf(&history_cf, history_key, value);
+            count += 1;
+            if count.is_multiple_of(1_000_000) {
+                db.write(std::mem::take(&mut batch))?;
+                log!("Indexed the history of {} flows", count);
+            }
+        }
+        db.write(batch)?;
+        log!("Indexed the history of {} flows", count);
+        Ok(())
+    }
+
+    // Header records of the blocks below the height, as many as its median time past is taken over
+    fn headers_below(
+        height: u64,
+        db_tx: &rocksdb::Transaction<TransactionDB<MultiThreaded>>,
+        header_cf: &Arc<rocksdb::BoundColumnFamily>,
+    ) -> Result<Vec<Option<HeaderRecord>>, IndexerError> {
+        (height.saturating_sub(MEDIAN_TIME_SPAN - 1)..height)
+            .map(|height| {
+                db_tx
+                    .get_cf(header_cf, height.to_be_bytes())?
+                    .map(|record| HeaderRecord::try_from(record.as_slice()))
+                    .transpose()
+                    .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))
+            })
+            .collect()
+    }
+
+    // Deletes the hash, header and hash to height rows of the block at the height
+    fn delete_block_rows(
+        height: u64,
+        db_tx: &rocksdb::Transaction<TransactionDB<MultiThreaded>>,
+        block_hash_cf: &Arc<rocksdb::BoundColumnFamily>,
+        header_cf: &Arc<rocksdb::BoundColumnFamily>,
+        block_height_cf: &Arc<rocksdb::BoundColumnFamily>,
+    ) -> Result<(), rocksdb::Error> {
+        if let Some(block_hash) = db_tx.get_cf(block_hash_cf, height.to_be_bytes())? {
+            db_tx.delete_cf(block_height_cf, block_hash)?;
+        }
+        db_tx.delete_cf(block_hash_cf, height.to_be_bytes())?;
+        db_tx.delete_cf(header_cf, height.to_be_bytes())?;
+        Ok(())
+    }
+
+    // Maps the hashes of the indexed blocks to their heights
+    fn index_block_heights(db: &TransactionDB<MultiThreaded>) -> Result<(), IndexerError> {
+        let block_hash_cf = db.cf_handle(BLOCK_HASH_CF).unwrap();
+        let block_height_cf = db.cf_handle(BLOCK_HEIGHT_CF).unwrap();
+        let mut batch = WriteBatchWithTransaction::<true>::default();
+        for item in db.iterator_cf(&block_hash_cf, IteratorMode::Start) {
+            let (height, block_hash) = item?;
+            batch.put_cf(&block_height_cf, block_hash, height);
+        }
+        log!("Indexed the heights of {} block hashes", batch.len());
+        db.write(batch)?;
+        Ok(())
+    }
+
+    // Undoes the flow rows of blocks indexed after the last utxo cache flush, whose outputs were lost
+    // with the process, returns the flush height indexing resumes from
+    fn recover_unflushed(db: &TransactionDB<MultiThreaded>) -> Result<u64, IndexerError> {
+        let address_cf = db.cf_handle(ADDRESS_CF).unwrap();
+        let history_cf = db.cf_handle(HISTORY_CF).unwrap();
+        let script_history_cf = db.cf_handle(SCRIPT_HISTORY_CF).unwrap();
+        let undo_cf = db.cf_handle(UNDO_CF).unwrap();
+        let block_hash_cf = db.cf_handle(BLOCK_HASH_CF).unwrap();
+        let header_cf = db.cf_handle(HEADER_CF).unwrap();
+        let block_height_cf = db.cf_handle(BLOCK_HEIGHT_CF).unwrap();
+        let tx_cf = db.cf_handle(TX_CF).unwrap();
+        let balance_cf = db.cf_handle(ADDRESS_BALANCE_CF).unwrap();
+        let stats_cf = db.cf_handle(ADDRESS_STATS_CF).unwrap();
+        let meta_cf = db.cf_handle(META_CF).unwrap();
+        let get_height = |key: &[u8]| -> Result<Option<u64>, IndexerError> {
+            db.get_cf(&meta_cf, key)?
+                .map(|height| decode_height(&height))
+                .transpose()
+                .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))
+        };
+        let last_height = get_height(LAST_HEIGHT_KEY)?.unwrap_or(0);
+        // dbs written before the utxo cache have every output in the db
+        let flushed_height = get_height(UTXO_FLUSH_HEIGHT_KEY)?.unwrap_or(last_height);
+        if flushed_height >= last_height {
+            return Ok(last_height);
+        }
+        log!(
+            "Utxo cache was flushed @ {}, undoing blocks up to {}",
+            flushed_height,
+            last_height
+        );
+        let db_tx = db.transaction();
+        let mut balances = BalanceDeltas::default();
+        for undo_height in ((flushed_height + 1)..=last_height).rev() {
+            let undo_bytes = db_tx.get_cf(&undo_cf, undo_height.to_be_bytes())?.ok_or(
+                IndexerError::RollbackError(format!("Missing undo record @ {}", undo_height)),
+            )?;
+            let undo = UndoRecord::try_from(undo_bytes.as_slice())
+                .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?;
+            Self::undo_address_rows(
+                &undo,
+                &db_tx,
+                &address_cf,
+                &history_cf,
+                &script_history_cf,
+                &stats_cf,
+                &mut balances,
+            )?;
+            for txid in &undo.txids {
+                db_tx.delete_cf(&tx_cf, txid.as_byte_array())?;
+            }
+            for (txid, location) in &undo.replaced_txs {
+                db_tx.put_cf(&tx_cf, txid.as_byte_array(), location.to_bytes())?;
+            }
+            db_tx.delete_cf(&undo_cf, undo_height.to_be_bytes())?;
+            Self::delete_block_rows(
+                undo_height,
+                &db_tx,
+                &block_hash_cf,
+                &header_cf,
+                &block_height_cf,
+            )?;
+        }
+        Self::write_balances(&balances, &db_tx, &balance_cf)?;
+        db_tx.put_cf(&meta_cf, LAST_HEIGHT_KEY, flushed_height.to_be_bytes())?;
         db_tx.commit()?;
+        Ok(flushed_height)
+    }
+
+    // Upgrades a db of an older schema version in place, legacy dbs are refused as they lack address rows
+    pub fn migrate(num_cores: i32, db_path: &str) -> Result<(), IndexerError> {
+        let db = Self::open_db(num_cores, db_path)?;
+        let version = match Self::stored_schema_version(&db)? {
+            None | Some(SCHEMA_VERSION) => {
+                log!("{} is at schema version {}", db_path, SCHEMA_VERSION);
+                return Ok(());
+            }
+            Some(version) if version < CODEC_SCHEMA_VERSION || version > SCHEMA_VERSION => {
+                return Err(Self::schema_error(db_path, version))
+            }
+            Some(version) => version,
+        };
+        // written outside of the transaction for their size, a rerun sums them up again
+        if version < STATS_SCHEMA_VERSION {
+            Self::sum_up_addresses(&db)?;
+        }
+        if version <= FLOW_HEIGHT_SCHEMA_VERSION {
+            Self::index_block_heights(&db)?;
+        }
+        if version <= TX_SCHEMA_VERSION {
+            Self::index_script_hashes(&db)?;
+        }
+        if version <= HISTORY_SCHEMA_VERSION {
+            Self::index_history(&db)?;
+        }
+        let meta_cf = db.cf_handle(META_CF).unwrap();
+        let db_tx = db.transaction();
+        if version < META_SCHEMA_VERSION {
+            if let Some(height) = db_tx.get(LAST_HEIGHT_KEY)? {
+                let height = codec::legacy::convert_last_height(&height)
+                    .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))?;
+                db_tx.put_cf(&meta_cf, LAST_HEIGHT_KEY, height)?;
+                db_tx.delete(LAST_HEIGHT_KEY)?;
+            }
+            db_tx.delete(codec::legacy::CODEC_VERSION_KEY)?;
+        }
+        // only mainnet could be indexed before the network was recorded
+        if version < NETWORK_SCHEMA_VERSION {
+            db_tx.put_cf(&meta_cf, NETWORK_KEY, encode_network(Network::Bitcoin))?;
+        }
+        if version <= SCRIPT_HISTORY_SCHEMA_VERSION {
+            if let Some(height) = db_tx.get_cf(&meta_cf, LAST_HEIGHT_KEY)? {
+                db_tx.put_cf(&meta_cf, SCRIPT_HISTORY_HEIGHT_KEY, height)?;
+            }
+        }
+        db_tx.put_cf(
+            &meta_cf,
+            SCHEMA_VERSION_KEY,
+            encode_schema_version(SCHEMA_VERSION),
+        )?;
+        db_tx.commit()?;
+        log!(
+            "Migrated {} from schema version {} to {}",
+            db_path,
+            version,
+            SCHEMA_VERSION
+        );
         Ok(())
     }
 
-    fn new(num_cores: i32, db_path: &str) -> Result<Self, IndexerError> {
+    // Opened read-only without creating column families, so that probing a db leaves it as it was
+    pub fn schema_version(_num_cores: i32, db_path: &str) -> Result<Option<u32>, IndexerError> {
+        let opts = Options::default();
+        let cfs = DB::list_cf(&opts, db_path)?;
+        let db = DB::open_cf_for_read_only(&opts, db_path, &cfs, false)?;
+        let meta_cf = db.cf_handle(META_CF);
+        Self::read_schema_version(
+            |key| match meta_cf {
+                Some(meta_cf) => db.get_cf(&meta_cf, key),
+                None => Ok(None),
+            },
+            |key| db.get(key),
+        )
+    }
+
+    fn stored_schema_version(
+        db: &TransactionDB<MultiThreaded>,
+    ) -> Result<Option<u32>, IndexerError> {
+        let meta_cf = db.cf_handle(META_CF).unwrap();
+        Self::read_schema_version(|key| db.get_cf(&meta_cf, key), |key| db.get(key))
+    }
+
+    // Dbs written before the schema version record are recognized by their metadata, empty ones have none
+    fn read_schema_version(
+        get_meta: impl Fn(&[u8]) -> Result<Option<Vec<u8>>, rocksdb::Error>,
+        get_default: impl Fn(&[u8]) -> Result<Option<Vec<u8>>, rocksdb::Error>,
+    ) -> Result<Option<u32>, IndexerError> {
+        if let Some(version) = get_meta(SCHEMA_VERSION_KEY)? {
+            return decode_schema_version(&version)
+                .map(Some)
+                .map_err(|e| IndexerError::CodecError(format!("{:?}", e)));
+        }
+        if get_default(codec::legacy::CODEC_VERSION_KEY)?.is_some() {
+            Ok(Some(CODEC_SCHEMA_VERSION))
+        } else if get_default(LAST_HEIGHT_KEY)?.is_some() {
+            Ok(Some(LEGACY_SCHEMA_VERSION))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Stamps a new db with the schema version and network, refuses an existing one not matching them
+    fn check_metadata(
+        db: &TransactionDB<MultiThreaded>,
+        db_path: &str,
+        network: Network,
+    ) -> Result<(), IndexerError> {
+        let meta_cf = db.cf_handle(META_CF).unwrap();
+        match Self::stored_schema_version(db)? {
+            Some(SCHEMA_VERSION) => {}
+            Some(version) => return Err(Self::schema_error(db_path, version)),
+            None => {
+                db.put_cf(&meta_cf, NETWORK_KEY, encode_network(network))?;
+                db.put_cf(
+                    &meta_cf,
+                    SCHEMA_VERSION_KEY,
+                    encode_schema_version(SCHEMA_VERSION),
+                )?;
+            }
+        }
+        let stored_network = db
+            .get_cf(&meta_cf, NETWORK_KEY)?
+            .map(|network| decode_network(&network))
+            .transpose()
+            .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))?;
+        match stored_network {
+            Some(stored_network) if stored_network == network => Ok(()),
+            Some(stored_network) => Err(IndexerError::NetworkError(format!(
+                "{} was built for network {}, not {}",
+                db_path, stored_network, network
+            ))),
+            None => Err(IndexerError::NetworkError(format!(
+                "{} has no network recorded",
+                db_path
+            ))),
+        }
+    }
+
+    fn schema_error(db_path: &str, version: u32) -> IndexerError {
+        if version == LEGACY_SCHEMA_VERSION {
+            // the legacy rocks-db engine never wrote address rows, there is nothing to convert
+            IndexerError::CodecError(format!(
+                "{} was indexed by the legacy rocks-db engine without address rows, reindex it into a new db",
+                db_path
+            ))
+        } else if version < SCHEMA_VERSION {
+            IndexerError::CodecError(format!(
+                "{} is at schema version {}, run `index_btc migrate` first",
+                db_path, version
+            ))
+        } else {
+            IndexerError::CodecError(format!(
+                "{} is at unknown schema version {}",
+                db_path, version
+            ))
+        }
+    }
+
+    fn open_db(
+        num_cores: i32,
+        db_path: &str,
+    ) -> Result<TransactionDB<MultiThreaded>, IndexerError> {
         let mut opts = Options::default();
         opts.create_if_missing(true);
         // Increase parallelism: setting the number of background threads
@@ -106,19 +661,576 @@ impl Indexer for RocksDbIndexer {
         opts.set_max_bytes_for_level_base(512 * 1024 * 1024);
         opts.set_use_direct_io_for_flush_and_compaction(true);
 
-        let cfs =
-            rocksdb::TransactionDB::<MultiThreaded>::list_cf(&opts, db_path).unwrap_or(vec![]);
+        // a new db has no column families to list yet
+        let cfs = if Path::new(db_path).exists() {
+            rocksdb::TransactionDB::<MultiThreaded>::list_cf(&opts, db_path)?
+        } else {
+            vec![]
+        };
 
         let txn_db_opts = TransactionDBOptions::default();
-        let instance =
-            TransactionDB::open_cf(&opts, &txn_db_opts, db_path.to_string(), &cfs).unwrap();
-        if cfs.iter().find(|cf| cf == &CACHE_CF).is_none() {
-            let options = rocksdb::Options::default();
-            instance.create_cf(CACHE_CF, &options).unwrap();
-            instance.create_cf(ADDRESS_CF, &options).unwrap();
+        let instance = TransactionDB::open_cf(&opts, &txn_db_opts, db_path.to_string(), &cfs)?;
+        for cf_name in [
+            CACHE_CF,
+            ADDRESS_CF,
+            META_CF,
+            UNDO_CF,
+            BLOCK_HASH_CF,
+            ADDRESS_BALANCE_CF,
+            ADDRESS_STATS_CF,
+            HEADER_CF,
+            BLOCK_HEIGHT_CF,
+            TX_CF,
+            SCRIPT_HASH_CF,
+            HISTORY_CF,
+            SCRIPT_HISTORY_CF,
+        ] {
+            if cfs.iter().find(|cf| cf == &cf_name).is_none() {
+                let options = rocksdb::Options::default();
+                instance.create_cf(cf_name, &options)?;
+            }
         }
+        Ok(instance)
+    }
+}
+
+impl Indexer for RocksDbIndexer {
+    fn get_last_height(&self) -> u64 {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let meta_cf = db.cf_handle(META_CF).unwrap();
+        db.get_cf(&meta_cf, LAST_HEIGHT_KEY)
+            .unwrap()
+            .map_or(0, |height| decode_height(&height).unwrap())
+    }
+
+    fn get_next_height(&self) -> u64 {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let meta_cf = db.cf_handle(META_CF).unwrap();
+        db.get_cf(&meta_cf, LAST_HEIGHT_KEY)
+            .unwrap()
+            .map_or(0, |height| decode_height(&height).unwrap() + 1)
+    }
+
+    fn update_blocks(&mut self, blocks: &[IndexedBlock]) -> Result<(), IndexerError> {
+        let Some(last_block) = blocks.last() else {
+            return Ok(());
+        };
+        let db_arc = self.db.clone();
+        let db = db_arc.write().unwrap();
+        let db_tx = db.transaction();
+        let address_cf = db.cf_handle(ADDRESS_CF).unwrap();
+        let history_cf = db.cf_handle(HISTORY_CF).unwrap();
+        let script_history_cf = db.cf_handle(SCRIPT_HISTORY_CF).unwrap();
+        let cache_cf = db.cf_handle(CACHE_CF).unwrap();
+        let undo_cf = db.cf_handle(UNDO_CF).unwrap();
+        let block_hash_cf = db.cf_handle(BLOCK_HASH_CF).unwrap();
+        let header_cf = db.cf_handle(HEADER_CF).unwrap();
+        let block_height_cf = db.cf_handle(BLOCK_HEIGHT_CF).unwrap();
+        let tx_cf = db.cf_handle(TX_CF).unwrap();
+        let script_hash_cf = db.cf_handle(SCRIPT_HASH_CF).unwrap();
+        let balance_cf = db.cf_handle(ADDRESS_BALANCE_CF).unwrap();
+        let stats_cf = db.cf_handle(ADDRESS_STATS_CF).unwrap();
+        let meta_cf = db.cf_handle(META_CF).unwrap();
+        let mut utxo_cache = self.utxo_cache.lock().unwrap();
+        let mut staged = utxo_cache.stage();
+        let mut batch = db_tx.get_writebatch();
+        let mut balances = BalanceDeltas::default();
+        let mut stats = HashMap::new();
+        let mut undo_records = Vec::with_capacity(blocks.len());
+        let mut coinbases = HashMap::new();
+        // the rows are only kept to be published while someone listens
+        let mut history_rows = self.events.has_subscribers().then(Vec::new);
+        for block in blocks {
+            let mut undo = UndoRecord::default();
+            let mut activity = BlockActivity::default();
+            for (position, sum_tx) in block.sum_txs.iter().enumerate() {
+                let location = TxLocation {
+                    height: block.height,
+                    position,
+                };
+                // coinbases of before bip30 may repeat the txid of an earlier one, only they can
+                if sum_tx.is_coinbase {
+                    let replaced = match coinbases.get(&sum_tx.txid) {
+                        Some(replaced) => Some(*replaced),
+                        None => db_tx
+                            .get_cf(&tx_cf, sum_tx.txid.as_byte_array())?
+                            .map(|location| TxLocation::try_from(location.as_slice()))
+                            .transpose()
+                            .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))?,
+                    };
+                    undo.replaced_txs
+                        .extend(replaced.map(|replaced| (sum_tx.txid, replaced)));
+                    coinbases.insert(sum_tx.txid, location);
+                }
+                batch.put_cf(&tx_cf, sum_tx.txid.as_byte_array(), location.to_bytes());
+                undo.txids.push(sum_tx.txid);
+                // a script always maps to the same address, so that rollbacks leave these rows be,
+                // op_return outputs share a single address and are never queried by their script
+                for utxo in sum_tx.outs.iter().filter(|utxo| utxo.address != OP_RETURN) {
+                    if let Some(script_hash) = &utxo.script_hash {
+                        batch.put_cf(&script_hash_cf, script_hash.0, &utxo.address);
+                    }
+                }
+                self.process_outputs(
+                    sum_tx,
+                    position,
+                    block,
+                    &mut staged,
+                    &mut batch,
+                    &address_cf,
+                    &history_cf,
+                    &script_history_cf,
+                    &mut activity,
+                    &mut undo,
+                    &mut history_rows,
+                );
+                if !sum_tx.is_coinbase {
+                    self.process_inputs(
+                        sum_tx,
+                        position,
+                        block,
+                        &db_tx,
+                        &mut staged,
+                        &mut batch,
+                        &address_cf,
+                        &history_cf,
+                        &script_history_cf,
+                        &cache_cf,
+                        &mut activity,
+                        &mut undo,
+                        &mut history_rows,
+                    )?;
+                }
+            }
+            undo.address_stats =
+                activity.apply(block.height, &mut stats, &mut balances, |address| {
+                    Self::read_stats(&db_tx, &stats_cf, address)
+                })?;
+            undo_records.push(undo);
+        }
+        // the batch is a copy of the transaction's writes, it must be replayed into it
+        db_tx.rebuild_from_writebatch(&batch)?;
+        Self::write_balances(&balances, &db_tx, &balance_cf)?;
+        for (address, stats) in &stats {
+            db_tx.put_cf(&stats_cf, address, stats.to_bytes())?;
+        }
+        let mut headers = Self::headers_below(blocks[0].height, &db_tx, &header_cf)?;
+        for (IndexedBlock { height, header, .. }, undo) in blocks.iter().zip(undo_records) {
+            let block_hash = header.block_hash();
+            let record = HeaderRecord::new(*header, *height, &headers);
+            db_tx.put_cf(&undo_cf, height.to_be_bytes(), undo.to_bytes())?;
+            db_tx.put_cf(
+                &block_hash_cf,
+                height.to_be_bytes(),
+                block_hash.as_byte_array(),
+            )?;
+            db_tx.put_cf(&header_cf, height.to_be_bytes(), record.to_bytes())?;
+            db_tx.put_cf(
+                &block_height_cf,
+                block_hash.as_byte_array(),
+                height.to_be_bytes(),
+            )?;
+            headers.push(Some(record));
+        }
+        // a db without a flush height would take its unflushed blocks as flushed when reopened
+        let flush = staged.needs_flush(last_block.height)
+            || db_tx.get_cf(&meta_cf, UTXO_FLUSH_HEIGHT_KEY)?.is_none();
+        if flush {
+            Self::write_utxo_cache(
+                &staged,
+                last_block.height,
+                &db_tx,
+                &cache_cf,
+                &undo_cf,
+                &meta_cf,
+            )?;
+        }
+        db_tx.put_cf(&meta_cf, LAST_HEIGHT_KEY, last_block.height.to_be_bytes())?;
+        let flows = history_rows
+            .as_deref()
+            .map(events::decode_flows)
+            .transpose()?
+            .unwrap_or_default();
+        db_tx.commit()?;
+        let changes = staged.into_changes();
+        utxo_cache.apply(changes);
+        if flush {
+            utxo_cache.flushed(last_block.height);
+        }
+        self.events.publish(
+            last_block.height,
+            Some(last_block.header.block_hash()),
+            false,
+            flows,
+            &balances,
+        );
+        Ok(())
+    }
+
+    fn get_block_hash(&self, height: u64) -> Result<Option<BlockHash>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let block_hash_cf = db.cf_handle(BLOCK_HASH_CF).unwrap();
+        db.get_cf(&block_hash_cf, height.to_be_bytes())?
+            .map(|hash| {
+                BlockHash::from_slice(&hash)
+                    .map_err(|e| IndexerError::ParseError(format!("Invalid block hash : {}", e)))
+            })
+            .transpose()
+    }
+
+    fn get_header(&self, height: u64) -> Result<Option<HeaderRecord>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let header_cf = db.cf_handle(HEADER_CF).unwrap();
+        db.get_cf(&header_cf, height.to_be_bytes())?
+            .map(|record| HeaderRecord::try_from(record.as_slice()))
+            .transpose()
+            .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))
+    }
+
+    fn get_block_height(&self, block_hash: &BlockHash) -> Result<Option<u64>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let block_height_cf = db.cf_handle(BLOCK_HEIGHT_CF).unwrap();
+        db.get_cf(&block_height_cf, block_hash.as_byte_array())?
+            .map(|height| decode_height(&height))
+            .transpose()
+            .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))
+    }
+
+    fn get_tx_location(&self, txid: &Txid) -> Result<Option<TxLocation>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let tx_cf = db.cf_handle(TX_CF).unwrap();
+        db.get_cf(&tx_cf, txid.as_byte_array())?
+            .map(|location| TxLocation::try_from(location.as_slice()))
+            .transpose()
+            .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))
+    }
+
+    fn rollback(&mut self, height: u64) -> Result<(), IndexerError> {
+        let last_height = self.get_last_height();
+        let db_arc = self.db.clone();
+        let db = db_arc.write().unwrap();
+        let db_tx = db.transaction();
+        let address_cf = db.cf_handle(ADDRESS_CF).unwrap();
+        let history_cf = db.cf_handle(HISTORY_CF).unwrap();
+        let script_history_cf = db.cf_handle(SCRIPT_HISTORY_CF).unwrap();
+        let cache_cf = db.cf_handle(CACHE_CF).unwrap();
+        let undo_cf = db.cf_handle(UNDO_CF).unwrap();
+        let block_hash_cf = db.cf_handle(BLOCK_HASH_CF).unwrap();
+        let header_cf = db.cf_handle(HEADER_CF).unwrap();
+        let block_height_cf = db.cf_handle(BLOCK_HEIGHT_CF).unwrap();
+        let tx_cf = db.cf_handle(TX_CF).unwrap();
+        let balance_cf = db.cf_handle(ADDRESS_BALANCE_CF).unwrap();
+        let stats_cf = db.cf_handle(ADDRESS_STATS_CF).unwrap();
+        let meta_cf = db.cf_handle(META_CF).unwrap();
+        let mut balances = BalanceDeltas::default();
+        // outputs of the rolled back blocks may still be in the utxo cache, undo records apply to the db
+        let mut utxo_cache = self.utxo_cache.lock().unwrap();
+        Self::write_utxo_cache(
+            &utxo_cache.stage(),
+            height,
+            &db_tx,
+            &cache_cf,
+            &undo_cf,
+            &meta_cf,
+        )?;
+        for undo_height in ((height + 1)..=last_height).rev() {
+            let undo_bytes = db_tx.get_cf(&undo_cf, undo_height.to_be_bytes())?.ok_or(
+                IndexerError::RollbackError(format!("Missing undo record @ {}", undo_height)),
+            )?;
+            let undo = UndoRecord::try_from(undo_bytes.as_slice())
+                .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?;
+            Self::undo_address_rows(
+                &undo,
+                &db_tx,
+                &address_cf,
+                &history_cf,
+                &script_history_cf,
+                &stats_cf,
+                &mut balances,
+            )?;
+            for txid in &undo.txids {
+                db_tx.delete_cf(&tx_cf, txid.as_byte_array())?;
+            }
+            for (txid, location) in &undo.replaced_txs {
+                db_tx.put_cf(&tx_cf, txid.as_byte_array(), location.to_bytes())?;
+            }
+            // outputs both created and spent within the block must end up deleted
+            for (cache_key, utxo_bytes) in &undo.spent_utxos {
+                db_tx.put_cf(&cache_cf, cache_key, utxo_bytes)?;
+            }
+            for cache_key in &undo.cache_keys {
+                db_tx.delete_cf(&cache_cf, cache_key)?;
+            }
+            db_tx.delete_cf(&undo_cf, undo_height.to_be_bytes())?;
+            Self::delete_block_rows(
+                undo_height,
+                &db_tx,
+                &block_hash_cf,
+                &header_cf,
+                &block_height_cf,
+            )?;
+        }
+        Self::write_balances(&balances, &db_tx, &balance_cf)?;
+        db_tx.put_cf(&meta_cf, LAST_HEIGHT_KEY, height.to_be_bytes())?;
+        let hash = db_tx
+            .get_cf(&block_hash_cf, height.to_be_bytes())?
+            .map(|hash| BlockHash::from_slice(&hash))
+            .transpose()
+            .map_err(|e| IndexerError::ParseError(format!("Invalid block hash : {}", e)))?;
+        db_tx.commit()?;
+        utxo_cache.flushed(height);
+        self.events
+            .publish(height, hash, true, Vec::new(), &balances);
+        Ok(())
+    }
+
+    fn get_utxo(&self, outpoint: &IndexedTxid) -> Result<Option<Utxo>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let cache_key = outpoint.to_bytes();
+        let cached = {
+            let utxo_cache = self.utxo_cache.lock().unwrap();
+            if utxo_cache.is_spent(&cache_key) {
+                return Ok(None);
+            }
+            utxo_cache.get(&cache_key).cloned()
+        };
+        let utxo_bytes = match cached {
+            Some(utxo_bytes) => Some(utxo_bytes),
+            None => db.get_cf(&db.cf_handle(CACHE_CF).unwrap(), &cache_key)?,
+        };
+        utxo_bytes
+            .map(|utxo_bytes| Utxo::try_from(utxo_bytes.as_slice()))
+            .transpose()
+            .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))
+    }
+
+    fn get_script_address(&self, script_hash: &ScriptHash) -> Result<Option<String>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let script_hash_cf = db.cf_handle(SCRIPT_HASH_CF).unwrap();
+        db.get_cf(&script_hash_cf, script_hash.0)?
+            .map(String::from_utf8)
+            .transpose()
+            .map_err(|e| IndexerError::ParseError(format!("Invalid address : {}", e)))
+    }
+
+    fn get_script_history(
+        &self,
+        script_hash: &ScriptHash,
+    ) -> Result<Vec<(AddressFlow, u64)>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let meta_cf = db.cf_handle(META_CF).unwrap();
+        if let Some(height) = db.get_cf(&meta_cf, SCRIPT_HISTORY_HEIGHT_KEY)? {
+            let height =
+                decode_height(&height).map_err(|e| IndexerError::CodecError(format!("{:?}", e)))?;
+            return Err(IndexerError::MissingScriptHistory { height });
+        }
+        let script_hash_cf = db.cf_handle(SCRIPT_HASH_CF).unwrap();
+        let Some(address) = db.get_cf(&script_hash_cf, script_hash.0)? else {
+            return Ok(Vec::new());
+        };
+        let address = String::from_utf8(address)
+            .map_err(|e| IndexerError::ParseError(format!("Invalid address : {}", e)))?;
+        let script_history_cf = db.cf_handle(SCRIPT_HISTORY_CF).unwrap();
+        let mut history = Vec::new();
+        for item in db.prefix_iterator_cf(&script_history_cf, script_hash.0) {
+            let (key, value) = item?;
+            if !key.starts_with(&script_hash.0) {
+                break;
+            }
+            history.push(
+                decode_script_history_row(&address, &key, &value)
+                    .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?,
+            );
+        }
+        Ok(history)
+    }
+
+    fn flush(&self) -> Result<(), IndexerError> {
+        let last_height = self.get_last_height();
+        let db_arc = self.db.clone();
+        let db = db_arc.write().unwrap();
+        let cache_cf = db.cf_handle(CACHE_CF).unwrap();
+        let undo_cf = db.cf_handle(UNDO_CF).unwrap();
+        let meta_cf = db.cf_handle(META_CF).unwrap();
+        let mut utxo_cache = self.utxo_cache.lock().unwrap();
+        let db_tx = db.transaction();
+        Self::write_utxo_cache(
+            &utxo_cache.stage(),
+            last_height,
+            &db_tx,
+            &cache_cf,
+            &undo_cf,
+            &meta_cf,
+        )?;
+        db_tx.commit()?;
+        utxo_cache.flushed(last_height);
+        // commits are in the wal already, a synced empty write forces it to disk
+        let mut write_opts = WriteOptions::default();
+        write_opts.set_sync(true);
+        db.write_opt(WriteBatchWithTransaction::default(), &write_opts)?;
+        Ok(())
+    }
+
+    fn get_history(&self, address: &str) -> Result<Vec<(AddressFlow, u64)>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let address_cf = db.cf_handle(ADDRESS_CF).unwrap();
+        let prefix = address_prefix(address);
+        let mut history = Vec::new();
+        for item in db.prefix_iterator_cf(&address_cf, &prefix) {
+            let (key, value) = item?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            let mut flow = AddressFlow::try_from(key.as_ref())
+                .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?;
+            let flow_value = decode_flow_value(&flow.flow, &value)
+                .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?;
+            flow.spent_by = flow_value.spent_by;
+            flow.height = flow_value.height;
+            flow.time = flow_value.time;
+            flow.position = flow_value.position;
+            history.push((flow, flow_value.value));
+        }
+        Ok(history)
+    }
+
+    fn get_flow(
+        &self,
+        address: &str,
+        flow: &Flow,
+        outpoint: &IndexedTxid,
+    ) -> Result<Option<FlowValue>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let address_cf = db.cf_handle(ADDRESS_CF).unwrap();
+        let address_key = address_key(address, flow, &outpoint.tx_id, outpoint.index);
+        db.get_cf(&address_cf, address_key)?
+            .map(|value| decode_flow_value(flow, &value))
+            .transpose()
+            .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))
+    }
+
+    fn get_history_filtered(
+        &self,
+        address: &str,
+        filter: &HistoryFilter,
+    ) -> Result<Vec<(AddressFlow, u64)>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let history_cf = db.cf_handle(HISTORY_CF).unwrap();
+        let prefix = address_prefix(address);
+        let start = history_bound(&prefix, filter.start_height.unwrap_or(0));
+        let mut history = Vec::new();
+        for item in db.iterator_cf(&history_cf, IteratorMode::From(&start, Direction::Forward)) {
+            let (key, value) = item?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            let (flow, value) = decode_history_row(&key, &value)
+                .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?;
+            if filter
+                .end_height
+                .is_some_and(|end_height| flow.height.is_some_and(|height| height > end_height))
+            {
+                break;
+            }
+            if filter.matches(&flow) {
+                history.push((flow, value));
+            }
+        }
+        Ok(history)
+    }
+
+    fn get_history_page(
+        &self,
+        address: &str,
+        before: Option<(u64, usize)>,
+        tx_count: usize,
+    ) -> Result<Vec<(AddressFlow, u64)>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let history_cf = db.cf_handle(HISTORY_CF).unwrap();
+        let prefix = address_prefix(address);
+        // no row sits at the bound itself, it is shorter than the keys under it
+        let end = match before {
+            Some((height, position)) => history_position_bound(&prefix, height, position),
+            None => history_bound(&prefix, u64::MAX),
+        };
+        let mut txids = HashSet::new();
+        let mut history = Vec::new();
+        for item in db.iterator_cf(&history_cf, IteratorMode::From(&end, Direction::Reverse)) {
+            let (key, value) = item?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            let (flow, value) = decode_history_row(&key, &value)
+                .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?;
+            if let Some(txid) = flow.txid() {
+                if !txids.contains(&txid) && txids.len() == tx_count {
+                    break;
+                }
+                txids.insert(txid);
+            }
+            history.push((flow, value));
+        }
+        Ok(history)
+    }
+
+    fn get_balance(&self, address: &str) -> Result<u64, IndexerError> {
+        self.get_balance_at_event(address)
+            .map(|(balance, _)| balance)
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Arc<IndexEvent>> {
+        self.events.subscribe()
+    }
+
+    fn get_balance_at_event(&self, address: &str) -> Result<(u64, u64), IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let balance_cf = db.cf_handle(ADDRESS_BALANCE_CF).unwrap();
+        let balance = match db.get_cf(&balance_cf, address)? {
+            Some(balance) => {
+                decode_value(&balance).map_err(|e| IndexerError::CodecError(format!("{:?}", e)))?
+            }
+            None => 0,
+        };
+        Ok((balance, self.events.sequence()))
+    }
+
+    fn get_address_stats(&self, address: &str) -> Result<Option<AddressStats>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let stats_cf = db.cf_handle(ADDRESS_STATS_CF).unwrap();
+        db.get_cf(&stats_cf, address)?
+            .map(|stats| AddressStats::try_from(stats.as_slice()))
+            .transpose()
+            .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))
+    }
+
+    fn new(
+        num_cores: i32,
+        db_path: &str,
+        network: Network,
+        mut utxo_cache: UtxoCache,
+    ) -> Result<Self, IndexerError> {
+        let instance = Self::open_db(num_cores, db_path)?;
+        Self::check_metadata(&instance, db_path, network)?;
+        utxo_cache.flushed(Self::recover_unflushed(&instance)?);
         Ok(RocksDbIndexer {
             db: Arc::new(RwLock::new(instance)),
+            utxo_cache: Arc::new(Mutex::new(utxo_cache)),
+            events: EventPublisher::default(),
         })
     }
 }
@@ -1,13 +1,21 @@
 use crate::log;
-use bitcoincore_rpc::{Auth, Client, RpcApi};
-use chrono::DateTime;
-use futures::stream::StreamExt;
-use tokio::task::{self, JoinError};
-use tokio_stream::Stream; // Add this line to import the `model` module
+use bitcoincore_rpc::{jsonrpc, Auth, Client, Error, RpcApi};
+use futures::stream::{BoxStream, StreamExt};
+use index_btc::source::{BlockSource, Height, SourceBlock, SourceError};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+use tokio::task;
 
 use std::sync::Arc;
 
-type Height = u64;
+// Transient errors are retried with exponentially growing delays, about 5 minutes in total
+const MAX_RETRIES: u32 = 12;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// bitcoind answers with this code until it has loaded its block index
+const RPC_IN_WARMUP: i32 = -28;
 
 pub struct RpcClient {
     rpc_client: Arc<Client>,
@@ -18,33 +26,88 @@ impl RpcClient {
         let rpc = Arc::new(Client::new(&rpc_url, user_pass).unwrap());
         RpcClient { rpc_client: rpc }
     }
+}
+
+// A restarting or warming up bitcoind and dropped connections are worth waiting for
+fn is_transient(error: &Error) -> bool {
+    match error {
+        Error::JsonRpc(jsonrpc::Error::Transport(_)) => true,
+        Error::JsonRpc(jsonrpc::Error::Rpc(e)) => e.code == RPC_IN_WARMUP,
+        Error::Io(_) => true,
+        _ => false,
+    }
+}
+
+// Calls the rpc until it succeeds, backing off with jitter between transient failures
+fn with_retry<T>(call: &str, f: impl Fn() -> Result<T, Error>) -> Result<T, SourceError> {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut retries = 0;
+    loop {
+        match f() {
+            Ok(result) => return Ok(result),
+            Err(e) if is_transient(&e) && retries < MAX_RETRIES => {
+                retries += 1;
+                // half of the delay is random so that parallel fetches do not retry in lockstep
+                let jitter = RandomState::new().build_hasher().finish()
+                    % (backoff.as_millis() as u64 / 2 + 1);
+                let delay = backoff / 2 + Duration::from_millis(jitter);
+                log!(
+                    "Rpc {} failed, retry {} of {} in {:?} : {}",
+                    call,
+                    retries,
+                    MAX_RETRIES,
+                    delay,
+                    e
+                );
+                std::thread::sleep(delay);
+                backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+            }
+            Err(e) => return Err(SourceError::RpcError(format!("{} : {}", call, e))),
+        }
+    }
+}
+
+// Fetches the block at the height of the best chain of bitcoind
+fn fetch_block(rpc_client: &Client, height: Height) -> Result<SourceBlock, SourceError> {
+    // Get the block hash at the specified height
+    let block_hash = with_retry("getblockhash", || rpc_client.get_block_hash(height))?;
+
+    // Get the block by its hash
+    let block = with_retry("getblock", || rpc_client.get_block(&block_hash))?;
+
+    Ok(SourceBlock {
+        height,
+        hash: block_hash,
+        block,
+    })
+}
+
+impl BlockSource for RpcClient {
+    fn get_block_count(&self) -> Result<Height, SourceError> {
+        with_retry("getblockcount", || self.rpc_client.get_block_count())
+    }
+
+    fn get_block_hash(&self, height: Height) -> Result<bitcoin::BlockHash, SourceError> {
+        with_retry("getblockhash", || self.rpc_client.get_block_hash(height))
+    }
+
+    fn get_block(&self, height: Height) -> Result<SourceBlock, SourceError> {
+        fetch_block(&self.rpc_client, height)
+    }
 
-    pub fn fetch_blocks(
+    fn fetch_blocks(
         &self,
         start_height: Height,
         end_height: Height,
-    ) -> impl Stream<Item = Result<(Height, bitcoin::Block), JoinError>> + '_ {
+    ) -> BoxStream<'_, Result<SourceBlock, SourceError>> {
         let heights = start_height..=end_height;
         tokio_stream::iter(heights)
             .map(move |height| {
                 let rpc_client = self.rpc_client.clone();
-                task::spawn_blocking(move || {
-                    // Get the block hash at the specified height
-                    let block_hash = rpc_client.get_block_hash(height).unwrap();
-
-                    // Get the block by its hash
-                    let block = rpc_client.get_block(&block_hash).unwrap();
-
-                    // print the block hash if height is divisible by 1000
-                    if height % 1000 == 0 {
-                        let datetime =
-                            DateTime::from_timestamp(block.header.time as i64, 0).unwrap();
-                        let readable_date = datetime.format("%Y-%m-%d %H:%M:%S").to_string();
-                        log!("Block @ {} : {} : {}", height, readable_date, block_hash);
-                    }
-                    (height, block)
-                })
+                task::spawn_blocking(move || fetch_block(&rpc_client, height))
             })
             .buffered(128)
+            .map(|result| result.map_err(SourceError::from).and_then(|result| result))
+            .boxed()
     }
 }
@@ -0,0 +1,323 @@
+use crate::log;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use bitcoin::{Address, Network, Transaction, Txid};
+use index_btc::esplora;
+use index_btc::indexer::{blocking_query, Indexer, IndexerError};
+use index_btc::model::{HistoryFilter, ScriptHash};
+use index_btc::source::BlockSource;
+use index_btc::websocket::{self, flow_value};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::Arc;
+
+type ApiResult = Result<Json<Value>, (StatusCode, Json<Value>)>;
+
+// Serves until the shutdown future resolves, letting requests in flight complete, the Esplora
+// endpoints read transactions from the block source and websockets follow the commits
+pub async fn serve<I>(
+    indexer: I,
+    source: Option<Arc<dyn BlockSource>>,
+    network: Network,
+    http_addr: String,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), std::io::Error>
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    let app = Router::new()
+        .route("/address/:address/balance", get(get_balance::<I>))
+        .route("/address/:address/utxos", get(get_utxos::<I>))
+        .route("/address/:address/history", get(get_history::<I>))
+        .route("/address/:address/stats", get(get_address_stats::<I>))
+        .route("/scripthash/:script_hash/utxos", get(get_script_utxos::<I>))
+        .with_state(indexer.clone())
+        .merge(
+            Router::new()
+                .route("/transaction/:txid", get(get_transaction::<I>))
+                .with_state(TxState {
+                    indexer: indexer.clone(),
+                    source: source.clone(),
+                    network,
+                }),
+        )
+        .merge(esplora::router(indexer.clone(), source, network))
+        .merge(websocket::router(indexer));
+
+    let listener = tokio::net::TcpListener::bind(&http_addr).await?;
+    log!("Serving http api at : {}", http_addr);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+// Runs the query as json, its errors as a 500 response
+async fn query<I, F>(indexer: I, f: F) -> ApiResult
+where
+    I: Indexer + Send + 'static,
+    F: FnOnce(&I) -> Result<Value, IndexerError> + Send + 'static,
+{
+    blocking_query(move || f(&indexer))
+        .await
+        .map_err(|e| error_response(e.to_string()))?
+        .map(Json)
+        .map_err(|e| error_response(format!("{:?}", e)))
+}
+
+fn error_response(error: String) -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({ "error": error })),
+    )
+}
+
+// Heights and unix times bounding the history, like `?start_time=1709251200&end_time=1711929599`
+fn history_filter(
+    params: &HashMap<String, String>,
+) -> Result<HistoryFilter, (StatusCode, Json<Value>)> {
+    fn param<T: std::str::FromStr>(
+        params: &HashMap<String, String>,
+        name: &str,
+    ) -> Result<Option<T>, (StatusCode, Json<Value>)> {
+        params
+            .get(name)
+            .map(|value| value.parse())
+            .transpose()
+            .map_err(|_| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({ "error": format!("Invalid {}", name) })),
+                )
+            })
+    }
+    Ok(HistoryFilter {
+        start_height: param(params, "start_height")?,
+        end_height: param(params, "end_height")?,
+        start_time: param(params, "start_time")?,
+        end_time: param(params, "end_time")?,
+    })
+}
+
+async fn get_balance<I>(State(indexer): State<I>, Path(address): Path<String>) -> ApiResult
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    query(indexer, move |indexer| {
+        let height = indexer.get_last_height();
+        let balance = indexer.get_balance(&address)?;
+        Ok(json!({ "address": address, "height": height, "balance": balance }))
+    })
+    .await
+}
+
+async fn get_utxos<I>(State(indexer): State<I>, Path(address): Path<String>) -> ApiResult
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    query(indexer, move |indexer| {
+        let height = indexer.get_last_height();
+        let utxos: Vec<Value> = indexer
+            .get_utxos(&address)?
+            .into_iter()
+            .map(|(utxo, value)| json!({ "txid": utxo.tx_id.to_string(), "index": utxo.index, "value": value }))
+            .collect();
+        Ok(json!({ "address": address, "height": height, "utxos": utxos }))
+    })
+    .await
+}
+
+async fn get_history<I>(
+    State(indexer): State<I>,
+    Path(address): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> ApiResult
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    let filter = history_filter(&params)?;
+    query(indexer, move |indexer| {
+        let height = indexer.get_last_height();
+        let history: Vec<Value> = indexer
+            .get_history_filtered(&address, &filter)?
+            .iter()
+            .map(|(flow, value)| flow_value(flow, *value))
+            .collect();
+        Ok(json!({ "address": address, "height": height, "history": history }))
+    })
+    .await
+}
+
+async fn get_address_stats<I>(State(indexer): State<I>, Path(address): Path<String>) -> ApiResult
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    query(indexer, move |indexer| {
+        let height = indexer.get_last_height();
+        let stats = indexer.get_address_stats(&address)?.unwrap_or_default();
+        Ok(json!({
+            "address": address,
+            "height": height,
+            "funded_count": stats.funded_count,
+            "spent_count": stats.spent_count,
+            "received": stats.received,
+            "sent": stats.sent,
+            "tx_count": stats.tx_count,
+            "first_seen": stats.first_seen,
+            "last_seen": stats.last_seen,
+        }))
+    })
+    .await
+}
+
+#[derive(Clone)]
+struct TxState<I> {
+    indexer: I,
+    source: Option<Arc<dyn BlockSource>>,
+    network: Network,
+}
+
+// Transactions are read from the block source at the location the txid index has for them,
+// decoded like the verbose getrawtransaction of bitcoin-core with values in sats
+async fn get_transaction<I>(State(state): State<TxState<I>>, Path(txid): Path<String>) -> ApiResult
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    let txid = Txid::from_str(&txid).map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Invalid txid" })),
+        )
+    })?;
+    let source = state.source.ok_or_else(|| {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "error": "No block source, transactions need bitcoin rpc credentials" })),
+        )
+    })?;
+    let network = state.network;
+    let found = blocking_query(move || {
+        let indexer = state.indexer;
+        let Some((location, tx)) = indexer.get_transaction(source.as_ref(), &txid)? else {
+            return Ok(None);
+        };
+        // read after the lookup, commits landing meanwhile only raise the tip, a rollback meanwhile
+        // leaves the transaction unconfirmed
+        let height = indexer.get_last_height();
+        let confirmed = indexer
+            .get_block_hash(location.height)?
+            .filter(|_| location.height <= height);
+        Ok(Some(json!({
+            "height": confirmed.map(|_| location.height),
+            "position": confirmed.map(|_| location.position),
+            "blockhash": confirmed.map(|hash| hash.to_string()),
+            "confirmations": confirmed.map(|_| height + 1 - location.height),
+            "transaction": decoded_tx(&tx, network),
+        })))
+    })
+    .await
+    .map_err(|e| error_response(e.to_string()))?
+    .map_err(|e: IndexerError| error_response(format!("{:?}", e)))?;
+    found.map(Json).ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Transaction not found" })),
+        )
+    })
+}
+
+fn decoded_tx(tx: &Transaction, network: Network) -> Value {
+    let vin: Vec<Value> = tx
+        .input
+        .iter()
+        .map(|input| {
+            let witness: Vec<String> = input.witness.iter().map(base16::encode_lower).collect();
+            if tx.is_coinbase() {
+                json!({
+                    "coinbase": base16::encode_lower(input.script_sig.as_bytes()),
+                    "txinwitness": witness,
+                    "sequence": input.sequence.0,
+                })
+            } else {
+                json!({
+                    "txid": input.previous_output.txid.to_string(),
+                    "vout": input.previous_output.vout,
+                    "scriptSig": {
+                        "asm": input.script_sig.to_asm_string(),
+                        "hex": base16::encode_lower(input.script_sig.as_bytes()),
+                    },
+                    "txinwitness": witness,
+                    "sequence": input.sequence.0,
+                })
+            }
+        })
+        .collect();
+    let vout: Vec<Value> = tx
+        .output
+        .iter()
+        .enumerate()
+        .map(|(n, output)| {
+            let address = Address::from_script(&output.script_pubkey, network).ok();
+            json!({
+                "value": output.value.to_sat(),
+                "n": n,
+                "scriptPubKey": {
+                    "asm": output.script_pubkey.to_asm_string(),
+                    "hex": base16::encode_lower(output.script_pubkey.as_bytes()),
+                    "address": address.map(|address| address.to_string()),
+                },
+            })
+        })
+        .collect();
+    json!({
+        "txid": tx.compute_txid().to_string(),
+        "hash": tx.compute_wtxid().to_string(),
+        "version": tx.version.0,
+        "size": tx.total_size(),
+        "vsize": tx.vsize(),
+        "weight": tx.weight().to_wu(),
+        "locktime": tx.lock_time.to_consensus_u32(),
+        "vin": vin,
+        "vout": vout,
+        "hex": bitcoin::consensus::encode::serialize_hex(tx),
+    })
+}
+
+// Script hashes are sha256 of the output script in Electrum byte order, any script can be queried
+// by it whatever address it was indexed under
+fn script_hash_param(script_hash: &str) -> Result<ScriptHash, (StatusCode, Json<Value>)> {
+    script_hash.parse().map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Invalid script hash" })),
+        )
+    })
+}
+
+async fn get_script_utxos<I>(State(indexer): State<I>, Path(script_hash): Path<String>) -> ApiResult
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    let script_hash = script_hash_param(&script_hash)?;
+    query(indexer, move |indexer| {
+        let height = indexer.get_last_height();
+        let address = indexer.get_script_address(&script_hash)?;
+        let utxos = indexer.get_script_utxos(&script_hash)?;
+        let balance: u64 = utxos.iter().map(|(_, value)| value).sum();
+        let utxos: Vec<Value> = utxos
+            .into_iter()
+            .map(|(utxo, value)| json!({ "txid": utxo.tx_id.to_string(), "index": utxo.index, "value": value }))
+            .collect();
+        Ok(json!({
+            "script_hash": script_hash.to_string(),
+            "address": address,
+            "height": height,
+            "balance": balance,
+            "utxos": utxos,
+        }))
+    })
+    .await
+}
@@ -1,112 +1,1431 @@
-use index_btc::indexer::{Indexer, IndexerError};
-use index_btc::model::{self, SumTx, Utxo, ADDRESS_CF, CACHE_CF, LAST_HEIGHT_KEY, META_CF};
-use sled::transaction::{TransactionError, Transactional, UnabortableTransactionError};
+use crate::cache::{StagedCache, UtxoCache};
+use crate::codec::{
+    self, address_key, address_prefix, decode_flow_value, decode_height, decode_history_row,
+    decode_network, decode_schema_version, decode_script_history_row, decode_value,
+    encode_input_value, encode_network, encode_output_value, encode_schema_version, encode_value,
+    flow_history_key, history_bound, history_key, history_position_bound, outpoint_key,
+    CODEC_SCHEMA_VERSION, FLOW_HEIGHT_SCHEMA_VERSION, HISTORY_SCHEMA_VERSION,
+    LEGACY_SCHEMA_VERSION, META_SCHEMA_VERSION, NETWORK_SCHEMA_VERSION, SCHEMA_VERSION,
+    SCHEMA_VERSION_KEY, SCRIPT_HISTORY_SCHEMA_VERSION, STATS_SCHEMA_VERSION, TX_SCHEMA_VERSION,
+};
+use crate::events::{self, EventPublisher, IndexEvent};
+use crate::indexer::{Indexer, IndexerError};
+use crate::log;
+use crate::model::{
+    AddressFlow, AddressStats, BalanceDeltas, BlockActivity, Flow, FlowValue, HeaderRecord,
+    HistoryFilter, IndexedBlock, IndexedTxid, ScriptHash, Spend, SumTx, TxLocation, UndoRecord,
+    Utxo, ADDRESS_BALANCE_CF, ADDRESS_CF, ADDRESS_STATS_CF, BLOCK_HASH_CF, BLOCK_HEIGHT_CF,
+    CACHE_CF, HEADER_CF, HISTORY_CF, LAST_HEIGHT_KEY, MEDIAN_TIME_SPAN, META_CF, NETWORK_KEY,
+    OP_RETURN, SCRIPT_HASH_CF, SCRIPT_HISTORY_CF, SCRIPT_HISTORY_HEIGHT_KEY, TX_CF, UNDO_CF,
+    UTXO_FLUSH_HEIGHT_KEY,
+};
+use bitcoin::hashes::Hash;
+use bitcoin::{BlockHash, Network, Txid};
+use sled::transaction::{
+    ConflictableTransactionError, TransactionError, Transactional, TransactionalTree,
+    UnabortableTransactionError,
+};
 use sled::Tree;
-use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::str;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::broadcast;
 
 pub struct SledDbIndexer {
     db: Arc<RwLock<sled::Db>>,
+    utxo_cache: Arc<Mutex<UtxoCache>>,
+    events: EventPublisher,
 }
 
 impl Clone for SledDbIndexer {
     fn clone(&self) -> SledDbIndexer {
         SledDbIndexer {
             db: Arc::clone(&self.db),
+            utxo_cache: Arc::clone(&self.utxo_cache),
+            events: self.events.clone(),
         }
     }
 }
 
 impl SledDbIndexer {
     // Method to process the outputs of a transaction
+    #[allow(clippy::too_many_arguments)]
     fn process_outputs(
         &self,
         sum_tx: &SumTx,
-        tree: &sled::transaction::TransactionalTree,
+        position: usize,
+        block: &IndexedBlock,
+        utxo_cache: &mut StagedCache,
         batch: &mut sled::Batch,
-    ) -> Result<(), UnabortableTransactionError> {
+        history_batch: &mut sled::Batch,
+        script_history_batch: &mut sled::Batch,
+        activity: &mut BlockActivity,
+        undo: &mut UndoRecord,
+        history_rows: &mut Option<Vec<(Vec<u8>, Vec<u8>)>>,
+    ) {
         for utxo in sum_tx.outs.iter() {
-            let tx_id_with_index = format!("{}|{}", &sum_tx.txid, utxo.index);
-            let utxo_bytes = utxo.to_string().into_bytes();
-            tree.insert(tx_id_with_index.as_bytes(), utxo_bytes)?;
-            let address_key = format!("{}|{}|{}|{}", utxo.address, "O", &sum_tx.txid, utxo.index);
-            batch.insert(
-                address_key.as_bytes(),
-                u64::to_be_bytes(utxo.value).as_slice(),
+            let cache_key = outpoint_key(&sum_tx.txid, utxo.index);
+            utxo_cache.add(cache_key.clone(), utxo.to_bytes());
+            activity.fund(&utxo.address, &sum_tx.txid, utxo.value);
+            let address_key = address_key(&utxo.address, &Flow::O, &sum_tx.txid, utxo.index);
+            let value = encode_output_value(utxo.value, block.height, block.header.time, position);
+            let address_history_key = history_key(
+                &address_prefix(&utxo.address),
+                block.height,
+                position,
+                &Flow::O,
+                &sum_tx.txid,
+                utxo.index,
             );
+            if let Some(history_rows) = history_rows {
+                history_rows.push((address_history_key.clone(), value.clone()));
+            }
+            history_batch.insert(address_history_key, value.as_slice());
+            // op_return outputs are never queried by their script
+            if let Some(script_hash) = utxo.script_hash.filter(|_| utxo.address != OP_RETURN) {
+                let script_history_key = history_key(
+                    &script_hash.0,
+                    block.height,
+                    position,
+                    &Flow::O,
+                    &sum_tx.txid,
+                    utxo.index,
+                );
+                script_history_batch.insert(script_history_key.as_slice(), value.as_slice());
+                undo.script_history_keys.push(script_history_key);
+            }
+            batch.insert(address_key.as_slice(), value);
+            undo.cache_keys.push(cache_key);
+            undo.address_keys.push(address_key);
         }
-        Ok(())
     }
 
     // Method to process the inputs of a transaction
+    #[allow(clippy::too_many_arguments)]
     fn process_inputs(
         &self,
         sum_tx: &SumTx,
-        tree: &sled::transaction::TransactionalTree,
+        position: usize,
+        block: &IndexedBlock,
+        cache_tree: &Tree,
+        utxo_cache: &mut StagedCache,
         batch: &mut sled::Batch,
-    ) {
-        for indexed_txid in &sum_tx.ins {
-            let tx_cache_key = indexed_txid.to_string();
-            let utxo_str = tree.get(tx_cache_key).unwrap().unwrap();
-            let utxo: Utxo = Utxo::try_from(utxo_str.to_vec()).unwrap();
-            let address_key = format!(
-                "{}|{}|{}|{}",
-                utxo.address, "I", indexed_txid.tx_id, indexed_txid.index
+        history_batch: &mut sled::Batch,
+        script_history_batch: &mut sled::Batch,
+        activity: &mut BlockActivity,
+        undo: &mut UndoRecord,
+        history_rows: &mut Option<Vec<(Vec<u8>, Vec<u8>)>>,
+    ) -> Result<(), IndexerError> {
+        for (vin, indexed_txid) in sum_tx.ins.iter().enumerate() {
+            let cache_key = indexed_txid.to_bytes();
+            let utxo_bytes = match utxo_cache.spend(&cache_key) {
+                Some(utxo_bytes) => utxo_bytes,
+                None => cache_tree
+                    .get(&cache_key)
+                    .map_err(|e| IndexerError::SledError(e.to_string()))?
+                    .ok_or_else(|| IndexerError::MissingUtxo {
+                        height: block.height,
+                        outpoint: indexed_txid.clone(),
+                    })?
+                    .to_vec(),
+            };
+            let utxo =
+                Utxo::try_from(utxo_bytes.as_slice()).map_err(|e| IndexerError::InvalidUtxo {
+                    height: block.height,
+                    outpoint: indexed_txid.clone(),
+                    error: format!("{:?}", e),
+                })?;
+            activity.spend(&utxo.address, &sum_tx.txid, utxo.value);
+            let address_key = address_key(
+                &utxo.address,
+                &Flow::I,
+                &indexed_txid.tx_id,
+                indexed_txid.index,
             );
-            batch.insert(
-                address_key.as_bytes(),
-                u64::to_be_bytes(utxo.value).as_slice(),
+            let spend = Spend {
+                tx_id: sum_tx.txid,
+                vin,
+                height: block.height,
+            };
+            let value = encode_input_value(utxo.value, &spend, block.header.time, position);
+            let address_history_key = history_key(
+                &address_prefix(&utxo.address),
+                block.height,
+                position,
+                &Flow::I,
+                &indexed_txid.tx_id,
+                indexed_txid.index,
             );
+            if let Some(history_rows) = history_rows {
+                history_rows.push((address_history_key.clone(), value.clone()));
+            }
+            history_batch.insert(address_history_key, value.as_slice());
+            // outputs cached before script hashes were recorded leave no script history
+            if let Some(script_hash) = utxo.script_hash {
+                let script_history_key = history_key(
+                    &script_hash.0,
+                    block.height,
+                    position,
+                    &Flow::I,
+                    &indexed_txid.tx_id,
+                    indexed_txid.index,
+                );
+                script_history_batch.insert(script_history_key.as_slice(), value.as_slice());
+                undo.script_history_keys.push(script_history_key);
+            }
+            batch.insert(address_key.as_slice(), value);
+            undo.spent_utxos.push((cache_key, utxo_bytes));
+            undo.address_keys.push(address_key);
+        }
+        Ok(())
+    }
+
+    // The writes flushing the utxo cache to the cache tree
+    fn cache_batch(utxo_cache: &StagedCache) -> sled::Batch {
+        let mut batch = sled::Batch::default();
+        for (cache_key, utxo_bytes) in utxo_cache.added() {
+            batch.insert(cache_key.as_slice(), utxo_bytes.as_slice());
         }
+        // spent outputs leave the cache so that it holds exactly the utxo set
+        for cache_key in utxo_cache.spent() {
+            batch.remove(cache_key.as_slice());
+        }
+        batch
     }
-}
 
-impl Indexer for SledDbIndexer {
-    fn update_balance(
-        &mut self,
+    // Flushes the utxo cache in the transaction, recording the height it was flushed at
+    fn write_utxo_cache(
+        utxo_cache: &StagedCache,
         height: u64,
-        sum_txs: &Vec<model::SumTx>,
+        cache_batch: &sled::Batch,
+        cache_tree: &sled::transaction::TransactionalTree,
+        undo_tree: &sled::transaction::TransactionalTree,
+        meta_tree: &sled::transaction::TransactionalTree,
+    ) -> Result<(), UnabortableTransactionError> {
+        cache_tree.apply_batch(cache_batch)?;
+        for undo_height in utxo_cache.expired_undo_heights(height) {
+            undo_tree.remove(&undo_height.to_be_bytes())?;
+        }
+        meta_tree.insert(UTXO_FLUSH_HEIGHT_KEY, &height.to_be_bytes())?;
+        Ok(())
+    }
+
+    // Applies the balance deltas to the stored balances in the transaction
+    fn write_balances(
+        balances: &BalanceDeltas,
+        balance_tree: &TransactionalTree,
+    ) -> Result<(), ConflictableTransactionError<IndexerError>> {
+        for (address, delta) in balances.iter() {
+            let balance = balance_tree
+                .get(address.as_bytes())?
+                .map(|balance| decode_value(&balance))
+                .transpose()
+                .map_err(|e| {
+                    ConflictableTransactionError::Abort(IndexerError::CodecError(format!(
+                        "{:?}",
+                        e
+                    )))
+                })?
+                .unwrap_or(0);
+            match BalanceDeltas::apply(address, balance, delta)
+                .map_err(ConflictableTransactionError::Abort)?
+            {
+                Some(balance) => balance_tree.insert(address.as_bytes(), &encode_value(balance))?,
+                None => balance_tree.remove(address.as_bytes())?,
+            };
+        }
+        Ok(())
+    }
+
+    // Deletes the flow rows of an undo record along with their address and script history rows,
+    // reverting their effect on balances, and puts back the stats of its addresses
+    fn undo_address_rows(
+        undo: &UndoRecord,
+        address_tree: &TransactionalTree,
+        history_tree: &TransactionalTree,
+        script_history_tree: &TransactionalTree,
+        stats_tree: &TransactionalTree,
+        balances: &mut BalanceDeltas,
+    ) -> Result<(), ConflictableTransactionError<IndexerError>> {
+        let parse_error =
+            |e| ConflictableTransactionError::Abort(IndexerError::ParseError(format!("{:?}", e)));
+        for address_key in &undo.address_keys {
+            if let Some(value) = address_tree.remove(address_key.as_slice())? {
+                balances
+                    .revert_flow(address_key, &value)
+                    .map_err(parse_error)?;
+                history_tree.remove(flow_history_key(address_key, &value).map_err(parse_error)?)?;
+            }
+        }
+        for script_history_key in &undo.script_history_keys {
+            script_history_tree.remove(script_history_key.as_slice())?;
+        }
+        for (address, stats) in &undo.address_stats {
+            match stats {
+                Some(stats) => stats_tree.insert(address.as_bytes(), stats.to_bytes())?,
+                None => stats_tree.remove(address.as_bytes())?,
+            };
+        }
+        Ok(())
+    }
+
+    fn read_stats(stats_tree: &Tree, address: &str) -> Result<Option<AddressStats>, IndexerError> {
+        stats_tree
+            .get(address.as_bytes())
+            .map_err(|e| IndexerError::SledError(e.to_string()))?
+            .map(|stats| AddressStats::try_from(stats.as_ref()))
+            .transpose()
+            .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))
+    }
+
+    // Sums up the balance and stats of every address from its flow rows, which are contiguous by
+    // address, heights are unknown since output flow rows carry none
+    fn sum_up_addresses(db: &sled::Db) -> Result<(), IndexerError> {
+        let address_tree = Self::open_tree(db, ADDRESS_CF)?;
+        let balance_tree = Self::open_tree(db, ADDRESS_BALANCE_CF)?;
+        let stats_tree = Self::open_tree(db, ADDRESS_STATS_CF)?;
+        let sled_error = |e: sled::Error| IndexerError::SledError(e.to_string());
+        let mut balance_batch = sled::Batch::default();
+        let mut stats_batch = sled::Batch::default();
+        let mut current: Option<(String, AddressStats)> = None;
+        let mut count = 0u64;
+        let put_address = |balance_batch: &mut sled::Batch,
+                           stats_batch: &mut sled::Batch,
+                           (address, stats): (String, AddressStats)| {
+            if stats.balance() > 0 {
+                balance_batch.insert(address.as_bytes(), encode_value(stats.balance()).as_slice());
+            }
+            stats_batch.insert(address.as_bytes(), stats.to_bytes());
+        };
+        for item in address_tree.iter() {
+            let (key, value) = item.map_err(sled_error)?;
+            let flow = AddressFlow::try_from(key.as_ref())
+                .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?;
+            let FlowValue { value, .. } = decode_flow_value(&flow.flow, &value)
+                .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?;
+            if current
+                .as_ref()
+                .is_none_or(|(address, _)| *address != flow.address)
+            {
+                if let Some(previous) = current.replace((flow.address, AddressStats::default())) {
+                    put_address(&mut balance_batch, &mut stats_batch, previous);
+                    count += 1;
+                    if count.is_multiple_of(1_000_000) {
+                        balance_tree
+                            .apply_batch(std::mem::take(&mut balance_batch))
+                            .map_err(sled_error)?;
+                        stats_tree
+                            .apply_batch(std::mem::take(&mut stats_batch))
+                            .map_err(sled_error)?;
+                        log!("Summed up {} addresses", count);
+                    }
+                }
+            }
+            let (_, stats) = current.as_mut().unwrap();
+            match flow.flow {
+                Flow::O => stats.fund(value),
+                Flow::I => stats.spend(value),
+            }
+        }
+        if let Some(last) = current {
+            put_address(&mut balance_batch, &mut stats_batch, last);
+            count += 1;
+        }
+        balance_tree
+            .apply_batch(balance_batch)
+            .map_err(sled_error)?;
+        stats_tree.apply_batch(stats_batch).map_err(sled_error)?;
+        log!("Summed up {} addresses", count);
+        Ok(())
+    }
+
+    fn read_header(header_tree: &Tree, height: u64) -> Result<Option<HeaderRecord>, IndexerError> {
+        header_tree
+            .get(height.to_be_bytes())
+            .map_err(|e| IndexerError::SledError(e.to_string()))?
+            .map(|record| HeaderRecord::try_from(record.as_ref()))
+            .transpose()
+            .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))
+    }
+
+    // Deletes the hash, header and hash to height rows of the block at the height
+    fn delete_block_rows(
+        height: u64,
+        block_hash_tree: &TransactionalTree,
+        header_tree: &TransactionalTree,
+        block_height_tree: &TransactionalTree,
+    ) -> Result<(), UnabortableTransactionError> {
+        if let Some(block_hash) = block_hash_tree.remove(&height.to_be_bytes())? {
+            block_height_tree.remove(block_hash)?;
+        }
+        header_tree.remove(&height.to_be_bytes())?;
+        Ok(())
+    }
+
+    // Maps the hashes of the indexed blocks to their heights
+    fn index_block_heights(db: &sled::Db) -> Result<(), IndexerError> {
+        let block_hash_tree = Self::open_tree(db, BLOCK_HASH_CF)?;
+        let block_height_tree = Self::open_tree(db, BLOCK_HEIGHT_CF)?;
+        let sled_error = |e: sled::Error| IndexerError::SledError(e.to_string());
+        let mut batch = sled::Batch::default();
+        let mut count = 0u64;
+        for item in block_hash_tree.iter() {
+            let (height, block_hash) = item.map_err(sled_error)?;
+            batch.insert(block_hash, height);
+            count += 1;
+        }
+        block_height_tree.apply_batch(batch).map_err(sled_error)?;
+        log!("Indexed the heights of {} block hashes", count);
+        Ok(())
+    }
+
+    // Maps the script hashes of the indexed addresses to them, scripts indexed under a hash of
+    // their asm have no address to derive theirs from
+    fn index_script_hashes(db: &sled::Db) -> Result<(), IndexerError> {
+        let stats_tree = Self::open_tree(db, ADDRESS_STATS_CF)?;
+        let script_hash_tree = Self::open_tree(db, SCRIPT_HASH_CF)?;
+        let sled_error = |e: sled::Error| IndexerError::SledError(e.to_string());
+        let mut batch = sled::Batch::default();
+        let mut count = 0u64;
+        for item in stats_tree.iter() {
+            let (address, _) = item.map_err(sled_error)?;
+            let address = std::str::from_utf8(&address)
+                .map_err(|e| IndexerError::ParseError(format!("Invalid address : {}", e)))?;
+            if let Some(script_hash) = ScriptHash::from_address(address) {
+                batch.insert(&script_hash.0, address.as_bytes());
+                count += 1;
+                if count.is_multiple_of(1_000_000) {
+                    script_hash_tree
+                        .apply_batch(std::mem::take(&mut batch))
+                        .map_err(sled_error)?;
+                    log!("Indexed the script hashes of {} addresses", count);
+                }
+            }
+        }
+        script_hash_tree.apply_batch(batch).map_err(sled_error)?;
+        log!("Indexed the script hashes of {} addresses", count);
+        Ok(())
+    }
+
+    // Copies the flow rows into the history, ordered by height only as their positions are unknown
+    fn index_history(db: &sled::Db) -> Result<(), IndexerError> {
+        let address_tree = Self::open_tree(db, ADDRESS_CF)?;
+        let history_tree = Self::open_tree(db, HISTORY_CF)?;
+        let sled_error = |e: sled::Error| IndexerError::SledError(e.to_string());
+        let mut batch = sled::Batch::default();
+        let mut count = 0u64;
+        for item in address_tree.iter() {
+            let (key, value) = item.map_err(sled_error)?;
+            let history_key = flow_history_key(&key, &value)
+                .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?;
+            batch.insert(history_key, value);
+            count += 1;
+            if count.is_multiple_of(1_000_000) {
+                history_tree
+                    .apply_batch(std::mem::take(&mut batch))
+                    .map_err(sled_error)?;
+                log!("Indexed the history of {} flows", count);
+            }
+        }
+        history_tree.apply_batch(batch).map_err(sled_error)?;
+        log!("Indexed the history of {} flows", count);
+        Ok(())
+    }
+
+    // Undoes the flow rows of blocks indexed after the last utxo cache flush, whose outputs were lost
+    // with the process, returns the flush height indexing resumes from
+    fn recover_unflushed(db: &sled::Db) -> Result<u64, IndexerError> {
+        let address_tree = Self::open_tree(db, ADDRESS_CF)?;
+        let history_tree = Self::open_tree(db, HISTORY_CF)?;
+        let script_history_tree = Self::open_tree(db, SCRIPT_HISTORY_CF)?;
+        let meta_tree = Self::open_tree(db, META_CF)?;
+        let undo_tree = Self::open_tree(db, UNDO_CF)?;
+        let block_hash_tree = Self::open_tree(db, BLOCK_HASH_CF)?;
+        let header_tree = Self::open_tree(db, HEADER_CF)?;
+        let block_height_tree = Self::open_tree(db, BLOCK_HEIGHT_CF)?;
+        let tx_tree = Self::open_tree(db, TX_CF)?;
+        let balance_tree = Self::open_tree(db, ADDRESS_BALANCE_CF)?;
+        let stats_tree = Self::open_tree(db, ADDRESS_STATS_CF)?;
+        let get_height = |key: &[u8]| -> Result<Option<u64>, IndexerError> {
+            meta_tree
+                .get(key)
+                .map_err(|e| IndexerError::SledError(e.to_string()))?
+                .map(|height| decode_height(&height))
+                .transpose()
+                .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))
+        };
+        let last_height = get_height(LAST_HEIGHT_KEY)?.unwrap_or(0);
+        // dbs written before the utxo cache have every output in the db
+        let flushed_height = get_height(UTXO_FLUSH_HEIGHT_KEY)?.unwrap_or(last_height);
+        if flushed_height >= last_height {
+            return Ok(last_height);
+        }
+        log!(
+            "Utxo cache was flushed @ {}, undoing blocks up to {}",
+            flushed_height,
+            last_height
+        );
+        (
+            &address_tree,
+            &history_tree,
+            &script_history_tree,
+            &meta_tree,
+            &undo_tree,
+            &block_hash_tree,
+            &header_tree,
+            &block_height_tree,
+            &tx_tree,
+            &balance_tree,
+            &stats_tree,
+        )
+            .transaction(
+                |(
+                    address_tree,
+                    history_tree,
+                    script_history_tree,
+                    meta_tree,
+                    undo_tree,
+                    block_hash_tree,
+                    header_tree,
+                    block_height_tree,
+                    tx_tree,
+                    balance_tree,
+                    stats_tree,
+                )| {
+                    let mut balances = BalanceDeltas::default();
+                    for undo_height in ((flushed_height + 1)..=last_height).rev() {
+                        let undo_bytes = undo_tree.get(undo_height.to_be_bytes())?.ok_or(
+                            ConflictableTransactionError::Abort(IndexerError::RollbackError(
+                                format!("Missing undo record @ {}", undo_height),
+                            )),
+                        )?;
+                        let undo = UndoRecord::try_from(undo_bytes.as_ref()).map_err(|e| {
+                            ConflictableTransactionError::Abort(IndexerError::ParseError(format!(
+                                "{:?}",
+                                e
+                            )))
+                        })?;
+                        Self::undo_address_rows(
+                            &undo,
+                            address_tree,
+                            history_tree,
+                            script_history_tree,
+                            stats_tree,
+                            &mut balances,
+                        )?;
+                        for txid in &undo.txids {
+                            tx_tree.remove(txid.as_byte_array())?;
+                        }
+                        for (txid, location) in &undo.replaced_txs {
+                            tx_tree.insert(txid.as_byte_array(), location.to_bytes())?;
+                        }
+                        undo_tree.remove(&undo_height.to_be_bytes())?;
+                        Self::delete_block_rows(
+                            undo_height,
+                            block_hash_tree,
+                            header_tree,
+                            block_height_tree,
+                        )?;
+                    }
+                    Self::write_balances(&balances, balance_tree)?;
+                    meta_tree.insert(LAST_HEIGHT_KEY, &flushed_height.to_be_bytes())?;
+                    Ok(())
+                },
+            )
+            .map_err(|e: TransactionError<IndexerError>| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => IndexerError::SledError(e.to_string()),
+            })?;
+        Ok(flushed_height)
+    }
+
+    // Copies the rows of a db in the legacy string layout into this one using the binary codec
+    pub fn convert_legacy(
+        _num_cores: i32,
+        db_path: &str,
+        legacy_db_path: &str,
     ) -> Result<(), IndexerError> {
+        let db = Self::open_db(db_path)?;
+        let legacy_db = Self::open_db(legacy_db_path)?;
+        let convert_error = |e| IndexerError::CodecError(format!("{:?}", e));
+        let sled_error = |e: sled::Error| IndexerError::SledError(e.to_string());
+
+        for tree_name in [ADDRESS_CF, CACHE_CF, UNDO_CF, BLOCK_HASH_CF] {
+            let legacy_tree = Self::open_tree(&legacy_db, tree_name)?;
+            let tree = Self::open_tree(&db, tree_name)?;
+            let mut batch = sled::Batch::default();
+            let mut count: u64 = 0;
+            for item in legacy_tree.iter() {
+                let (key, value) = item.map_err(sled_error)?;
+                match tree_name {
+                    ADDRESS_CF => {
+                        let value =
+                            u64::from_be_bytes(value.as_ref().try_into().map_err(|_| {
+                                IndexerError::CodecError("Invalid address value".to_string())
+                            })?);
+                        let key =
+                            codec::legacy::convert_address_key(&key).map_err(convert_error)?;
+                        batch.insert(key, encode_value(value).as_slice());
+                    }
+                    CACHE_CF => {
+                        let key = codec::legacy::convert_cache_key(&key).map_err(convert_error)?;
+                        let value =
+                            codec::legacy::convert_cache_value(&value).map_err(convert_error)?;
+                        batch.insert(key, value);
+                    }
+                    UNDO_CF => {
+                        let value =
+                            codec::legacy::convert_undo_record(&value).map_err(convert_error)?;
+                        batch.insert(key, value);
+                    }
+                    _ => batch.insert(key, value),
+                }
+                count += 1;
+                if count.is_multiple_of(1_000_000) {
+                    tree.apply_batch(std::mem::take(&mut batch))
+                        .map_err(sled_error)?;
+                    log!("Converted {} rows of {}", count, tree_name);
+                }
+            }
+            tree.apply_batch(batch).map_err(sled_error)?;
+            log!("Converted {} rows of {}", count, tree_name);
+        }
+        // schema version is written last, without it the conversion was interrupted
+        let legacy_meta_tree = Self::open_tree(&legacy_db, META_CF)?;
+        let meta_tree = Self::open_tree(&db, META_CF)?;
+        if let Some(height) = legacy_meta_tree.get(LAST_HEIGHT_KEY).map_err(sled_error)? {
+            let height = codec::legacy::convert_last_height(&height).map_err(convert_error)?;
+            meta_tree
+                .insert(LAST_HEIGHT_KEY, &height)
+                .map_err(sled_error)?;
+        }
+        // only mainnet could be indexed by the legacy layout
+        meta_tree
+            .insert(NETWORK_KEY, encode_network(Network::Bitcoin))
+            .map_err(sled_error)?;
+        // balances are summed up by the migration that follows
+        meta_tree
+            .insert(
+                SCHEMA_VERSION_KEY,
+                &encode_schema_version(NETWORK_SCHEMA_VERSION),
+            )
+            .map_err(sled_error)?;
+        db.flush().map_err(sled_error)?;
+        Ok(())
+    }
+
+    // Upgrades a db of an older schema version in place, legacy dbs need `convert_legacy` instead
+    pub fn migrate(_num_cores: i32, db_path: &str) -> Result<(), IndexerError> {
+        let db = Self::open_db(db_path)?;
+        let meta_tree = Self::open_tree(&db, META_CF)?;
+        let version = match Self::stored_schema_version(&meta_tree)? {
+            None | Some(SCHEMA_VERSION) => {
+                log!("{} is at schema version {}", db_path, SCHEMA_VERSION);
+                return Ok(());
+            }
+            Some(version) if version < CODEC_SCHEMA_VERSION || version > SCHEMA_VERSION => {
+                return Err(Self::schema_error(db_path, version))
+            }
+            Some(version) => version,
+        };
+        // written outside of the transaction for their size, a rerun sums them up again
+        if version < STATS_SCHEMA_VERSION {
+            Self::sum_up_addresses(&db)?;
+        }
+        if version <= FLOW_HEIGHT_SCHEMA_VERSION {
+            Self::index_block_heights(&db)?;
+        }
+        if version <= TX_SCHEMA_VERSION {
+            Self::index_script_hashes(&db)?;
+        }
+        if version <= HISTORY_SCHEMA_VERSION {
+            Self::index_history(&db)?;
+        }
+        meta_tree
+            .transaction(|meta_tree| {
+                if version < META_SCHEMA_VERSION {
+                    if let Some(height) = meta_tree.get(LAST_HEIGHT_KEY)? {
+                        let height = codec::legacy::convert_last_height(&height).map_err(|e| {
+                            ConflictableTransactionError::Abort(IndexerError::CodecError(format!(
+                                "{:?}",
+                                e
+                            )))
+                        })?;
+                        meta_tree.insert(LAST_HEIGHT_KEY, &height)?;
+                    }
+                    meta_tree.remove(codec::legacy::CODEC_VERSION_KEY)?;
+                }
+                // only mainnet could be indexed before the network was recorded
+                if version < NETWORK_SCHEMA_VERSION {
+                    meta_tree.insert(NETWORK_KEY, encode_network(Network::Bitcoin))?;
+                }
+                if version <= SCRIPT_HISTORY_SCHEMA_VERSION {
+                    if let Some(height) = meta_tree.get(LAST_HEIGHT_KEY)? {
+                        meta_tree.insert(SCRIPT_HISTORY_HEIGHT_KEY, height)?;
+                    }
+                }
+                meta_tree.insert(SCHEMA_VERSION_KEY, &encode_schema_version(SCHEMA_VERSION))?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError<IndexerError>| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => IndexerError::SledError(e.to_string()),
+            })?;
+        db.flush()
+            .map_err(|e| IndexerError::SledError(e.to_string()))?;
+        log!(
+            "Migrated {} from schema version {} to {}",
+            db_path,
+            version,
+            SCHEMA_VERSION
+        );
+        Ok(())
+    }
+
+    pub fn schema_version(_num_cores: i32, db_path: &str) -> Result<Option<u32>, IndexerError> {
+        let db = Self::open_db(db_path)?;
+        Self::stored_schema_version(&Self::open_tree(&db, META_CF)?)
+    }
+
+    // Dbs written before the schema version record are recognized by their metadata, empty ones have none
+    fn stored_schema_version(meta_tree: &Tree) -> Result<Option<u32>, IndexerError> {
+        let sled_error = |e: sled::Error| IndexerError::SledError(e.to_string());
+        if let Some(version) = meta_tree.get(SCHEMA_VERSION_KEY).map_err(sled_error)? {
+            return decode_schema_version(&version)
+                .map(Some)
+                .map_err(|e| IndexerError::CodecError(format!("{:?}", e)));
+        }
+        if meta_tree
+            .contains_key(codec::legacy::CODEC_VERSION_KEY)
+            .map_err(sled_error)?
+        {
+            Ok(Some(CODEC_SCHEMA_VERSION))
+        } else if meta_tree
+            .contains_key(LAST_HEIGHT_KEY)
+            .map_err(sled_error)?
+        {
+            Ok(Some(LEGACY_SCHEMA_VERSION))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Stamps a new db with the schema version and network, refuses an existing one not matching them
+    fn check_metadata(
+        meta_tree: &Tree,
+        db_path: &str,
+        network: Network,
+    ) -> Result<(), IndexerError> {
+        let sled_error = |e: sled::Error| IndexerError::SledError(e.to_string());
+        match Self::stored_schema_version(meta_tree)? {
+            Some(SCHEMA_VERSION) => {}
+            Some(version) => return Err(Self::schema_error(db_path, version)),
+            None => {
+                meta_tree
+                    .insert(NETWORK_KEY, encode_network(network))
+                    .map_err(sled_error)?;
+                meta_tree
+                    .insert(SCHEMA_VERSION_KEY, &encode_schema_version(SCHEMA_VERSION))
+                    .map_err(sled_error)?;
+            }
+        }
+        let stored_network = meta_tree
+            .get(NETWORK_KEY)
+            .map_err(sled_error)?
+            .map(|network| decode_network(&network))
+            .transpose()
+            .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))?;
+        match stored_network {
+            Some(stored_network) if stored_network == network => Ok(()),
+            Some(stored_network) => Err(IndexerError::NetworkError(format!(
+                "{} was built for network {}, not {}",
+                db_path, stored_network, network
+            ))),
+            None => Err(IndexerError::NetworkError(format!(
+                "{} has no network recorded",
+                db_path
+            ))),
+        }
+    }
+
+    fn schema_error(db_path: &str, version: u32) -> IndexerError {
+        if version < SCHEMA_VERSION {
+            IndexerError::CodecError(format!(
+                "{} is at schema version {}, run `index_btc migrate` first",
+                db_path, version
+            ))
+        } else {
+            IndexerError::CodecError(format!(
+                "{} is at unknown schema version {}",
+                db_path, version
+            ))
+        }
+    }
+
+    fn open_db(db_path: &str) -> Result<sled::Db, IndexerError> {
+        sled::open(db_path).map_err(|e| IndexerError::SledError(e.to_string()))
+    }
+
+    fn open_tree(db: &sled::Db, name: &str) -> Result<Tree, IndexerError> {
+        db.open_tree(name)
+            .map_err(|e| IndexerError::SledError(e.to_string()))
+    }
+}
+
+impl Indexer for SledDbIndexer {
+    fn update_blocks(&mut self, blocks: &[IndexedBlock]) -> Result<(), IndexerError> {
+        let Some(last_block) = blocks.last() else {
+            return Ok(());
+        };
         let db_arc = self.db.clone();
         let db = db_arc.write().unwrap();
         let address_tree = db.open_tree(ADDRESS_CF).unwrap();
+        let history_tree = db.open_tree(HISTORY_CF).unwrap();
+        let script_history_tree = db.open_tree(SCRIPT_HISTORY_CF).unwrap();
         let cache_tree: Tree = db.open_tree(CACHE_CF).unwrap();
         let meta_tree: Tree = db.open_tree(META_CF).unwrap();
+        let undo_tree: Tree = db.open_tree(UNDO_CF).unwrap();
+        let block_hash_tree: Tree = db.open_tree(BLOCK_HASH_CF).unwrap();
+        let header_tree: Tree = db.open_tree(HEADER_CF).unwrap();
+        let block_height_tree: Tree = db.open_tree(BLOCK_HEIGHT_CF).unwrap();
+        let tx_tree: Tree = db.open_tree(TX_CF).unwrap();
+        let script_hash_tree: Tree = db.open_tree(SCRIPT_HASH_CF).unwrap();
+        let balance_tree: Tree = db.open_tree(ADDRESS_BALANCE_CF).unwrap();
+        let stats_tree: Tree = db.open_tree(ADDRESS_STATS_CF).unwrap();
+        let mut utxo_cache = self.utxo_cache.lock().unwrap();
+        let mut staged = utxo_cache.stage();
 
-        (&address_tree, &cache_tree, &meta_tree)
-            .transaction(|(address_tree, cache_tree, meta_tree)| {
-                let mut address_batch = sled::Batch::default();
-                for sum_tx in sum_txs {
-                    self.process_outputs(&sum_tx, &cache_tree, &mut address_batch)?;
-                    if !sum_tx.is_coinbase {
-                        self.process_inputs(sum_tx, &cache_tree, &mut address_batch);
+        // the transaction closure may run more than once, so blocks are processed ahead of it,
+        // the write lock keeps the cache tree unchanged meanwhile
+        let mut address_batch = sled::Batch::default();
+        let mut history_batch = sled::Batch::default();
+        let mut script_history_batch = sled::Batch::default();
+        let mut tx_batch = sled::Batch::default();
+        let mut script_hash_batch = sled::Batch::default();
+        let mut balances = BalanceDeltas::default();
+        let mut stats = HashMap::new();
+        let mut undo_records = Vec::with_capacity(blocks.len());
+        let mut coinbases = HashMap::new();
+        // the rows are only kept to be published while someone listens
+        let mut history_rows = self.events.has_subscribers().then(Vec::new);
+        for block in blocks {
+            let mut undo = UndoRecord::default();
+            let mut activity = BlockActivity::default();
+            for (position, sum_tx) in block.sum_txs.iter().enumerate() {
+                let location = TxLocation {
+                    height: block.height,
+                    position,
+                };
+                // coinbases of before bip30 may repeat the txid of an earlier one, only they can
+                if sum_tx.is_coinbase {
+                    let replaced = match coinbases.get(&sum_tx.txid) {
+                        Some(replaced) => Some(*replaced),
+                        None => tx_tree
+                            .get(sum_tx.txid.as_byte_array())
+                            .map_err(|e| IndexerError::SledError(e.to_string()))?
+                            .map(|location| TxLocation::try_from(location.as_ref()))
+                            .transpose()
+                            .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))?,
+                    };
+                    undo.replaced_txs
+                        .extend(replaced.map(|replaced| (sum_tx.txid, replaced)));
+                    coinbases.insert(sum_tx.txid, location);
+                }
+                tx_batch.insert(sum_tx.txid.as_byte_array(), location.to_bytes());
+                undo.txids.push(sum_tx.txid);
+                // a script always maps to the same address, so that rollbacks leave these rows be,
+                // op_return outputs share a single address and are never queried by their script
+                for utxo in sum_tx.outs.iter().filter(|utxo| utxo.address != OP_RETURN) {
+                    if let Some(script_hash) = &utxo.script_hash {
+                        script_hash_batch.insert(&script_hash.0, utxo.address.as_bytes());
+                    }
+                }
+                self.process_outputs(
+                    sum_tx,
+                    position,
+                    block,
+                    &mut staged,
+                    &mut address_batch,
+                    &mut history_batch,
+                    &mut script_history_batch,
+                    &mut activity,
+                    &mut undo,
+                    &mut history_rows,
+                );
+                if !sum_tx.is_coinbase {
+                    self.process_inputs(
+                        sum_tx,
+                        position,
+                        block,
+                        &cache_tree,
+                        &mut staged,
+                        &mut address_batch,
+                        &mut history_batch,
+                        &mut script_history_batch,
+                        &mut activity,
+                        &mut undo,
+                        &mut history_rows,
+                    )?;
+                }
+            }
+            undo.address_stats =
+                activity.apply(block.height, &mut stats, &mut balances, |address| {
+                    Self::read_stats(&stats_tree, address)
+                })?;
+            undo_records.push(undo.to_bytes());
+        }
+        let mut stats_batch = sled::Batch::default();
+        for (address, stats) in &stats {
+            stats_batch.insert(address.as_bytes(), stats.to_bytes());
+        }
+        let block_hashes: Vec<BlockHash> = blocks
+            .iter()
+            .map(|block| block.header.block_hash())
+            .collect();
+        // header records of the blocks below the first one, as many as its median time past is taken over
+        let first_height = blocks[0].height;
+        let mut headers = (first_height.saturating_sub(MEDIAN_TIME_SPAN - 1)..first_height)
+            .map(|height| Self::read_header(&header_tree, height))
+            .collect::<Result<Vec<_>, _>>()?;
+        let below = headers.len();
+        for block in blocks {
+            let record = HeaderRecord::new(block.header, block.height, &headers);
+            headers.push(Some(record));
+        }
+        let header_records: Vec<Vec<u8>> = headers[below..]
+            .iter()
+            .flatten()
+            .map(HeaderRecord::to_bytes)
+            .collect();
+        // a db without a flush height would take its unflushed blocks as flushed when reopened
+        let flush = staged.needs_flush(last_block.height)
+            || meta_tree
+                .get(UTXO_FLUSH_HEIGHT_KEY)
+                .map_err(|e| IndexerError::SledError(e.to_string()))?
+                .is_none();
+        let cache_batch = flush.then(|| Self::cache_batch(&staged));
+        let flows = history_rows
+            .as_deref()
+            .map(events::decode_flows)
+            .transpose()?
+            .unwrap_or_default();
+
+        (
+            &address_tree,
+            &history_tree,
+            &script_history_tree,
+            &cache_tree,
+            &meta_tree,
+            &undo_tree,
+            &block_hash_tree,
+            &header_tree,
+            &block_height_tree,
+            &tx_tree,
+            &script_hash_tree,
+            &balance_tree,
+            &stats_tree,
+        )
+            .transaction(
+                |(
+                    address_tree,
+                    history_tree,
+                    script_history_tree,
+                    cache_tree,
+                    meta_tree,
+                    undo_tree,
+                    block_hash_tree,
+                    header_tree,
+                    block_height_tree,
+                    tx_tree,
+                    script_hash_tree,
+                    balance_tree,
+                    stats_tree,
+                )| {
+                    address_tree.apply_batch(&address_batch)?;
+                    history_tree.apply_batch(&history_batch)?;
+                    script_history_tree.apply_batch(&script_history_batch)?;
+                    tx_tree.apply_batch(&tx_batch)?;
+                    script_hash_tree.apply_batch(&script_hash_batch)?;
+                    Self::write_balances(&balances, balance_tree)?;
+                    stats_tree.apply_batch(&stats_batch)?;
+                    for (((block, block_hash), undo), record) in blocks
+                        .iter()
+                        .zip(&block_hashes)
+                        .zip(&undo_records)
+                        .zip(&header_records)
+                    {
+                        let height = block.height.to_be_bytes();
+                        undo_tree.insert(&height, undo.as_slice())?;
+                        block_hash_tree.insert(&height, block_hash.as_byte_array())?;
+                        header_tree.insert(&height, record.as_slice())?;
+                        block_height_tree.insert(block_hash.as_byte_array(), &height)?;
+                    }
+                    if let Some(cache_batch) = &cache_batch {
+                        Self::write_utxo_cache(
+                            &staged,
+                            last_block.height,
+                            cache_batch,
+                            cache_tree,
+                            undo_tree,
+                            meta_tree,
+                        )?;
                     }
+                    meta_tree.insert(LAST_HEIGHT_KEY, &last_block.height.to_be_bytes())?;
+                    Ok(())
+                },
+            )
+            .map_err(|e: TransactionError<IndexerError>| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => IndexerError::SledError(e.to_string()),
+            })?;
+        let changes = staged.into_changes();
+        utxo_cache.apply(changes);
+        if flush {
+            utxo_cache.flushed(last_block.height);
+        }
+        self.events.publish(
+            last_block.height,
+            block_hashes.last().copied(),
+            false,
+            flows,
+            &balances,
+        );
+        Ok(())
+    }
+
+    fn get_block_hash(&self, height: u64) -> Result<Option<BlockHash>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let block_hash_tree = Self::open_tree(&db, BLOCK_HASH_CF)?;
+        block_hash_tree
+            .get(height.to_be_bytes())
+            .map_err(|e| IndexerError::SledError(e.to_string()))?
+            .map(|hash| {
+                BlockHash::from_slice(&hash)
+                    .map_err(|e| IndexerError::ParseError(format!("Invalid block hash : {}", e)))
+            })
+            .transpose()
+    }
+
+    fn get_header(&self, height: u64) -> Result<Option<HeaderRecord>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        Self::read_header(&Self::open_tree(&db, HEADER_CF)?, height)
+    }
+
+    fn get_block_height(&self, block_hash: &BlockHash) -> Result<Option<u64>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let block_height_tree = Self::open_tree(&db, BLOCK_HEIGHT_CF)?;
+        block_height_tree
+            .get(block_hash.as_byte_array())
+            .map_err(|e| IndexerError::SledError(e.to_string()))?
+            .map(|height| decode_height(&height))
+            .transpose()
+            .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))
+    }
+
+    fn get_tx_location(&self, txid: &Txid) -> Result<Option<TxLocation>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let tx_tree = Self::open_tree(&db, TX_CF)?;
+        tx_tree
+            .get(txid.as_byte_array())
+            .map_err(|e| IndexerError::SledError(e.to_string()))?
+            .map(|location| TxLocation::try_from(location.as_ref()))
+            .transpose()
+            .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))
+    }
+
+    fn rollback(&mut self, height: u64) -> Result<(), IndexerError> {
+        let last_height = self.get_last_height();
+        let db_arc = self.db.clone();
+        let db = db_arc.write().unwrap();
+        let address_tree = Self::open_tree(&db, ADDRESS_CF)?;
+        let history_tree = Self::open_tree(&db, HISTORY_CF)?;
+        let script_history_tree = Self::open_tree(&db, SCRIPT_HISTORY_CF)?;
+        let cache_tree = Self::open_tree(&db, CACHE_CF)?;
+        let meta_tree = Self::open_tree(&db, META_CF)?;
+        let undo_tree = Self::open_tree(&db, UNDO_CF)?;
+        let block_hash_tree = Self::open_tree(&db, BLOCK_HASH_CF)?;
+        let header_tree = Self::open_tree(&db, HEADER_CF)?;
+        let block_height_tree = Self::open_tree(&db, BLOCK_HEIGHT_CF)?;
+        let tx_tree = Self::open_tree(&db, TX_CF)?;
+        let balance_tree = Self::open_tree(&db, ADDRESS_BALANCE_CF)?;
+        let stats_tree = Self::open_tree(&db, ADDRESS_STATS_CF)?;
+        // outputs of the rolled back blocks may still be in the utxo cache, undo records apply to the db
+        let mut utxo_cache = self.utxo_cache.lock().unwrap();
+        let staged = utxo_cache.stage();
+        let cache_batch = Self::cache_batch(&staged);
+
+        let balances = (
+            &address_tree,
+            &history_tree,
+            &script_history_tree,
+            &cache_tree,
+            &meta_tree,
+            &undo_tree,
+            &block_hash_tree,
+            &header_tree,
+            &block_height_tree,
+            &tx_tree,
+            &balance_tree,
+            &stats_tree,
+        )
+            .transaction(
+                |(
+                    address_tree,
+                    history_tree,
+                    script_history_tree,
+                    cache_tree,
+                    meta_tree,
+                    undo_tree,
+                    block_hash_tree,
+                    header_tree,
+                    block_height_tree,
+                    tx_tree,
+                    balance_tree,
+                    stats_tree,
+                )| {
+                    let mut balances = BalanceDeltas::default();
+                    Self::write_utxo_cache(
+                        &staged,
+                        height,
+                        &cache_batch,
+                        cache_tree,
+                        undo_tree,
+                        meta_tree,
+                    )?;
+                    for undo_height in ((height + 1)..=last_height).rev() {
+                        let undo_bytes = undo_tree.get(undo_height.to_be_bytes())?.ok_or(
+                            ConflictableTransactionError::Abort(IndexerError::RollbackError(
+                                format!("Missing undo record @ {}", undo_height),
+                            )),
+                        )?;
+                        let undo = UndoRecord::try_from(undo_bytes.as_ref()).map_err(|e| {
+                            ConflictableTransactionError::Abort(IndexerError::ParseError(format!(
+                                "{:?}",
+                                e
+                            )))
+                        })?;
+                        Self::undo_address_rows(
+                            &undo,
+                            address_tree,
+                            history_tree,
+                            script_history_tree,
+                            stats_tree,
+                            &mut balances,
+                        )?;
+                        for txid in &undo.txids {
+                            tx_tree.remove(txid.as_byte_array())?;
+                        }
+                        for (txid, location) in &undo.replaced_txs {
+                            tx_tree.insert(txid.as_byte_array(), location.to_bytes())?;
+                        }
+                        // outputs both created and spent within the block must end up deleted
+                        for (cache_key, utxo_bytes) in &undo.spent_utxos {
+                            cache_tree.insert(cache_key.as_slice(), utxo_bytes.as_slice())?;
+                        }
+                        for cache_key in &undo.cache_keys {
+                            cache_tree.remove(cache_key.as_slice())?;
+                        }
+                        undo_tree.remove(&undo_height.to_be_bytes())?;
+                        Self::delete_block_rows(
+                            undo_height,
+                            block_hash_tree,
+                            header_tree,
+                            block_height_tree,
+                        )?;
+                    }
+                    Self::write_balances(&balances, balance_tree)?;
+                    meta_tree.insert(LAST_HEIGHT_KEY, &height.to_be_bytes())?;
+                    Ok(balances)
+                },
+            )
+            .map_err(|e: TransactionError<IndexerError>| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => IndexerError::SledError(e.to_string()),
+            })?;
+        utxo_cache.flushed(height);
+        let hash = block_hash_tree
+            .get(height.to_be_bytes())
+            .map_err(|e| IndexerError::SledError(e.to_string()))?
+            .map(|hash| BlockHash::from_slice(&hash))
+            .transpose()
+            .map_err(|e| IndexerError::ParseError(format!("Invalid block hash : {}", e)))?;
+        self.events
+            .publish(height, hash, true, Vec::new(), &balances);
+        Ok(())
+    }
+
+    fn get_history(&self, address: &str) -> Result<Vec<(AddressFlow, u64)>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let address_tree = Self::open_tree(&db, ADDRESS_CF)?;
+        let prefix = address_prefix(address);
+        let mut history = Vec::new();
+        for item in address_tree.scan_prefix(&prefix) {
+            let (key, value) = item.map_err(|e| IndexerError::SledError(e.to_string()))?;
+            let mut flow = AddressFlow::try_from(key.as_ref())
+                .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?;
+            let flow_value = decode_flow_value(&flow.flow, &value)
+                .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?;
+            flow.spent_by = flow_value.spent_by;
+            flow.height = flow_value.height;
+            flow.time = flow_value.time;
+            flow.position = flow_value.position;
+            history.push((flow, flow_value.value));
+        }
+        Ok(history)
+    }
+
+    fn get_flow(
+        &self,
+        address: &str,
+        flow: &Flow,
+        outpoint: &IndexedTxid,
+    ) -> Result<Option<FlowValue>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let address_tree = Self::open_tree(&db, ADDRESS_CF)?;
+        let address_key = address_key(address, flow, &outpoint.tx_id, outpoint.index);
+        address_tree
+            .get(address_key)
+            .map_err(|e| IndexerError::SledError(e.to_string()))?
+            .map(|value| decode_flow_value(flow, &value))
+            .transpose()
+            .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))
+    }
+
+    fn get_history_filtered(
+        &self,
+        address: &str,
+        filter: &HistoryFilter,
+    ) -> Result<Vec<(AddressFlow, u64)>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let history_tree = Self::open_tree(&db, HISTORY_CF)?;
+        let prefix = address_prefix(address);
+        let start = history_bound(&prefix, filter.start_height.unwrap_or(0));
+        let mut history = Vec::new();
+        for item in history_tree.range(start..) {
+            let (key, value) = item.map_err(|e| IndexerError::SledError(e.to_string()))?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            let (flow, value) = decode_history_row(&key, &value)
+                .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?;
+            if filter
+                .end_height
+                .is_some_and(|end_height| flow.height.is_some_and(|height| height > end_height))
+            {
+                break;
+            }
+            if filter.matches(&flow) {
+                history.push((flow, value));
+            }
+        }
+        Ok(history)
+    }
+
+    fn get_history_page(
+        &self,
+        address: &str,
+        before: Option<(u64, usize)>,
+        tx_count: usize,
+    ) -> Result<Vec<(AddressFlow, u64)>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let history_tree = Self::open_tree(&db, HISTORY_CF)?;
+        let prefix = address_prefix(address);
+        // no row sits at the bound itself, it is shorter than the keys under it
+        let end = match before {
+            Some((height, position)) => history_position_bound(&prefix, height, position),
+            None => history_bound(&prefix, u64::MAX),
+        };
+        let mut txids = HashSet::new();
+        let mut history = Vec::new();
+        for item in history_tree.range(..end).rev() {
+            let (key, value) = item.map_err(|e| IndexerError::SledError(e.to_string()))?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            let (flow, value) = decode_history_row(&key, &value)
+                .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?;
+            if let Some(txid) = flow.txid() {
+                if !txids.contains(&txid) && txids.len() == tx_count {
+                    break;
                 }
-                address_tree.apply_batch(&address_batch).unwrap();
-                meta_tree.insert(LAST_HEIGHT_KEY, height.to_string().as_bytes())?;
+                txids.insert(txid);
+            }
+            history.push((flow, value));
+        }
+        Ok(history)
+    }
+
+    fn get_balance(&self, address: &str) -> Result<u64, IndexerError> {
+        self.get_balance_at_event(address)
+            .map(|(balance, _)| balance)
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Arc<IndexEvent>> {
+        self.events.subscribe()
+    }
+
+    fn get_balance_at_event(&self, address: &str) -> Result<(u64, u64), IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let balance_tree = Self::open_tree(&db, ADDRESS_BALANCE_CF)?;
+        let balance = match balance_tree
+            .get(address.as_bytes())
+            .map_err(|e| IndexerError::SledError(e.to_string()))?
+        {
+            Some(balance) => {
+                decode_value(&balance).map_err(|e| IndexerError::CodecError(format!("{:?}", e)))?
+            }
+            None => 0,
+        };
+        Ok((balance, self.events.sequence()))
+    }
+
+    fn get_address_stats(&self, address: &str) -> Result<Option<AddressStats>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        Self::read_stats(&Self::open_tree(&db, ADDRESS_STATS_CF)?, address)
+    }
+
+    fn get_utxo(&self, outpoint: &IndexedTxid) -> Result<Option<Utxo>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let cache_key = outpoint.to_bytes();
+        let cached = {
+            let utxo_cache = self.utxo_cache.lock().unwrap();
+            if utxo_cache.is_spent(&cache_key) {
+                return Ok(None);
+            }
+            utxo_cache.get(&cache_key).cloned()
+        };
+        let utxo_bytes = match cached {
+            Some(utxo_bytes) => Some(utxo_bytes),
+            None => Self::open_tree(&db, CACHE_CF)?
+                .get(&cache_key)
+                .map_err(|e| IndexerError::SledError(e.to_string()))?
+                .map(|utxo_bytes| utxo_bytes.to_vec()),
+        };
+        utxo_bytes
+            .map(|utxo_bytes| Utxo::try_from(utxo_bytes.as_slice()))
+            .transpose()
+            .map_err(|e| IndexerError::CodecError(format!("{:?}", e)))
+    }
+
+    fn get_script_address(&self, script_hash: &ScriptHash) -> Result<Option<String>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let script_hash_tree = Self::open_tree(&db, SCRIPT_HASH_CF)?;
+        script_hash_tree
+            .get(script_hash.0)
+            .map_err(|e| IndexerError::SledError(e.to_string()))?
+            .map(|address| String::from_utf8(address.to_vec()))
+            .transpose()
+            .map_err(|e| IndexerError::ParseError(format!("Invalid address : {}", e)))
+    }
+
+    fn get_script_history(
+        &self,
+        script_hash: &ScriptHash,
+    ) -> Result<Vec<(AddressFlow, u64)>, IndexerError> {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        let sled_error = |e: sled::Error| IndexerError::SledError(e.to_string());
+        let meta_tree = Self::open_tree(&db, META_CF)?;
+        if let Some(height) = meta_tree
+            .get(SCRIPT_HISTORY_HEIGHT_KEY)
+            .map_err(sled_error)?
+        {
+            let height =
+                decode_height(&height).map_err(|e| IndexerError::CodecError(format!("{:?}", e)))?;
+            return Err(IndexerError::MissingScriptHistory { height });
+        }
+        let script_hash_tree = Self::open_tree(&db, SCRIPT_HASH_CF)?;
+        let Some(address) = script_hash_tree.get(script_hash.0).map_err(sled_error)? else {
+            return Ok(Vec::new());
+        };
+        let address = str::from_utf8(&address)
+            .map_err(|e| IndexerError::ParseError(format!("Invalid address : {}", e)))?;
+        let script_history_tree = Self::open_tree(&db, SCRIPT_HISTORY_CF)?;
+        let mut history = Vec::new();
+        for item in script_history_tree.scan_prefix(script_hash.0) {
+            let (key, value) = item.map_err(sled_error)?;
+            history.push(
+                decode_script_history_row(address, &key, &value)
+                    .map_err(|e| IndexerError::ParseError(format!("{:?}", e)))?,
+            );
+        }
+        Ok(history)
+    }
+
+    fn flush(&self) -> Result<(), IndexerError> {
+        let last_height = self.get_last_height();
+        let db_arc = self.db.clone();
+        let db = db_arc.write().unwrap();
+        let cache_tree = Self::open_tree(&db, CACHE_CF)?;
+        let meta_tree = Self::open_tree(&db, META_CF)?;
+        let undo_tree = Self::open_tree(&db, UNDO_CF)?;
+        let mut utxo_cache = self.utxo_cache.lock().unwrap();
+        let staged = utxo_cache.stage();
+        let cache_batch = Self::cache_batch(&staged);
+        (&cache_tree, &undo_tree, &meta_tree)
+            .transaction(|(cache_tree, undo_tree, meta_tree)| {
+                Self::write_utxo_cache(
+                    &staged,
+                    last_height,
+                    &cache_batch,
+                    cache_tree,
+                    undo_tree,
+                    meta_tree,
+                )?;
                 Ok(())
             })
             .map_err(|e: TransactionError| IndexerError::SledError(e.to_string()))?;
+        utxo_cache.flushed(last_height);
+        db.flush()
+            .map_err(|e| IndexerError::SledError(e.to_string()))?;
         Ok(())
     }
 
     fn get_last_height(&self) -> u64 {
-        use zerocopy::FromBytes;
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        Self::open_tree(&db, META_CF)
+            .unwrap()
+            .get(LAST_HEIGHT_KEY)
+            .unwrap()
+            .map_or(0, |height| decode_height(&height).unwrap())
+    }
 
-        return self
-            .db
-            .clone()
-            .read()
+    fn get_next_height(&self) -> u64 {
+        let db_arc = self.db.clone();
+        let db = db_arc.read().unwrap();
+        Self::open_tree(&db, META_CF)
             .unwrap()
             .get(LAST_HEIGHT_KEY)
             .unwrap()
-            .map_or(0, |height| u64::read_from(height.as_ref()).unwrap());
+            .map_or(0, |height| decode_height(&height).unwrap() + 1)
     }
 
-    fn new(num_cores: i32, db_path: &str) -> Result<Self, IndexerError> {
-        let instance: sled::Db =
-            sled::open(db_path).map_err(|e| IndexerError::SledError(e.to_string()))?;
+    fn new(
+        num_cores: i32,
+        db_path: &str,
+        network: Network,
+        mut utxo_cache: UtxoCache,
+    ) -> Result<Self, IndexerError> {
+        let instance = Self::open_db(db_path)?;
+        Self::check_metadata(&Self::open_tree(&instance, META_CF)?, db_path, network)?;
+        utxo_cache.flushed(Self::recover_unflushed(&instance)?);
         Ok(SledDbIndexer {
             db: Arc::new(RwLock::new(instance)),
+            utxo_cache: Arc::new(Mutex::new(utxo_cache)),
+            events: EventPublisher::default(),
         })
     }
 }
@@ -0,0 +1,148 @@
+use bitcoin::consensus::deserialize;
+use bitcoin::{Block, BlockHash};
+use futures::stream::{self, BoxStream, StreamExt};
+use std::io;
+use std::path::Path;
+use std::sync::RwLock;
+use tokio::task::JoinError;
+
+pub type Height = u64;
+
+#[derive(Debug)]
+pub enum SourceError {
+    TaskError(String),
+    MissingBlock(Height),
+    RpcError(String),
+    IoError(String),
+}
+
+impl From<JoinError> for SourceError {
+    fn from(error: JoinError) -> Self {
+        SourceError::TaskError(error.to_string())
+    }
+}
+
+impl From<io::Error> for SourceError {
+    fn from(error: io::Error) -> Self {
+        SourceError::IoError(error.to_string())
+    }
+}
+
+impl std::fmt::Display for SourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SourceError::TaskError(e) => write!(f, "Block task failed : {}", e),
+            SourceError::MissingBlock(height) => write!(f, "Missing block @ {}", height),
+            SourceError::RpcError(e) => write!(f, "Rpc failed : {}", e),
+            SourceError::IoError(e) => write!(f, "Reading blocks failed : {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceBlock {
+    pub height: Height,
+    pub hash: BlockHash,
+    pub block: Block,
+}
+
+impl SourceBlock {
+    pub fn new(height: Height, block: Block) -> Self {
+        SourceBlock {
+            height,
+            hash: block.block_hash(),
+            block,
+        }
+    }
+}
+
+// Where blocks come from, heights and hashes are those of the source's best chain
+pub trait BlockSource: Send + Sync {
+    // Height of the tip of the best chain
+    fn get_block_count(&self) -> Result<Height, SourceError>;
+
+    fn get_block_hash(&self, height: Height) -> Result<BlockHash, SourceError>;
+
+    // Block at the height of the best chain, to look up what the index only locates
+    fn get_block(&self, height: Height) -> Result<SourceBlock, SourceError>;
+
+    // Blocks from the start height up to and including the end height, in height order
+    fn fetch_blocks(
+        &self,
+        start_height: Height,
+        end_height: Height,
+    ) -> BoxStream<'_, Result<SourceBlock, SourceError>>;
+}
+
+// Blocks held in memory by height from genesis, to replay a chain or run fixtures without a node
+pub struct MemorySource {
+    blocks: RwLock<Vec<Block>>,
+}
+
+impl MemorySource {
+    pub fn new(blocks: Vec<Block>) -> Self {
+        MemorySource {
+            blocks: RwLock::new(blocks),
+        }
+    }
+
+    // One hex encoded block per line, as printed by `bitcoin-cli getblock <hash> 0`
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let blocks = std::fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let bytes = base16::decode(line.trim())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                deserialize(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            })
+            .collect::<io::Result<Vec<Block>>>()?;
+        Ok(MemorySource::new(blocks))
+    }
+
+    // A block not extending the tip replaces all blocks above its parent, just like a reorg
+    pub fn push(&self, block: Block) {
+        let mut blocks = self.blocks.write().unwrap();
+        if let Some(parent) = blocks
+            .iter()
+            .rposition(|parent| parent.block_hash() == block.header.prev_blockhash)
+        {
+            blocks.truncate(parent + 1);
+        }
+        blocks.push(block);
+    }
+}
+
+impl BlockSource for MemorySource {
+    fn get_block_count(&self) -> Result<Height, SourceError> {
+        Ok(self.blocks.read().unwrap().len().saturating_sub(1) as Height)
+    }
+
+    fn get_block_hash(&self, height: Height) -> Result<BlockHash, SourceError> {
+        self.blocks
+            .read()
+            .unwrap()
+            .get(height as usize)
+            .map(|block| block.block_hash())
+            .ok_or(SourceError::MissingBlock(height))
+    }
+
+    fn get_block(&self, height: Height) -> Result<SourceBlock, SourceError> {
+        self.blocks
+            .read()
+            .unwrap()
+            .get(height as usize)
+            .map(|block| SourceBlock::new(height, block.clone()))
+            .ok_or(SourceError::MissingBlock(height))
+    }
+
+    fn fetch_blocks(
+        &self,
+        start_height: Height,
+        end_height: Height,
+    ) -> BoxStream<'_, Result<SourceBlock, SourceError>> {
+        stream::iter(start_height..=end_height)
+            .map(move |height| self.get_block(height))
+            .boxed()
+    }
+}
@@ -0,0 +1,445 @@
+use crate::indexer::{Indexer, IndexerError};
+use crate::model::IndexedBlock;
+use crate::source::{BlockSource, Height, SourceBlock, SourceError};
+use crate::zmq::{self, BlockNotification};
+use crate::{log, process};
+use bitcoin::{Block, Network};
+use chrono::DateTime;
+use futures::stream::StreamExt;
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tokio::task;
+
+// How often the source is asked for new blocks once the index caught up with the tip
+const TIP_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Blocks this close to the tip are committed one by one, so that each is served as soon as it arrives
+const GROUP_TIP_DISTANCE: Height = 6;
+
+// Errors that stop syncing, retries are exhausted by then
+#[derive(Debug)]
+pub enum SyncError {
+    Source {
+        height: Height,
+        error: SourceError,
+    },
+    Indexer {
+        height: Height,
+        error: IndexerError,
+    },
+    // the start height leaves a gap above the index, or asks to roll back genesis
+    StartHeight {
+        start_height: Height,
+        next_height: Height,
+    },
+    // the db could not be opened or migrated
+    Db(IndexerError),
+    // the settings leave nothing to sync from or with
+    Config(String),
+    Io(io::Error),
+}
+
+impl From<io::Error> for SyncError {
+    fn from(error: io::Error) -> Self {
+        SyncError::Io(error)
+    }
+}
+
+impl std::fmt::Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncError::Source { height, error } => {
+                write!(f, "Fetching block @ {} failed : {}", height, error)
+            }
+            SyncError::Indexer { height, error } => {
+                write!(f, "Indexing block @ {} failed : {:?}", height, error)
+            }
+            SyncError::StartHeight {
+                start_height,
+                next_height,
+            } => write!(
+                f,
+                "Cannot start syncing @ {}, the index continues @ {}",
+                start_height, next_height
+            ),
+            SyncError::Db(error) => write!(f, "Opening the db failed : {:?}", error),
+            SyncError::Config(message) => write!(f, "{}", message),
+            SyncError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// How blocks are synced, whatever source they come from
+pub struct SyncSettings {
+    // blocks whose transactions are summed up at once
+    pub parallelism: usize,
+    pub network: Network,
+    pub zmq_url: Option<String>,
+    // height to stop syncing at, otherwise new blocks are followed at the tip
+    pub end_height: Option<Height>,
+    pub group_commit_blocks: usize,
+    // a group is committed once its rows add up to this many bytes, whatever the block count
+    pub group_commit_bytes: usize,
+    pub shutdown: watch::Receiver<bool>,
+}
+
+impl SyncSettings {
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    // Resolves once a shutdown was requested
+    pub fn shutdown_requested(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut shutdown = self.shutdown.clone();
+        async move {
+            let _ = shutdown.wait_for(|shutting_down| *shutting_down).await;
+        }
+    }
+}
+
+fn source_error(height: Height) -> impl FnOnce(SourceError) -> SyncError {
+    move |error| SyncError::Source { height, error }
+}
+
+fn indexer_error(height: Height) -> impl FnOnce(IndexerError) -> SyncError {
+    move |error| SyncError::Indexer { height, error }
+}
+
+// Source calls may sleep between retries, so they run on the blocking pool rather than stall the runtime
+async fn on_source<S, T, F>(source: &Arc<S>, height: Height, call: F) -> Result<T, SyncError>
+where
+    S: BlockSource + ?Sized + 'static,
+    T: Send + 'static,
+    F: FnOnce(&S) -> Result<T, SourceError> + Send + 'static,
+{
+    let source = source.clone();
+    task::spawn_blocking(move || call(&source))
+        .await
+        .map_err(SourceError::from)
+        .and_then(|result| result)
+        .map_err(source_error(height))
+}
+
+pub struct SyncStats {
+    pub start_time: Instant,
+    pub total_tx_count: u64,
+}
+
+impl SyncStats {
+    pub fn new() -> Self {
+        SyncStats {
+            start_time: Instant::now(),
+            total_tx_count: 0,
+        }
+    }
+}
+
+impl Default for SyncStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Height to sync from, a start height below the next one to index rolls the index back to just below it
+pub fn start_height<I: Indexer>(
+    indexer: &mut I,
+    start_height: Option<Height>,
+) -> Result<Height, SyncError> {
+    let next_height = indexer.get_next_height();
+    match start_height {
+        None => Ok(next_height),
+        Some(start_height)
+            if start_height > next_height || (start_height == 0 && next_height > 0) =>
+        {
+            Err(SyncError::StartHeight {
+                start_height,
+                next_height,
+            })
+        }
+        Some(start_height) => {
+            if start_height < next_height {
+                log!(
+                    "Rolling back to {} to start syncing from it",
+                    start_height - 1
+                );
+                indexer
+                    .rollback(start_height - 1)
+                    .map_err(indexer_error(start_height - 1))?;
+            }
+            Ok(start_height)
+        }
+    }
+}
+
+// Indexes blocks of the source up to the height, rolling back reorgs on the way, returns the next height to index
+pub async fn sync_blocks<I: Indexer, S: BlockSource + ?Sized + 'static>(
+    source: &Arc<S>,
+    indexer: &mut I,
+    settings: &SyncSettings,
+    mut from_height: Height,
+    to_height: Height,
+    stats: &mut SyncStats,
+) -> Result<Height, SyncError> {
+    while from_height <= to_height && !settings.is_shutting_down() {
+        // the range may end below the tip of the source, blocks are committed one by one only near the latter
+        let tip_height = on_source(source, from_height, |source| source.get_block_count()).await?;
+        log!(
+            "Initiating syncing from {} to {} with parallelism {}",
+            from_height,
+            to_height,
+            settings.parallelism
+        );
+        let reorg_height = index_blocks(
+            source.as_ref(),
+            indexer,
+            settings,
+            from_height,
+            to_height,
+            tip_height,
+            stats,
+        )
+        .await?;
+        if let Some(height) = reorg_height {
+            let fork_height = find_fork_height(source, indexer, height - 1).await?;
+            log!(
+                "Reorg detected @ {}, rolling back to fork @ {}",
+                height,
+                fork_height
+            );
+            indexer
+                .rollback(fork_height)
+                .map_err(indexer_error(fork_height))?;
+        }
+        from_height = indexer.get_next_height();
+    }
+    Ok(from_height)
+}
+
+// Syncs up to the end height, or keeps following the tip of the source when there is none
+pub async fn follow_tip<I: Indexer, S: BlockSource + ?Sized + 'static>(
+    source: &Arc<S>,
+    indexer: &mut I,
+    settings: &SyncSettings,
+    mut from_height: Height,
+    stats: &mut SyncStats,
+) -> Result<(), SyncError> {
+    let mut zmq_blocks = match &settings.zmq_url {
+        Some(zmq_url) => match zmq::subscribe_blocks(zmq_url).await {
+            Ok(receiver) => {
+                log!("Subscribed to block notifications at : {}", zmq_url);
+                Some(receiver)
+            }
+            Err(e) => {
+                log!(
+                    "Zmq at {} unavailable, polling for new blocks : {}",
+                    zmq_url,
+                    e
+                );
+                None
+            }
+        },
+        None => None,
+    };
+
+    let end_height = settings.end_height;
+    while !settings.is_shutting_down() {
+        let to_height = match end_height {
+            Some(end_height) => end_height,
+            None => on_source(source, from_height, |source| source.get_block_count()).await?,
+        };
+        if from_height <= to_height {
+            from_height =
+                sync_blocks(source, indexer, settings, from_height, to_height, stats).await?;
+            continue;
+        }
+        if end_height.is_some() {
+            break;
+        }
+        // a pushed block extending the indexed tip is indexed right away, anything else is fetched from the source
+        if let Some(block) = wait_for_block(&mut zmq_blocks, settings).await {
+            let last_height = indexer.get_last_height();
+            let last_hash = indexer
+                .get_block_hash(last_height)
+                .map_err(indexer_error(last_height))?;
+            if last_hash == Some(block.header.prev_blockhash) {
+                let header = block.header;
+                let sum_txs =
+                    process::process_txs(settings.parallelism, settings.network, block.txdata)
+                        .await;
+                indexer
+                    .update_balance(last_height + 1, &header, &sum_txs)
+                    .map_err(indexer_error(last_height + 1))?;
+                log!("Block @ {} : {}", last_height + 1, header.block_hash());
+                stats.total_tx_count += sum_txs.len() as u64;
+                from_height = last_height + 2;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Waits for the next block notification, falls back to polling interval when zmq is unavailable
+async fn wait_for_block(
+    zmq_blocks: &mut Option<mpsc::Receiver<BlockNotification>>,
+    settings: &SyncSettings,
+) -> Option<Block> {
+    let notification = async {
+        match zmq_blocks {
+            Some(receiver) => {
+                match tokio::time::timeout(TIP_POLL_INTERVAL, receiver.recv()).await {
+                    Ok(Some(BlockNotification::RawBlock(block))) => Some(block),
+                    Ok(Some(BlockNotification::HashBlock)) | Err(_) => None,
+                    Ok(None) => {
+                        log!("Zmq subscription closed, polling for new blocks");
+                        *zmq_blocks = None;
+                        None
+                    }
+                }
+            }
+            None => {
+                tokio::time::sleep(TIP_POLL_INTERVAL).await;
+                None
+            }
+        }
+    };
+    // a shutdown need not wait for the poll interval to end
+    tokio::select! {
+        block = notification => block,
+        _ = settings.shutdown_requested() => None,
+    }
+}
+
+// Indexes blocks in order, returns the height of the first block that does not extend the indexed chain
+async fn index_blocks<I: Indexer, S: BlockSource + ?Sized>(
+    source: &S,
+    indexer: &mut I,
+    settings: &SyncSettings,
+    from_height: Height,
+    to_height: Height,
+    tip_height: Height,
+    stats: &mut SyncStats,
+) -> Result<Option<Height>, SyncError> {
+    let (parallelism, network) = (settings.parallelism, settings.network);
+    let mut blocks = source
+        .fetch_blocks(from_height, to_height)
+        // on shutdown no more blocks are fetched, those being processed are still committed
+        .take_until(Box::pin(settings.shutdown_requested()))
+        .map(|result| async move {
+            match result {
+                Ok(SourceBlock {
+                    height,
+                    hash,
+                    block,
+                }) => {
+                    log_block(height, &hash, &block);
+                    let header = block.header;
+                    let sum_txs = process::process_txs(parallelism, network, block.txdata).await;
+                    Ok(IndexedBlock {
+                        height,
+                        header,
+                        sum_txs,
+                    })
+                }
+                Err(e) => Err(e),
+            }
+        })
+        .buffered(128);
+
+    // blocks arrive in height order, a failed one is the block expected next
+    let mut next_height = from_height;
+    // blocks of the group are not indexed yet, each must extend the one taken before it
+    let mut last_hash = match from_height.checked_sub(1) {
+        Some(height) => indexer
+            .get_block_hash(height)
+            .map_err(indexer_error(height))?,
+        None => None,
+    };
+    let mut group = Vec::new();
+    let mut group_size = 0;
+    while let Some(result) = blocks.next().await {
+        let block = match result {
+            Ok(block) => block,
+            Err(e) => {
+                commit_group(indexer, &mut group)?;
+                return Err(source_error(next_height)(e));
+            }
+        };
+        let height = block.height;
+        if last_hash.is_some_and(|hash| hash != block.header.prev_blockhash) {
+            commit_group(indexer, &mut group)?;
+            return Ok(Some(height));
+        }
+        last_hash = Some(block.header.block_hash());
+        next_height = height + 1;
+        if height % 1000 == 0 {
+            let total_time = stats.start_time.elapsed().as_secs();
+            let txs_per_sec = format!("{:.1}", stats.total_tx_count as f64 / total_time as f64);
+            log!("Indexing Speed: {} txs/sec", txs_per_sec);
+        }
+        stats.total_tx_count += block.sum_txs.len() as u64;
+        group_size += block.write_size();
+        group.push(block);
+        if group.len() >= settings.group_commit_blocks
+            || group_size >= settings.group_commit_bytes
+            || tip_height.saturating_sub(height) < GROUP_TIP_DISTANCE
+        {
+            commit_group(indexer, &mut group)?;
+            group_size = 0;
+        }
+    }
+    commit_group(indexer, &mut group)?;
+    Ok(None)
+}
+
+// Indexes the blocks of the group in a single commit
+fn commit_group<I: Indexer>(
+    indexer: &mut I,
+    group: &mut Vec<IndexedBlock>,
+) -> Result<(), SyncError> {
+    if let Some(last_block) = group.last() {
+        let height = last_block.height;
+        indexer
+            .update_blocks(group)
+            .map_err(indexer_error(height))?;
+        group.clear();
+    }
+    Ok(())
+}
+
+// print the block hash if height is divisible by 1000
+fn log_block(height: Height, hash: &bitcoin::BlockHash, block: &Block) {
+    if height % 1000 == 0 {
+        let datetime = DateTime::from_timestamp(block.header.time as i64, 0).unwrap();
+        let readable_date = datetime.format("%Y-%m-%d %H:%M:%S").to_string();
+        log!("Block @ {} : {} : {}", height, readable_date, hash);
+    }
+}
+
+// Walks back from the height until the indexed block hash matches the one of the source
+async fn find_fork_height<I: Indexer, S: BlockSource + ?Sized + 'static>(
+    source: &Arc<S>,
+    indexer: &I,
+    height: Height,
+) -> Result<Height, SyncError> {
+    let mut fork_height = height;
+    while fork_height > 0 {
+        let indexed_hash = indexer
+            .get_block_hash(fork_height)
+            .map_err(indexer_error(fork_height))?;
+        if indexed_hash.is_none() {
+            break;
+        }
+        let source_hash = on_source(source, fork_height, move |source| {
+            source.get_block_hash(fork_height)
+        })
+        .await?;
+        if indexed_hash == Some(source_hash) {
+            break;
+        }
+        fork_height -= 1;
+    }
+    Ok(fork_height)
+}
@@ -0,0 +1,252 @@
+use crate::events::IndexEvent;
+use crate::indexer::{blocking_query, Indexer, IndexerError};
+use crate::log;
+use crate::model::AddressFlow;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::Response;
+use axum::routing::get;
+use axum::Router;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use tokio::sync::broadcast::error::RecvError;
+
+// Clients subscribe with `{"action": "subscribe", "addresses": [..], "blocks": true}` and are
+// pushed the new tip and the flows and balance delta of their addresses after each commit
+pub fn router<I>(indexer: I) -> Router
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/ws", get(upgrade::<I>))
+        .with_state(indexer)
+}
+
+// Json of a flow as served over http and pushed to websockets
+pub fn flow_value(flow: &AddressFlow, value: u64) -> Value {
+    json!({
+        "flow": flow.flow.to_string(),
+        "txid": flow.tx_id.to_string(),
+        "index": flow.utxo_index,
+        "value": value,
+        "height": flow.height,
+        "time": flow.time,
+        "spent_by": flow.spent_by.as_ref().map(|spend| json!({
+            "txid": spend.tx_id.to_string(),
+            "vin": spend.vin,
+            "height": spend.height,
+        })),
+    })
+}
+
+async fn upgrade<I>(State(indexer): State<I>, ws: WebSocketUpgrade) -> Response
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    ws.on_upgrade(move |socket| async move {
+        if let Err(e) = handle_socket(indexer, socket).await {
+            log!("Websocket failed : {}", e);
+        }
+    })
+}
+
+// Subscriptions of a socket with what it was last told
+#[derive(Default)]
+struct Subscriptions {
+    blocks: bool,
+    // balance of each subscribed address as last sent, with the sequence of the last event it
+    // includes so that events read along with it are not counted twice
+    balances: HashMap<String, (u64, u64)>,
+}
+
+async fn handle_socket<I>(indexer: I, mut socket: WebSocket) -> Result<(), axum::Error>
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    let mut events = indexer.subscribe();
+    let mut subscriptions = Subscriptions::default();
+    loop {
+        tokio::select! {
+            message = socket.recv() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e),
+                };
+                let indexer = indexer.clone();
+                let reply;
+                (reply, subscriptions) = blocking_query(move || {
+                    let reply = handle_request(&indexer, &mut subscriptions, &text);
+                    (reply, subscriptions)
+                })
+                .await
+                .map_err(axum::Error::new)?;
+                socket.send(Message::Text(reply.to_string())).await?;
+            }
+            event = events.recv() => {
+                let notifications = match event {
+                    Ok(event) => subscriptions.notifications(&event),
+                    // events the socket fell behind by are lost, balances are read again instead
+                    Err(RecvError::Lagged(missed)) => {
+                        log!("Websocket missed {} index events", missed);
+                        let indexer = indexer.clone();
+                        let notifications;
+                        (notifications, subscriptions) = blocking_query(move || {
+                            let notifications = subscriptions.resync(&indexer);
+                            (notifications, subscriptions)
+                        })
+                        .await
+                        .map_err(axum::Error::new)?;
+                        match notifications {
+                            Ok(notifications) => notifications,
+                            Err(e) => {
+                                log!("Rereading websocket balances failed : {:?}", e);
+                                Vec::new()
+                            }
+                        }
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                for notification in notifications {
+                    socket.send(Message::Text(notification.to_string())).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn handle_request<I: Indexer>(indexer: &I, subscriptions: &mut Subscriptions, text: &str) -> Value {
+    let result = serde_json::from_str::<Value>(text)
+        .map_err(|e| e.to_string())
+        .and_then(|request| subscriptions.update(indexer, &request));
+    match result {
+        Ok(reply) => reply,
+        Err(error) => json!({ "type": "error", "error": error }),
+    }
+}
+
+fn address_notification(
+    address: &str,
+    height: u64,
+    balance: u64,
+    delta: i64,
+    flows: Vec<Value>,
+) -> Value {
+    json!({
+        "type": "address",
+        "address": address,
+        "height": height,
+        "balance": balance,
+        "delta": delta,
+        "flows": flows,
+    })
+}
+
+impl Subscriptions {
+    fn update<I: Indexer>(&mut self, indexer: &I, request: &Value) -> Result<Value, String> {
+        let addresses = match request.get("addresses") {
+            None => Vec::new(),
+            Some(addresses) => addresses
+                .as_array()
+                .and_then(|addresses| addresses.iter().map(Value::as_str).collect())
+                .ok_or("Addresses must be an array of strings")?,
+        };
+        let blocks = request.get("blocks").and_then(Value::as_bool);
+        match request.get("action").and_then(Value::as_str) {
+            Some("subscribe") => {
+                let mut balances = Map::new();
+                for address in addresses {
+                    let (balance, sequence) = indexer
+                        .get_balance_at_event(address)
+                        .map_err(|e| format!("{:?}", e))?;
+                    self.balances
+                        .insert(address.to_string(), (balance, sequence));
+                    balances.insert(address.to_string(), json!(balance));
+                }
+                self.blocks |= blocks.unwrap_or(false);
+                Ok(json!({
+                    "type": "subscribed",
+                    "height": indexer.get_last_height(),
+                    "blocks": self.blocks,
+                    "balances": balances,
+                }))
+            }
+            Some("unsubscribe") => {
+                for address in &addresses {
+                    self.balances.remove(*address);
+                }
+                self.blocks &= !blocks.unwrap_or(false);
+                Ok(json!({
+                    "type": "unsubscribed",
+                    "blocks": self.blocks,
+                    "addresses": addresses,
+                }))
+            }
+            _ => Err("Action must be subscribe or unsubscribe".to_string()),
+        }
+    }
+
+    // The tip and the flows and balance delta of the subscribed addresses the event touched
+    fn notifications(&mut self, event: &IndexEvent) -> Vec<Value> {
+        let mut notifications = Vec::new();
+        if self.blocks {
+            notifications.push(json!({
+                "type": if event.rollback { "rollback" } else { "tip" },
+                "height": event.height,
+                "hash": event.hash.map(|hash| hash.to_string()),
+            }));
+        }
+        let mut flows: HashMap<&str, Vec<Value>> = HashMap::new();
+        for (flow, value) in &event.flows {
+            if self.balances.contains_key(&flow.address) {
+                flows
+                    .entry(&flow.address)
+                    .or_default()
+                    .push(flow_value(flow, *value));
+            }
+        }
+        for (address, (balance, sequence)) in self.balances.iter_mut() {
+            if *sequence >= event.sequence {
+                continue;
+            }
+            *sequence = event.sequence;
+            let delta = event.deltas.get(address).copied().unwrap_or(0);
+            let flows = flows.remove(address.as_str()).unwrap_or_default();
+            if flows.is_empty() && delta == 0 {
+                continue;
+            }
+            *balance = balance.saturating_add_signed(delta);
+            notifications.push(address_notification(
+                address,
+                event.height,
+                *balance,
+                delta,
+                flows,
+            ));
+        }
+        notifications
+    }
+
+    // Balances read again after missing events, with the change since those last sent
+    fn resync<I: Indexer>(&mut self, indexer: &I) -> Result<Vec<Value>, IndexerError> {
+        let height = indexer.get_last_height();
+        let mut notifications = Vec::new();
+        for (address, (balance, sequence)) in self.balances.iter_mut() {
+            let (new_balance, new_sequence) = indexer.get_balance_at_event(address)?;
+            *sequence = new_sequence;
+            if new_balance != *balance {
+                notifications.push(address_notification(
+                    address,
+                    height,
+                    new_balance,
+                    new_balance as i64 - *balance as i64,
+                    Vec::new(),
+                ));
+                *balance = new_balance;
+            }
+        }
+        Ok(notifications)
+    }
+}
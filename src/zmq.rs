@@ -0,0 +1,66 @@
+use crate::log;
+use bitcoin::Block;
+use tokio::sync::mpsc;
+use zeromq::{Socket, SocketRecv, SubSocket, ZmqMessage};
+
+const HASH_BLOCK_TOPIC: &str = "hashblock";
+const RAW_BLOCK_TOPIC: &str = "rawblock";
+
+pub enum BlockNotification {
+    // only announces a new block, which is then fetched by rpc
+    HashBlock,
+    RawBlock(Block),
+}
+
+// Subscribes to bitcoin-core's zmqpubhashblock/zmqpubrawblock topics, the receiver closes when the connection fails
+pub async fn subscribe_blocks(
+    zmq_url: &str,
+) -> Result<mpsc::Receiver<BlockNotification>, zeromq::ZmqError> {
+    let mut socket = SubSocket::new();
+    socket.connect(zmq_url).await?;
+    socket.subscribe(HASH_BLOCK_TOPIC).await?;
+    socket.subscribe(RAW_BLOCK_TOPIC).await?;
+
+    let (sender, receiver) = mpsc::channel(16);
+    let zmq_url = zmq_url.to_string();
+    tokio::spawn(async move {
+        // a node publishing raw blocks announces each block by hash as well, which would fetch it again
+        let mut raw_blocks_published = false;
+        loop {
+            let message = match socket.recv().await {
+                Ok(message) => message,
+                Err(e) => {
+                    log!("Zmq subscription to {} failed : {}", zmq_url, e);
+                    break;
+                }
+            };
+            match parse_notification(&message) {
+                Ok(Some(BlockNotification::HashBlock)) if raw_blocks_published => {}
+                Ok(Some(notification)) => {
+                    raw_blocks_published |= matches!(notification, BlockNotification::RawBlock(_));
+                    if sender.send(notification).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => log!("Invalid zmq message : {}", e),
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+// Messages are multipart [topic, body, sequence], raw blocks are consensus encoded
+pub fn parse_notification(message: &ZmqMessage) -> Result<Option<BlockNotification>, String> {
+    let topic = message.get(0).ok_or("Missing topic")?;
+    let body = message.get(1).ok_or("Missing body")?;
+    match topic.as_ref() {
+        topic if topic == HASH_BLOCK_TOPIC.as_bytes() => Ok(Some(BlockNotification::HashBlock)),
+        topic if topic == RAW_BLOCK_TOPIC.as_bytes() => {
+            let block: Block =
+                bitcoin::consensus::deserialize(body.as_ref()).map_err(|e| e.to_string())?;
+            Ok(Some(BlockNotification::RawBlock(block)))
+        }
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,84 @@
+use bitcoin::constants::genesis_block;
+use bitcoin::{Block, CompactTarget, Network};
+use common::{block, coinbase, script};
+use index_btc::blk::BlkReader;
+use index_btc::source::BlockSource;
+use tempfile::TempDir;
+
+mod common;
+
+// Records of magic, size and block as bitcoin-core appends them, obfuscated with the xor key of the
+// dir, followed by the zeroed preallocated tail which is not
+fn write_blk_file(dir: &TempDir, name: &str, xor_key: [u8; 8], blocks: &[&Block]) {
+    std::fs::write(dir.path().join("xor.dat"), xor_key).unwrap();
+    let mut bytes = Vec::new();
+    for block in blocks {
+        let block_bytes = bitcoin::consensus::serialize(*block);
+        bytes.extend_from_slice(&Network::Regtest.magic().to_bytes());
+        bytes.extend_from_slice(&(block_bytes.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&block_bytes);
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte ^= xor_key[i % 8];
+    }
+    bytes.extend_from_slice(&[0u8; 64]);
+    std::fs::write(dir.path().join(name), bytes).unwrap();
+}
+
+fn with_bits(mut block: Block, bits: u32) -> Block {
+    block.header.bits = CompactTarget::from_consensus(bits);
+    block
+}
+
+#[test]
+fn follows_the_branch_with_the_most_work() {
+    let dir = TempDir::new().unwrap();
+    let genesis = genesis_block(Network::Regtest);
+    // three blocks at the lowest difficulty against a single one with far more work
+    let long_1 = block(genesis.block_hash(), 1, 0, vec![coinbase(1, script(1), 50)]);
+    let long_2 = block(long_1.block_hash(), 2, 0, vec![coinbase(2, script(1), 50)]);
+    let long_3 = block(long_2.block_hash(), 3, 0, vec![coinbase(3, script(1), 50)]);
+    let heavy_1 = with_bits(
+        block(genesis.block_hash(), 1, 1, vec![coinbase(1, script(2), 50)]),
+        0x1d00ffff,
+    );
+    assert!(
+        heavy_1.header.work() > long_1.header.work() + long_2.header.work() + long_3.header.work()
+    );
+    // blocks are stored in the order they were received, not by height
+    let xor_key = [1, 2, 3, 4, 5, 6, 7, 8];
+    write_blk_file(
+        &dir,
+        "blk00000.dat",
+        xor_key,
+        &[&genesis, &long_2, &heavy_1],
+    );
+    write_blk_file(&dir, "blk00001.dat", xor_key, &[&long_1, &long_3]);
+
+    let reader = BlkReader::open(dir.path().to_str().unwrap(), Network::Regtest).unwrap();
+    assert_eq!(reader.get_block_count().unwrap(), 1);
+    assert_eq!(reader.get_block_hash(0).unwrap(), genesis.block_hash());
+    assert_eq!(reader.get_block_hash(1).unwrap(), heavy_1.block_hash());
+    assert_eq!(reader.get_block(1).unwrap().block, heavy_1);
+    assert!(reader.get_block(2).is_err());
+}
+
+#[test]
+fn follows_the_longest_branch_of_equal_difficulty() {
+    let dir = TempDir::new().unwrap();
+    let genesis = genesis_block(Network::Regtest);
+    let long_1 = block(genesis.block_hash(), 1, 0, vec![coinbase(1, script(1), 50)]);
+    let long_2 = block(long_1.block_hash(), 2, 0, vec![coinbase(2, script(1), 50)]);
+    let stale_1 = block(genesis.block_hash(), 1, 1, vec![coinbase(1, script(2), 50)]);
+    write_blk_file(
+        &dir,
+        "blk00000.dat",
+        [0; 8],
+        &[&genesis, &stale_1, &long_2, &long_1],
+    );
+
+    let reader = BlkReader::open(dir.path().to_str().unwrap(), Network::Regtest).unwrap();
+    assert_eq!(reader.get_block_count().unwrap(), 2);
+    assert_eq!(reader.get_block_hash(1).unwrap(), long_1.block_hash());
+    assert_eq!(reader.get_block_hash(2).unwrap(), long_2.block_hash());
+}
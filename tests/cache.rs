@@ -0,0 +1,174 @@
+use bitcoin::hashes::Hash;
+use bitcoin::transaction::OutPoint;
+use bitcoin::Txid;
+use common::{address, coinbase, index_block, open_indexer, reopen_indexer, script, spend};
+use index_btc::cache::UtxoCache;
+use index_btc::codec;
+use index_btc::indexer::Indexer;
+use index_btc::model::{IndexedTxid, MAX_REORG_DEPTH};
+use index_btc::rocksdb::RocksDbIndexer;
+use index_btc::sleddb::SledDbIndexer;
+
+mod common;
+
+fn key(seed: u8) -> Vec<u8> {
+    codec::outpoint_key(&Txid::from_byte_array([seed; 32]), 0)
+}
+
+#[test]
+fn outputs_spent_before_a_flush_never_reach_the_db() {
+    let mut cache = UtxoCache::new(1024 * 1024, 2000);
+    // created and spent within a commit
+    let mut staged = cache.stage();
+    staged.add(key(1), vec![1; 40]);
+    assert_eq!(staged.spend(&key(1)), Some(vec![1; 40]));
+    assert_eq!(staged.added().count(), 0);
+    assert_eq!(staged.spent().count(), 0);
+    let changes = staged.into_changes();
+    cache.apply(changes);
+    assert_eq!(cache.size(), 0);
+
+    // created by a commit and spent by a later one
+    let mut staged = cache.stage();
+    staged.add(key(2), vec![2; 40]);
+    let changes = staged.into_changes();
+    cache.apply(changes);
+    let mut staged = cache.stage();
+    assert_eq!(staged.spend(&key(2)), Some(vec![2; 40]));
+    assert_eq!(staged.added().count(), 0);
+    assert_eq!(staged.spent().count(), 0);
+    let changes = staged.into_changes();
+    cache.apply(changes);
+    assert!(cache.get(&key(2)).is_none() && !cache.is_spent(&key(2)));
+    assert_eq!(cache.size(), 0);
+
+    // flushed outputs are left to the db to delete
+    let mut staged = cache.stage();
+    assert_eq!(staged.spend(&key(3)), None);
+    assert_eq!(staged.spent().collect::<Vec<_>>(), vec![&key(3)]);
+    let changes = staged.into_changes();
+    cache.apply(changes);
+    assert!(cache.is_spent(&key(3)));
+}
+
+#[test]
+fn staged_changes_leave_the_cache_unchanged() {
+    let mut cache = UtxoCache::new(1024 * 1024, 2000);
+    let mut staged = cache.stage();
+    staged.add(key(1), vec![1; 40]);
+    let changes = staged.into_changes();
+    cache.apply(changes);
+    let size = cache.size();
+
+    let mut staged = cache.stage();
+    staged.add(key(2), vec![2; 40]);
+    staged.spend(&key(1));
+    staged.spend(&key(3));
+    drop(staged);
+    assert_eq!(cache.size(), size);
+    assert!(cache.get(&key(1)).is_some() && cache.get(&key(2)).is_none());
+    assert!(!cache.is_spent(&key(3)));
+}
+
+#[test]
+fn sizes_account_for_keys_and_values() {
+    let mut cache = UtxoCache::new(300, 2000);
+    let mut staged = cache.stage();
+    staged.add(key(1), vec![1; 100]);
+    assert!(!staged.needs_flush(1));
+    let changes = staged.into_changes();
+    cache.apply(changes);
+    let added_size = cache.size();
+    assert!(added_size > key(1).len() + 100);
+
+    // a spent flushed output costs its key
+    let mut staged = cache.stage();
+    staged.spend(&key(2));
+    let changes = staged.into_changes();
+    cache.apply(changes);
+    let spent_size = cache.size() - added_size;
+    assert!(spent_size > key(2).len() && spent_size < added_size);
+
+    let mut staged = cache.stage();
+    staged.add(key(3), vec![3; 100]);
+    assert!(staged.needs_flush(2));
+    staged.spend(&key(1));
+    staged.spend(&key(3));
+    assert!(!staged.needs_flush(2));
+    let changes = staged.into_changes();
+    cache.apply(changes);
+    assert_eq!(cache.size(), spent_size);
+
+    cache.flushed(2);
+    assert_eq!(cache.size(), 0);
+    assert_eq!(cache.flushed_height(), 2);
+}
+
+#[test]
+fn flushes_every_interval() {
+    let mut cache = UtxoCache::new(1024 * 1024, 10);
+    cache.flushed(5);
+    assert!(!cache.needs_flush(14));
+    assert!(cache.needs_flush(15));
+    assert!(UtxoCache::new(0, 10).needs_flush(1));
+}
+
+#[test]
+fn undo_records_expire_once_flushed_out_of_the_reorg_window() {
+    let mut cache = UtxoCache::new(1024 * 1024, 2000);
+    assert_eq!(cache.expired_undo_heights(MAX_REORG_DEPTH).count(), 0);
+    assert_eq!(
+        cache
+            .expired_undo_heights(MAX_REORG_DEPTH + 3)
+            .collect::<Vec<_>>(),
+        vec![1, 2, 3]
+    );
+    // blocks flushed before keep the undo records expired back then
+    cache.flushed(MAX_REORG_DEPTH + 3);
+    assert_eq!(
+        cache
+            .expired_undo_heights(MAX_REORG_DEPTH + 5)
+            .collect::<Vec<_>>(),
+        vec![4, 5]
+    );
+    assert_eq!(cache.expired_undo_heights(MAX_REORG_DEPTH + 3).count(), 0);
+}
+
+fn reopens_at_the_flush_height<I: Indexer>() {
+    let (mut indexer, dir) = open_indexer::<I>();
+    index_block(&mut indexer, vec![coinbase(0, script(1), 50)]);
+    let genesis_txid = coinbase(0, script(1), 50).compute_txid();
+    index_block(&mut indexer, vec![coinbase(1, script(2), 50)]);
+    indexer.flush().unwrap();
+    index_block(
+        &mut indexer,
+        vec![
+            coinbase(2, script(3), 50),
+            spend(OutPoint::new(genesis_txid, 0), vec![(script(4), 50)]),
+        ],
+    );
+    assert_eq!(indexer.get_last_height(), 2);
+    // the process dies before the next flush
+    drop(indexer);
+
+    let indexer = reopen_indexer::<I>(&dir);
+    assert_eq!(indexer.get_last_height(), 1);
+    assert!(indexer.get_block_hash(2).unwrap().is_none());
+    assert_eq!(indexer.get_balance(&address(&script(1))).unwrap(), 50);
+    assert_eq!(indexer.get_balance(&address(&script(4))).unwrap(), 0);
+    let genesis_output = IndexedTxid {
+        tx_id: genesis_txid,
+        index: 0,
+    };
+    assert!(indexer.get_utxo(&genesis_output).unwrap().is_some());
+}
+
+#[test]
+fn reopens_at_the_flush_height_rocks_db() {
+    reopens_at_the_flush_height::<RocksDbIndexer>();
+}
+
+#[test]
+fn reopens_at_the_flush_height_sled_db() {
+    reopens_at_the_flush_height::<SledDbIndexer>();
+}
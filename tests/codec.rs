@@ -0,0 +1,326 @@
+use bitcoin::block::{Header, Version};
+use bitcoin::hashes::Hash;
+use bitcoin::{BlockHash, CompactTarget, TxMerkleNode, Txid};
+use index_btc::codec::{self, legacy, Decoder};
+use index_btc::model::{
+    AddressFlow, AddressStats, Flow, HeaderRecord, IndexedTxid, ScriptHash, Spend, TxLocation,
+    UndoRecord, Utxo,
+};
+use std::str::FromStr;
+
+const ADDRESS: &str = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+
+fn txid(seed: u8) -> Txid {
+    Txid::from_byte_array([seed; 32])
+}
+
+fn utxo(script_hash: Option<ScriptHash>) -> Utxo {
+    Utxo {
+        index: 3,
+        address: ADDRESS.to_string(),
+        value: 5_000_000_000,
+        script_hash,
+    }
+}
+
+fn assert_utxo_eq(utxo: &Utxo, expected: &Utxo) {
+    assert_eq!(utxo.index, expected.index);
+    assert_eq!(utxo.address, expected.address);
+    assert_eq!(utxo.value, expected.value);
+    assert_eq!(utxo.script_hash, expected.script_hash);
+}
+
+#[test]
+fn varints_round_trip() {
+    for value in [
+        0,
+        1,
+        127,
+        128,
+        300,
+        16_383,
+        16_384,
+        u32::MAX as u64,
+        u64::MAX,
+    ] {
+        let mut bytes = Vec::new();
+        codec::write_varint(&mut bytes, value);
+        let mut decoder = Decoder::new(&bytes);
+        assert_eq!(decoder.read_varint().unwrap(), value);
+        decoder.finish().unwrap();
+    }
+}
+
+#[test]
+fn address_keys_round_trip() {
+    let key = codec::address_key(ADDRESS, &Flow::I, &txid(1), 300);
+    assert!(key.starts_with(&codec::address_prefix(ADDRESS)));
+    let address_flow = AddressFlow::try_from(key.as_slice()).unwrap();
+    assert_eq!(address_flow.address, ADDRESS);
+    assert!(matches!(address_flow.flow, Flow::I));
+    assert_eq!(address_flow.tx_id, txid(1));
+    assert_eq!(address_flow.utxo_index, 300);
+    assert_eq!(address_flow.to_bytes(), key);
+
+    let mut trailing = key.clone();
+    trailing.push(0);
+    assert!(AddressFlow::try_from(trailing.as_slice()).is_err());
+}
+
+#[test]
+fn flow_values_round_trip() {
+    let output = codec::decode_flow_value(
+        &Flow::O,
+        &codec::encode_output_value(7, 840_000, 1_700_000_000, 12),
+    )
+    .unwrap();
+    assert_eq!(output.value, 7);
+    assert_eq!(output.height, Some(840_000));
+    assert_eq!(output.time, Some(1_700_000_000));
+    assert_eq!(output.position, Some(12));
+    assert!(output.spent_by.is_none());
+
+    let spend = Spend {
+        tx_id: txid(2),
+        vin: 4,
+        height: 840_001,
+    };
+    let bytes = codec::encode_input_value(7, &spend, 1_700_000_600, 3);
+    let input = codec::decode_flow_value(&Flow::I, &bytes).unwrap();
+    assert_eq!(input.value, 7);
+    assert_eq!(input.height, Some(840_001));
+    assert_eq!(input.time, Some(1_700_000_600));
+    assert_eq!(input.position, Some(3));
+    let spent_by = input.spent_by.unwrap();
+    assert_eq!(
+        (spent_by.tx_id, spent_by.vin, spent_by.height),
+        (txid(2), 4, 840_001)
+    );
+
+    // rows written before spends or heights were recorded hold the value only
+    let value_only = codec::decode_flow_value(&Flow::I, &codec::encode_value(7)).unwrap();
+    assert_eq!(value_only.value, 7);
+    assert!(value_only.spent_by.is_none() && value_only.height.is_none());
+
+    // rows written before positions were recorded end after the time
+    let mut without_position = codec::encode_value(7).to_vec();
+    codec::write_varint(&mut without_position, 840_000);
+    codec::write_varint(&mut without_position, 1_700_000_000);
+    let output = codec::decode_flow_value(&Flow::O, &without_position).unwrap();
+    assert_eq!(output.time, Some(1_700_000_000));
+    assert!(output.position.is_none());
+}
+
+#[test]
+fn history_keys_order_flows_by_block_and_transaction() {
+    let prefix = codec::address_prefix(ADDRESS);
+    let keys = [
+        codec::history_key(&prefix, 9, 300, &Flow::I, &txid(1), 0),
+        codec::history_key(&prefix, 10, 2, &Flow::O, &txid(9), 0),
+        codec::history_key(&prefix, 10, 2, &Flow::I, &txid(1), 1),
+        codec::history_key(&prefix, 10, 256, &Flow::O, &txid(1), 0),
+        codec::history_key(&prefix, 256, 0, &Flow::O, &txid(1), 0),
+    ];
+    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(keys.iter().all(|key| key.starts_with(&prefix)));
+    assert!(codec::history_bound(&prefix, 10) > keys[0]);
+    assert!(codec::history_bound(&prefix, 10) < keys[1]);
+
+    // the history row of a flow row holds its value under a key derived from both
+    let address_key = codec::address_key(ADDRESS, &Flow::O, &txid(1), 300);
+    let value = codec::encode_output_value(7, 840_000, 1_700_000_000, 12);
+    let history_key = codec::flow_history_key(&address_key, &value).unwrap();
+    assert_eq!(
+        history_key,
+        codec::history_key(&prefix, 840_000, 12, &Flow::O, &txid(1), 300)
+    );
+    let (flow, value) = codec::decode_history_row(&history_key, &value).unwrap();
+    assert_eq!(flow.to_bytes(), address_key);
+    assert_eq!(value, 7);
+    assert_eq!(
+        (flow.height, flow.time, flow.position),
+        (Some(840_000), Some(1_700_000_000), Some(12))
+    );
+}
+
+#[test]
+fn cache_rows_round_trip() {
+    let indexed_txid = IndexedTxid {
+        tx_id: txid(3),
+        index: 129,
+    };
+    let key = indexed_txid.to_bytes();
+    assert_eq!(key, codec::outpoint_key(&txid(3), 129));
+    let decoded = IndexedTxid::try_from(key.as_slice()).unwrap();
+    assert_eq!((decoded.tx_id, decoded.index), (txid(3), 129));
+
+    for expected in [utxo(None), utxo(Some(ScriptHash([9; 32])))] {
+        let decoded = Utxo::try_from(expected.to_bytes().as_slice()).unwrap();
+        assert_utxo_eq(&decoded, &expected);
+    }
+}
+
+#[test]
+fn records_round_trip() {
+    for stats in [
+        AddressStats::default(),
+        AddressStats {
+            funded_count: 3,
+            spent_count: 1,
+            received: 150,
+            sent: 50,
+            tx_count: Some(3),
+            first_seen: Some(0),
+            last_seen: Some(840_000),
+        },
+    ] {
+        assert_eq!(
+            AddressStats::try_from(stats.to_bytes().as_slice()).unwrap(),
+            stats
+        );
+    }
+    // stats written before transactions were counted lack the count
+    let stats = AddressStats {
+        funded_count: 1,
+        tx_count: Some(1),
+        ..AddressStats::default()
+    };
+    let bytes = stats.to_bytes();
+    let decoded = AddressStats::try_from(&bytes[..bytes.len() - 1]).unwrap();
+    assert_eq!(decoded.funded_count, 1);
+    assert_eq!(decoded.tx_count, None);
+
+    let location = TxLocation {
+        height: 840_000,
+        position: 2_500,
+    };
+    assert_eq!(
+        TxLocation::try_from(location.to_bytes().as_slice()).unwrap(),
+        location
+    );
+
+    let header = Header {
+        version: Version::TWO,
+        prev_blockhash: BlockHash::all_zeros(),
+        merkle_root: TxMerkleNode::all_zeros(),
+        time: 1_600_000_000,
+        bits: CompactTarget::from_consensus(0x1d00ffff),
+        nonce: 42,
+    };
+    for record in [
+        HeaderRecord::new(header, 0, &[]),
+        HeaderRecord::new(header, 5, &[None]),
+    ] {
+        let decoded = HeaderRecord::try_from(record.to_bytes().as_slice()).unwrap();
+        assert_eq!(decoded.header, record.header);
+        assert_eq!(decoded.median_time, record.median_time);
+        assert_eq!(decoded.chainwork, record.chainwork);
+    }
+}
+
+#[test]
+fn undo_records_round_trip() {
+    let undo = UndoRecord {
+        address_keys: vec![codec::address_key(ADDRESS, &Flow::O, &txid(4), 0)],
+        cache_keys: vec![codec::outpoint_key(&txid(4), 0)],
+        spent_utxos: vec![(codec::outpoint_key(&txid(5), 1), utxo(None).to_bytes())],
+        address_stats: vec![
+            (ADDRESS.to_string(), None),
+            (
+                "1BoatSLRHtKNngkdXEeobR76b53LETtpyT".to_string(),
+                Some(AddressStats {
+                    funded_count: 1,
+                    received: 10,
+                    ..AddressStats::default()
+                }),
+            ),
+        ],
+        txids: vec![txid(4), txid(6)],
+        replaced_txs: vec![(
+            txid(4),
+            TxLocation {
+                height: 91_812,
+                position: 0,
+            },
+        )],
+        script_history_keys: vec![codec::history_key(
+            &[7; 32],
+            91_812,
+            1,
+            &Flow::O,
+            &txid(4),
+            0,
+        )],
+    };
+    let decoded = UndoRecord::try_from(undo.to_bytes().as_slice()).unwrap();
+    assert_eq!(decoded.address_keys, undo.address_keys);
+    assert_eq!(decoded.cache_keys, undo.cache_keys);
+    assert_eq!(decoded.spent_utxos, undo.spent_utxos);
+    assert_eq!(decoded.address_stats, undo.address_stats);
+    assert_eq!(decoded.txids, undo.txids);
+    assert_eq!(decoded.replaced_txs, undo.replaced_txs);
+    assert_eq!(decoded.script_history_keys, undo.script_history_keys);
+}
+
+// Legacy rows joined their fields with pipes, cache keys as `txid|index`
+#[test]
+fn indexed_txids_parse_txid_then_index() {
+    let tx_id = txid(7);
+    let indexed_txid = IndexedTxid::from_str(&format!("{}|12", tx_id)).unwrap();
+    assert_eq!(indexed_txid.tx_id, tx_id);
+    assert_eq!(indexed_txid.index, 12);
+    assert_eq!(indexed_txid.to_string(), format!("{}|12", tx_id));
+    assert!(IndexedTxid::from_str(&format!("12|{}", tx_id)).is_err());
+
+    let key = legacy::convert_cache_key(format!("{}|12", tx_id).as_bytes()).unwrap();
+    assert_eq!(key, codec::outpoint_key(&tx_id, 12));
+}
+
+#[test]
+fn legacy_rows_convert() {
+    let tx_id = txid(8);
+    let address_key = format!("{}|O|{}|1", ADDRESS, tx_id);
+    assert_eq!(
+        legacy::convert_address_key(address_key.as_bytes()).unwrap(),
+        codec::address_key(ADDRESS, &Flow::O, &tx_id, 1)
+    );
+    let value =
+        legacy::convert_cache_value(format!("3|{}|5000000000", ADDRESS).as_bytes()).unwrap();
+    assert_utxo_eq(&Utxo::try_from(value.as_slice()).unwrap(), &utxo(None));
+    assert_eq!(
+        legacy::convert_last_height(b"840000").unwrap(),
+        840_000u64.to_be_bytes()
+    );
+
+    // u32 big endian counts and lengths of address keys, cache keys and spent utxos
+    let cache_key = format!("{}|0", tx_id);
+    let spent_key = format!("{}|1", txid(9));
+    let spent_value = format!("3|{}|5000000000", ADDRESS);
+    let mut bytes = Vec::new();
+    for fields in [
+        vec![address_key.as_str()],
+        vec![cache_key.as_str()],
+        vec![spent_key.as_str(), spent_value.as_str()],
+    ] {
+        bytes.extend_from_slice(&1u32.to_be_bytes());
+        for field in fields {
+            bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(field.as_bytes());
+        }
+    }
+    let undo =
+        UndoRecord::try_from(legacy::convert_undo_record(&bytes).unwrap().as_slice()).unwrap();
+    assert_eq!(
+        undo.address_keys,
+        vec![codec::address_key(ADDRESS, &Flow::O, &tx_id, 1)]
+    );
+    assert_eq!(undo.cache_keys, vec![codec::outpoint_key(&tx_id, 0)]);
+    assert_eq!(undo.spent_utxos.len(), 1);
+    assert_eq!(undo.spent_utxos[0].0, codec::outpoint_key(&txid(9), 1));
+    assert_utxo_eq(
+        &Utxo::try_from(undo.spent_utxos[0].1.as_slice()).unwrap(),
+        &utxo(None),
+    );
+    assert!(undo.address_stats.is_empty() && undo.txids.is_empty());
+    assert!(undo.replaced_txs.is_empty());
+}
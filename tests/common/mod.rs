@@ -0,0 +1,154 @@
+// Fixtures shared by the test crates, each uses its own subset of them
+#![allow(dead_code)]
+
+use bitcoin::absolute::LockTime;
+use bitcoin::block::{Header, Version};
+use bitcoin::hashes::Hash;
+use bitcoin::transaction::{self, OutPoint, TxIn, TxOut};
+use bitcoin::{
+    Address, Amount, Block, BlockHash, CompactTarget, Network, ScriptBuf, Sequence, Transaction,
+    TxMerkleNode, WPubkeyHash, Witness,
+};
+use index_btc::cache::UtxoCache;
+use index_btc::indexer::Indexer;
+use index_btc::model::{IndexedBlock, SumTx};
+use std::fmt::Debug;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// A fresh regtest db of the engine in a temp dir, removed once the returned guard is dropped
+pub fn open_indexer<I: Indexer>() -> (I, TempDir) {
+    open_network_indexer(Network::Regtest)
+}
+
+pub fn open_network_indexer<I: Indexer>(network: Network) -> (I, TempDir) {
+    let dir = TempDir::new().unwrap();
+    let db_path = dir.path().join("db");
+    let indexer = I::new(
+        2,
+        db_path.to_str().unwrap(),
+        network,
+        UtxoCache::new(1024 * 1024, 2000),
+    )
+    .unwrap();
+    (indexer, dir)
+}
+
+// The db of a dropped indexer opened again, sled releases its lock from a background thread
+pub fn reopen_indexer<I: Indexer>(dir: &TempDir) -> I {
+    let db_path = dir.path().join("db");
+    retry(|| {
+        I::new(
+            2,
+            db_path.to_str().unwrap(),
+            Network::Regtest,
+            UtxoCache::new(1024 * 1024, 2000),
+        )
+    })
+}
+
+// Retries opening a db until the lock of a dropped one is released
+pub fn retry<T, E: Debug>(mut open: impl FnMut() -> Result<T, E>) -> T {
+    let mut attempts = 0;
+    loop {
+        match open() {
+            Ok(opened) => return opened,
+            Err(_) if attempts < 50 => {
+                attempts += 1;
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => panic!("could not reopen the db: {:?}", e),
+        }
+    }
+}
+
+pub fn script(seed: u8) -> ScriptBuf {
+    ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([seed; 20]))
+}
+
+pub fn coinbase(height: u64, script_pubkey: ScriptBuf, value: u64) -> Transaction {
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: ScriptBuf::from_bytes(height.to_le_bytes().to_vec()),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey,
+        }],
+    }
+}
+
+pub fn spend(previous_output: OutPoint, outputs: Vec<(ScriptBuf, u64)>) -> Transaction {
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: outputs
+            .into_iter()
+            .map(|(script_pubkey, value)| TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey,
+            })
+            .collect(),
+    }
+}
+
+pub fn address(script_pubkey: &ScriptBuf) -> String {
+    Address::from_script(script_pubkey, Network::Regtest)
+        .unwrap()
+        .to_string()
+}
+
+// A regtest block on top of the parent, the nonce tells apart competing blocks at a height
+pub fn block(
+    prev_blockhash: BlockHash,
+    height: u64,
+    nonce: u32,
+    txdata: Vec<Transaction>,
+) -> Block {
+    Block {
+        header: Header {
+            version: Version::TWO,
+            prev_blockhash,
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 1_600_000_000 + height as u32 * 600,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce,
+        },
+        txdata,
+    }
+}
+
+// The block of the transactions on top of the indexed tip, or genesis on an empty db
+pub fn next_block<I: Indexer>(indexer: &I, txs: Vec<Transaction>) -> IndexedBlock {
+    let height = indexer.get_next_height();
+    let prev_blockhash = match height {
+        0 => BlockHash::all_zeros(),
+        _ => indexer.get_block_hash(height - 1).unwrap().unwrap(),
+    };
+    let block = block(prev_blockhash, height, 0, txs);
+    IndexedBlock {
+        height,
+        header: block.header,
+        sum_txs: block
+            .txdata
+            .into_iter()
+            .map(|tx| SumTx::new(tx, Network::Regtest))
+            .collect(),
+    }
+}
+
+pub fn index_block<I: Indexer>(indexer: &mut I, txs: Vec<Transaction>) {
+    let block = next_block(indexer, txs);
+    indexer.update_blocks(&[block]).unwrap();
+}
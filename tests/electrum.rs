@@ -0,0 +1,379 @@
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::consensus::serialize;
+use bitcoin::transaction::OutPoint;
+use bitcoin::{Block, Network, PublicKey, ScriptBuf, Transaction};
+use common::{coinbase, index_block, next_block, open_indexer, script, spend};
+use index_btc::electrum;
+use index_btc::indexer::Indexer;
+use index_btc::model::ScriptHash;
+use index_btc::rocksdb::RocksDbIndexer;
+use index_btc::sleddb::SledDbIndexer;
+use index_btc::source::{BlockSource, MemorySource};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+
+mod common;
+
+struct Client {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+    next_id: u64,
+}
+
+impl Client {
+    async fn connect(addr: &str) -> Self {
+        for _ in 0..50 {
+            if let Ok(stream) = TcpStream::connect(addr).await {
+                let (reader, writer) = stream.into_split();
+                return Client {
+                    lines: BufReader::new(reader).lines(),
+                    writer,
+                    next_id: 0,
+                };
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("Electrum server at {} did not start", addr);
+    }
+
+    async fn send(&mut self, message: &Value) {
+        let line = format!("{}\n", message);
+        self.writer.write_all(line.as_bytes()).await.unwrap();
+    }
+
+    async fn receive(&mut self) -> Value {
+        let line = tokio::time::timeout(Duration::from_secs(10), self.lines.next_line())
+            .await
+            .expect("no message from the electrum server")
+            .unwrap()
+            .unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    async fn call(&mut self, method: &str, params: Value) -> Value {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send(&json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+            .await;
+        // notifications of subscriptions may come first
+        let response = loop {
+            let message = self.receive().await;
+            if message.get("id").is_some() {
+                break message;
+            }
+        };
+        assert_eq!(response["id"], json!(id));
+        response
+    }
+
+    async fn result(&mut self, method: &str, params: Value) -> Value {
+        let response = self.call(method, params).await;
+        assert!(response.get("error").is_none(), "{}", response);
+        response["result"].clone()
+    }
+}
+
+fn free_addr() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+// Indexes a block of the transactions and hands it to the source transactions are read from
+fn index_sourced<I: Indexer>(indexer: &mut I, source: &MemorySource, txs: Vec<Transaction>) {
+    let indexed = next_block(indexer, txs.clone());
+    source.push(Block {
+        header: indexed.header,
+        txdata: txs,
+    });
+    indexer.update_blocks(&[indexed]).unwrap();
+}
+
+async fn serves_scripts_of_the_fixture_chain<I>()
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    let alice = script(1);
+    let bob = script(2);
+    let alice_hash = ScriptHash::new(&alice).to_string();
+    let bob_hash = ScriptHash::new(&bob).to_string();
+
+    let (mut indexer, _dir) = open_indexer::<I>();
+    let source = Arc::new(MemorySource::new(vec![]));
+    let funding = coinbase(0, alice.clone(), 50_000);
+    let funding_txid = funding.compute_txid();
+    index_sourced(&mut indexer, &source, vec![funding]);
+    let payment = spend(
+        OutPoint::new(funding_txid, 0),
+        vec![(bob.clone(), 30_000), (alice.clone(), 19_000)],
+    );
+    let payment_txid = payment.compute_txid();
+    index_sourced(
+        &mut indexer,
+        &source,
+        vec![coinbase(1, bob.clone(), 50_000), payment.clone()],
+    );
+
+    let addr = free_addr();
+    let (shutdown_sender, shutdown) = oneshot::channel::<()>();
+    let server = tokio::spawn(electrum::serve(
+        indexer.clone(),
+        Some(source.clone() as Arc<dyn BlockSource>),
+        Network::Regtest,
+        addr.clone(),
+        async {
+            let _ = shutdown.await;
+        },
+    ));
+    let mut client = Client::connect(&addr).await;
+
+    let version = client
+        .result("server.version", json!(["test", "1.4"]))
+        .await;
+    assert_eq!(version[1], json!(electrum::PROTOCOL_VERSION));
+    let features = client.result("server.features", json!([])).await;
+    assert_eq!(
+        features["genesis_hash"],
+        json!(genesis_block(Network::Regtest).block_hash().to_string())
+    );
+    assert_eq!(features["hash_function"], json!("sha256"));
+
+    let raw_tx = client
+        .result(
+            "blockchain.transaction.get",
+            json!([payment_txid.to_string()]),
+        )
+        .await;
+    assert_eq!(raw_tx, json!(base16::encode_lower(&serialize(&payment))));
+    let header = client.result("blockchain.block.header", json!([1])).await;
+    let block_1 = source.get_block(1).unwrap().block;
+    assert_eq!(
+        header,
+        json!(base16::encode_lower(&serialize(&block_1.header)))
+    );
+    let unindexed = coinbase(5, script(3), 50_000).compute_txid();
+    let missing_tx = client
+        .call("blockchain.transaction.get", json!([unindexed.to_string()]))
+        .await;
+    assert_eq!(missing_tx["error"]["code"], json!(-32602));
+    let missing_header = client.call("blockchain.block.header", json!([5])).await;
+    assert_eq!(missing_header["error"]["code"], json!(-32602));
+
+    let balance = client
+        .result("blockchain.scripthash.get_balance", json!([alice_hash]))
+        .await;
+    assert_eq!(balance, json!({ "confirmed": 19_000, "unconfirmed": 0 }));
+
+    let history = client
+        .result("blockchain.scripthash.get_history", json!([alice_hash]))
+        .await;
+    assert_eq!(
+        history,
+        json!([
+            { "tx_hash": funding_txid.to_string(), "height": 0 },
+            { "tx_hash": payment_txid.to_string(), "height": 1 },
+        ])
+    );
+
+    let unspent = client
+        .result("blockchain.scripthash.listunspent", json!([alice_hash]))
+        .await;
+    assert_eq!(
+        unspent,
+        json!([{ "tx_hash": payment_txid.to_string(), "tx_pos": 1, "height": 1, "value": 19_000 }])
+    );
+
+    let unknown = ScriptHash::new(&script(3)).to_string();
+    let empty = client
+        .result("blockchain.scripthash.get_history", json!([unknown]))
+        .await;
+    assert_eq!(empty, json!([]));
+    let status = client
+        .result("blockchain.scripthash.subscribe", json!([unknown]))
+        .await;
+    assert_eq!(status, Value::Null);
+
+    let bad_params = client
+        .call("blockchain.scripthash.get_balance", json!(["nope"]))
+        .await;
+    assert_eq!(bad_params["error"]["code"], json!(-32602));
+    let unknown_method = client.call("blockchain.nope", json!([])).await;
+    assert_eq!(unknown_method["error"]["code"], json!(-32601));
+
+    let tip = client
+        .result("blockchain.headers.subscribe", json!([]))
+        .await;
+    assert_eq!(tip["height"], json!(1));
+    assert_eq!(tip["hex"].as_str().unwrap().len(), 160);
+    let bob_status = client
+        .result("blockchain.scripthash.subscribe", json!([bob_hash]))
+        .await;
+    assert!(bob_status.is_string());
+
+    client
+        .send(&json!([
+            { "jsonrpc": "2.0", "id": "a", "method": "server.ping", "params": [] },
+            { "jsonrpc": "2.0", "id": "b", "method": "blockchain.scripthash.get_balance", "params": [bob_hash] },
+        ]))
+        .await;
+    let batch = client.receive().await;
+    assert_eq!(batch[0]["id"], json!("a"));
+    assert_eq!(batch[1]["result"]["confirmed"], json!(80_000));
+
+    // a new block paying bob notifies the new tip and bob's new status
+    let coinbase_txid = {
+        let tx = coinbase(2, bob.clone(), 50_000);
+        let txid = tx.compute_txid();
+        index_sourced(&mut indexer, &source, vec![tx]);
+        txid
+    };
+    let mut notified = HashMap::new();
+    while notified.len() < 2 {
+        let notification = client.receive().await;
+        let method = notification["method"].as_str().unwrap().to_string();
+        notified.insert(method, notification["params"].clone());
+    }
+    assert_eq!(
+        notified["blockchain.headers.subscribe"][0]["height"],
+        json!(2)
+    );
+    let bob_notified = &notified["blockchain.scripthash.subscribe"];
+    assert_eq!(bob_notified[0], json!(bob_hash));
+    assert_ne!(bob_notified[1], bob_status);
+    let history = client
+        .result("blockchain.scripthash.get_history", json!([bob_hash]))
+        .await;
+    assert_eq!(history[2]["tx_hash"], json!(coinbase_txid.to_string()));
+
+    shutdown_sender.send(()).unwrap();
+    server.await.unwrap().unwrap();
+}
+
+async fn keeps_scripts_sharing_an_address_apart<I>()
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    let key: PublicKey = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+        .parse()
+        .unwrap();
+    let p2pk = ScriptBuf::new_p2pk(&key);
+    let p2pkh = ScriptBuf::new_p2pkh(&key.pubkey_hash());
+
+    let (mut indexer, _dir) = open_indexer::<I>();
+    let funding = coinbase(0, p2pk.clone(), 50_000);
+    let funding_txid = funding.compute_txid();
+    index_block(&mut indexer, vec![funding]);
+    let coinbase_1 = coinbase(1, p2pkh.clone(), 20_000);
+    index_block(&mut indexer, vec![coinbase_1.clone()]);
+    // the p2pk output pays the p2pkh script after the coinbase of its block
+    let coinbase_2 = coinbase(2, script(1), 50_000);
+    let payment = spend(
+        OutPoint::new(funding_txid, 0),
+        vec![(p2pkh.clone(), 50_000)],
+    );
+    index_block(&mut indexer, vec![coinbase_2, payment.clone()]);
+
+    let addr = free_addr();
+    let (shutdown_sender, shutdown) = oneshot::channel::<()>();
+    let server = tokio::spawn(electrum::serve(
+        indexer.clone(),
+        None,
+        Network::Regtest,
+        addr.clone(),
+        async {
+            let _ = shutdown.await;
+        },
+    ));
+    let mut client = Client::connect(&addr).await;
+
+    let p2pk_hash = ScriptHash::new(&p2pk).to_string();
+    let p2pkh_hash = ScriptHash::new(&p2pkh).to_string();
+    let history = client
+        .result("blockchain.scripthash.get_history", json!([p2pk_hash]))
+        .await;
+    assert_eq!(
+        history,
+        json!([
+            { "tx_hash": funding_txid.to_string(), "height": 0 },
+            { "tx_hash": payment.compute_txid().to_string(), "height": 2 },
+        ])
+    );
+    let history = client
+        .result("blockchain.scripthash.get_history", json!([p2pkh_hash]))
+        .await;
+    assert_eq!(
+        history,
+        json!([
+            { "tx_hash": coinbase_1.compute_txid().to_string(), "height": 1 },
+            { "tx_hash": payment.compute_txid().to_string(), "height": 2 },
+        ])
+    );
+    let p2pk_status = client
+        .result("blockchain.scripthash.subscribe", json!([p2pk_hash]))
+        .await;
+    let p2pkh_status = client
+        .result("blockchain.scripthash.subscribe", json!([p2pkh_hash]))
+        .await;
+    assert_ne!(p2pk_status, p2pkh_status);
+    // transactions are not served without a block source
+    let no_source = client
+        .call(
+            "blockchain.transaction.get",
+            json!([funding_txid.to_string()]),
+        )
+        .await;
+    assert_eq!(no_source["error"]["code"], json!(-32603));
+    let unspent = client
+        .result("blockchain.scripthash.listunspent", json!([p2pk_hash]))
+        .await;
+    assert_eq!(unspent, json!([]));
+    let balance = client
+        .result("blockchain.scripthash.get_balance", json!([p2pkh_hash]))
+        .await;
+    assert_eq!(balance["confirmed"], json!(70_000));
+
+    // rolling the payment back takes it out of the history of both scripts
+    indexer.rollback(1).unwrap();
+    let history = client
+        .result("blockchain.scripthash.get_history", json!([p2pk_hash]))
+        .await;
+    assert_eq!(
+        history,
+        json!([{ "tx_hash": funding_txid.to_string(), "height": 0 }])
+    );
+    let unspent = client
+        .result("blockchain.scripthash.listunspent", json!([p2pk_hash]))
+        .await;
+    assert_eq!(
+        unspent,
+        json!([{ "tx_hash": funding_txid.to_string(), "tx_pos": 0, "height": 0, "value": 50_000 }])
+    );
+
+    shutdown_sender.send(()).unwrap();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn serves_scripts_of_the_fixture_chain_rocks_db() {
+    serves_scripts_of_the_fixture_chain::<RocksDbIndexer>().await;
+}
+
+#[tokio::test]
+async fn serves_scripts_of_the_fixture_chain_sled_db() {
+    serves_scripts_of_the_fixture_chain::<SledDbIndexer>().await;
+}
+
+#[tokio::test]
+async fn keeps_scripts_sharing_an_address_apart_rocks_db() {
+    keeps_scripts_sharing_an_address_apart::<RocksDbIndexer>().await;
+}
+
+#[tokio::test]
+async fn keeps_scripts_sharing_an_address_apart_sled_db() {
+    keeps_scripts_sharing_an_address_apart::<SledDbIndexer>().await;
+}
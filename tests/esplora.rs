@@ -0,0 +1,314 @@
+use axum::body::{to_bytes, Body};
+use axum::http::{Request, StatusCode};
+use axum::Router;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::hashes::Hash;
+use bitcoin::transaction::OutPoint;
+use bitcoin::{Address, Block, BlockHash, Network, ScriptBuf};
+use common::{block, coinbase, open_network_indexer, script, spend};
+use index_btc::esplora;
+use index_btc::indexer::Indexer;
+use index_btc::model::{IndexedBlock, SumTx};
+use index_btc::rocksdb::RocksDbIndexer;
+use index_btc::sleddb::SledDbIndexer;
+use index_btc::source::{BlockSource, MemorySource};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tower::ServiceExt;
+
+mod common;
+
+// Responses of blockstream.info/api for the mainnet genesis block and its coinbase
+const GENESIS_TX: &str = r#"{
+  "txid": "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+  "version": 1,
+  "locktime": 0,
+  "vin": [{
+    "txid": "0000000000000000000000000000000000000000000000000000000000000000",
+    "vout": 4294967295,
+    "prevout": null,
+    "scriptsig": "04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73",
+    "scriptsig_asm": "OP_PUSHBYTES_4 ffff001d OP_PUSHBYTES_1 04 OP_PUSHBYTES_69 5468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73",
+    "is_coinbase": true,
+    "sequence": 4294967295
+  }],
+  "vout": [{
+    "scriptpubkey": "4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac",
+    "scriptpubkey_asm": "OP_PUSHBYTES_65 04678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5f OP_CHECKSIG",
+    "scriptpubkey_type": "p2pk",
+    "value": 5000000000
+  }],
+  "size": 204,
+  "weight": 816,
+  "fee": 0,
+  "status": {
+    "confirmed": true,
+    "block_height": 0,
+    "block_hash": "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+    "block_time": 1231006505
+  }
+}"#;
+
+const GENESIS_BLOCK: &str = r#"{
+  "id": "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+  "height": 0,
+  "version": 1,
+  "timestamp": 1231006505,
+  "tx_count": 1,
+  "size": 285,
+  "weight": 1140,
+  "merkle_root": "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+  "previousblockhash": null,
+  "mediantime": 1231006505,
+  "nonce": 2083236893,
+  "bits": 486604799,
+  "difficulty": 1.0
+}"#;
+
+// Address the genesis output is indexed under
+const GENESIS_ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+async fn get(app: &Router, uri: &str) -> (StatusCode, String) {
+    let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+async fn get_json(app: &Router, uri: &str) -> Value {
+    let (status, body) = get(app, uri).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    serde_json::from_str(&body).unwrap()
+}
+
+fn index<I: Indexer>(indexer: &mut I, source: &MemorySource, height: u64, block: Block) {
+    let indexed = IndexedBlock {
+        height,
+        header: block.header,
+        sum_txs: block
+            .txdata
+            .iter()
+            .cloned()
+            .map(|tx| SumTx::new(tx, Network::Bitcoin))
+            .collect(),
+    };
+    indexer.update_blocks(&[indexed]).unwrap();
+    source.push(block);
+}
+
+async fn serves_the_format_of_esplora<I>()
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    let (mut indexer, _dir) = open_network_indexer::<I>(Network::Bitcoin);
+    let source = Arc::new(MemorySource::new(vec![]));
+    let genesis = genesis_block(Network::Bitcoin);
+    let genesis_txid = genesis.txdata[0].compute_txid();
+    index(&mut indexer, &source, 0, genesis.clone());
+    let app = esplora::router(
+        indexer.clone(),
+        Some(source.clone() as Arc<dyn BlockSource>),
+        Network::Bitcoin,
+    );
+
+    let genesis_tx: Value = serde_json::from_str(GENESIS_TX).unwrap();
+    assert_eq!(
+        get_json(&app, &format!("/tx/{}", genesis_txid)).await,
+        genesis_tx
+    );
+    let genesis_value = get_json(&app, &format!("/block/{}", genesis.block_hash())).await;
+    assert_eq!(
+        genesis_value,
+        serde_json::from_str::<Value>(GENESIS_BLOCK).unwrap()
+    );
+    assert_eq!(
+        get(&app, "/blocks/tip/height").await,
+        (StatusCode::OK, "0".to_string())
+    );
+    assert_eq!(
+        get_json(&app, &format!("/tx/{}/outspends", genesis_txid)).await,
+        json!([{ "spent": false }])
+    );
+
+    // the genesis output pays a segwit address and a data carrier
+    let payee = script(1);
+    let payee_address = Address::from_script(&payee, Network::Bitcoin)
+        .unwrap()
+        .to_string();
+    let payment = spend(
+        OutPoint::new(genesis_txid, 0),
+        vec![
+            (payee.clone(), 4_999_990_000),
+            (ScriptBuf::new_op_return([1, 2, 3]), 0),
+        ],
+    );
+    let payment_txid = payment.compute_txid();
+    let block_1 = block(genesis.block_hash(), 1, 0, vec![payment]);
+    let status_1 = json!({
+        "confirmed": true,
+        "block_height": 1,
+        "block_hash": block_1.block_hash().to_string(),
+        "block_time": block_1.header.time,
+    });
+    index(&mut indexer, &source, 1, block_1);
+
+    let tx = get_json(&app, &format!("/tx/{}", payment_txid)).await;
+    assert_eq!(tx["vin"][0]["prevout"], genesis_tx["vout"][0]);
+    assert_eq!(tx["vin"][0]["is_coinbase"], json!(false));
+    assert_eq!(tx["vout"][0]["scriptpubkey_type"], json!("v0_p2wpkh"));
+    assert_eq!(tx["vout"][0]["scriptpubkey_address"], json!(payee_address));
+    assert_eq!(tx["vout"][1]["scriptpubkey_type"], json!("op_return"));
+    assert_eq!(tx["fee"], json!(10_000));
+    assert_eq!(tx["status"], status_1);
+    assert_eq!(
+        get_json(&app, &format!("/tx/{}/outspends", genesis_txid)).await,
+        json!([{ "spent": true, "txid": payment_txid.to_string(), "vin": 0, "status": status_1 }])
+    );
+    assert_eq!(
+        get_json(&app, &format!("/tx/{}/outspends", payment_txid)).await,
+        json!([{ "spent": false }, { "spent": false }])
+    );
+
+    let zero_stats = json!({
+        "funded_txo_count": 0,
+        "funded_txo_sum": 0,
+        "spent_txo_count": 0,
+        "spent_txo_sum": 0,
+        "tx_count": 0,
+    });
+    assert_eq!(
+        get_json(&app, &format!("/address/{}", GENESIS_ADDRESS)).await,
+        json!({
+            "address": GENESIS_ADDRESS,
+            "chain_stats": {
+                "funded_txo_count": 1,
+                "funded_txo_sum": 5_000_000_000u64,
+                "spent_txo_count": 1,
+                "spent_txo_sum": 5_000_000_000u64,
+                "tx_count": 2,
+            },
+            "mempool_stats": zero_stats,
+        })
+    );
+    assert_eq!(
+        get_json(&app, &format!("/address/{}/utxo", payee_address)).await,
+        json!([{ "txid": payment_txid.to_string(), "vout": 0, "status": status_1, "value": 4_999_990_000u64 }])
+    );
+    let txs = get_json(&app, &format!("/address/{}/txs", GENESIS_ADDRESS)).await;
+    let txids: Vec<&Value> = txs
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|tx| &tx["txid"])
+        .collect();
+    assert_eq!(
+        txids,
+        vec![
+            &json!(payment_txid.to_string()),
+            &json!(genesis_txid.to_string())
+        ]
+    );
+    let page = get_json(
+        &app,
+        &format!("/address/{}/txs/chain/{}", GENESIS_ADDRESS, payment_txid),
+    )
+    .await;
+    assert_eq!(page[0]["txid"], json!(genesis_txid.to_string()));
+
+    assert_eq!(
+        get(&app, "/tx/nothex").await,
+        (StatusCode::BAD_REQUEST, "Invalid hex string".to_string())
+    );
+    assert_eq!(
+        get(&app, "/address/nothing").await,
+        (
+            StatusCode::BAD_REQUEST,
+            "Invalid Bitcoin address".to_string()
+        )
+    );
+    assert_eq!(
+        get(&app, &format!("/tx/{}", genesis.block_hash())).await,
+        (StatusCode::NOT_FOUND, "Transaction not found".to_string())
+    );
+}
+
+// Txids of the transactions of an address page
+async fn page_txids(app: &Router, uri: &str) -> Vec<String> {
+    get_json(app, uri)
+        .await
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|tx| tx["txid"].as_str().unwrap().to_string())
+        .collect()
+}
+
+async fn pages_address_txs_newest_first<I>()
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    let (mut indexer, _dir) = open_network_indexer::<I>(Network::Bitcoin);
+    let source = Arc::new(MemorySource::new(vec![]));
+    let address = Address::from_script(&script(1), Network::Bitcoin)
+        .unwrap()
+        .to_string();
+    // a coinbase paying the address in each block, the last block spends the first one back to it
+    let mut prev_blockhash = BlockHash::all_zeros();
+    let mut txids = Vec::new();
+    for height in 0..31 {
+        let tx = match height {
+            30 => spend(OutPoint::new(txids[0], 0), vec![(script(1), 50)]),
+            _ => coinbase(height, script(1), 50),
+        };
+        txids.push(tx.compute_txid());
+        let block = block(prev_blockhash, height, 0, vec![tx]);
+        prev_blockhash = block.block_hash();
+        index(&mut indexer, &source, height, block);
+    }
+    let app = esplora::router(
+        indexer.clone(),
+        Some(source.clone() as Arc<dyn BlockSource>),
+        Network::Bitcoin,
+    );
+    let newest_first: Vec<String> = txids.iter().rev().map(|txid| txid.to_string()).collect();
+
+    let first_page = page_txids(&app, &format!("/address/{}/txs", address)).await;
+    assert_eq!(first_page, newest_first[..25]);
+    let second_page = page_txids(
+        &app,
+        &format!("/address/{}/txs/chain/{}", address, first_page[24]),
+    )
+    .await;
+    assert_eq!(second_page, newest_first[25..]);
+    assert!(page_txids(
+        &app,
+        &format!("/address/{}/txs/chain/{}", address, txids[0])
+    )
+    .await
+    .is_empty());
+    assert_eq!(
+        get_json(&app, &format!("/address/{}", address)).await["chain_stats"]["tx_count"],
+        json!(31)
+    );
+}
+
+#[tokio::test]
+async fn pages_address_txs_newest_first_rocks_db() {
+    pages_address_txs_newest_first::<RocksDbIndexer>().await;
+}
+
+#[tokio::test]
+async fn pages_address_txs_newest_first_sled_db() {
+    pages_address_txs_newest_first::<SledDbIndexer>().await;
+}
+
+#[tokio::test]
+async fn serves_the_format_of_esplora_rocks_db() {
+    serves_the_format_of_esplora::<RocksDbIndexer>().await;
+}
+
+#[tokio::test]
+async fn serves_the_format_of_esplora_sled_db() {
+    serves_the_format_of_esplora::<SledDbIndexer>().await;
+}
@@ -0,0 +1,283 @@
+use bitcoin::hashes::Hash;
+use bitcoin::transaction::OutPoint;
+use bitcoin::Txid;
+use common::{
+    address, coinbase, index_block, next_block, open_indexer, reopen_indexer, retry, script, spend,
+};
+use index_btc::codec::{encode_schema_version, SCHEMA_VERSION_KEY, SCRIPT_HISTORY_SCHEMA_VERSION};
+use index_btc::indexer::{Indexer, IndexerError};
+use index_btc::model::{BalanceDeltas, Flow, HistoryFilter, IndexedTxid, ScriptHash, META_CF};
+use index_btc::rocksdb::RocksDbIndexer;
+use index_btc::sleddb::SledDbIndexer;
+
+mod common;
+
+fn fails_on_a_missing_utxo<I: Indexer>() {
+    let (mut indexer, _dir) = open_indexer::<I>();
+    index_block(&mut indexer, vec![coinbase(0, script(1), 50)]);
+    let unknown = OutPoint::new(Txid::from_byte_array([7; 32]), 1);
+    let block = next_block(
+        &indexer,
+        vec![
+            coinbase(1, script(2), 50),
+            spend(unknown, vec![(script(3), 10)]),
+        ],
+    );
+
+    match indexer.update_blocks(&[block]) {
+        Err(IndexerError::MissingUtxo { height, outpoint }) => {
+            assert_eq!(height, 1);
+            assert_eq!(outpoint.tx_id, unknown.txid);
+            assert_eq!(outpoint.index, 1);
+        }
+        result => panic!("expected a missing utxo, got {:?}", result),
+    }
+    assert_eq!(indexer.get_last_height(), 0);
+    // outputs of the failed commit are not left in the utxo cache
+    let created = IndexedTxid {
+        tx_id: coinbase(1, script(2), 50).compute_txid(),
+        index: 0,
+    };
+    assert!(indexer.get_utxo(&created).unwrap().is_none());
+}
+
+#[test]
+fn fails_on_a_missing_utxo_rocks_db() {
+    fails_on_a_missing_utxo::<RocksDbIndexer>();
+}
+
+#[test]
+fn fails_on_a_missing_utxo_sled_db() {
+    fails_on_a_missing_utxo::<SledDbIndexer>();
+}
+
+fn balances_follow_commits_and_rollbacks<I: Indexer>() {
+    let (mut indexer, dir) = open_indexer::<I>();
+    let funding = coinbase(0, script(1), 50);
+    index_block(&mut indexer, vec![funding.clone()]);
+    let payment = spend(
+        OutPoint::new(funding.compute_txid(), 0),
+        vec![(script(2), 30), (script(1), 20)],
+    );
+    index_block(
+        &mut indexer,
+        vec![coinbase(1, script(3), 50), payment.clone()],
+    );
+    assert_eq!(indexer.get_balance(&address(&script(1))).unwrap(), 20);
+    assert_eq!(indexer.get_balance(&address(&script(2))).unwrap(), 30);
+    assert_eq!(indexer.get_balance(&address(&script(3))).unwrap(), 50);
+
+    indexer.rollback(0).unwrap();
+    assert_eq!(indexer.get_balance(&address(&script(1))).unwrap(), 50);
+    assert_eq!(indexer.get_balance(&address(&script(2))).unwrap(), 0);
+    assert_eq!(indexer.get_balance(&address(&script(3))).unwrap(), 0);
+
+    // blocks above the flush height are rolled back when the db is reopened
+    indexer.flush().unwrap();
+    index_block(&mut indexer, vec![coinbase(1, script(3), 50), payment]);
+    assert_eq!(indexer.get_balance(&address(&script(2))).unwrap(), 30);
+    drop(indexer);
+    let indexer = reopen_indexer::<I>(&dir);
+    assert_eq!(indexer.get_last_height(), 0);
+    assert_eq!(indexer.get_balance(&address(&script(1))).unwrap(), 50);
+    assert_eq!(indexer.get_balance(&address(&script(2))).unwrap(), 0);
+    assert_eq!(indexer.get_balance(&address(&script(3))).unwrap(), 0);
+}
+
+#[test]
+fn balances_follow_commits_and_rollbacks_rocks_db() {
+    balances_follow_commits_and_rollbacks::<RocksDbIndexer>();
+}
+
+#[test]
+fn balances_follow_commits_and_rollbacks_sled_db() {
+    balances_follow_commits_and_rollbacks::<SledDbIndexer>();
+}
+
+#[test]
+fn balances_never_go_below_zero() {
+    assert_eq!(BalanceDeltas::apply("a", 50, -20).unwrap(), Some(30));
+    assert_eq!(BalanceDeltas::apply("a", 50, -50).unwrap(), None);
+    match BalanceDeltas::apply("a", 50, -51) {
+        Err(IndexerError::InvalidBalance {
+            address,
+            balance,
+            delta,
+        }) => assert_eq!((address.as_str(), balance, delta), ("a", 50, -51)),
+        result => panic!("expected an invalid balance, got {:?}", result),
+    }
+    assert!(BalanceDeltas::apply("a", u64::MAX, 1).is_err());
+}
+
+fn history_follows_blocks_and_transactions<I: Indexer>() {
+    let (mut indexer, _dir) = open_indexer::<I>();
+    let funding = coinbase(0, script(1), 50);
+    index_block(&mut indexer, vec![funding.clone()]);
+    // a later transaction of block 1 pays the address, an earlier one spends its coinbase
+    let payment = spend(
+        OutPoint::new(funding.compute_txid(), 0),
+        vec![(script(2), 50)],
+    );
+    let refund = spend(
+        OutPoint::new(payment.compute_txid(), 0),
+        vec![(script(1), 50)],
+    );
+    index_block(
+        &mut indexer,
+        vec![coinbase(1, script(3), 50), payment.clone(), refund.clone()],
+    );
+    index_block(&mut indexer, vec![coinbase(2, script(1), 50)]);
+
+    let flows = |indexer: &I, filter: &HistoryFilter| -> Vec<(u64, usize, Txid, bool)> {
+        indexer
+            .get_history_filtered(&address(&script(1)), filter)
+            .unwrap()
+            .into_iter()
+            .map(|(flow, _)| {
+                let txid = match &flow.spent_by {
+                    Some(spend) => spend.tx_id,
+                    None => flow.tx_id,
+                };
+                (
+                    flow.height.unwrap(),
+                    flow.position.unwrap(),
+                    txid,
+                    matches!(flow.flow, Flow::O),
+                )
+            })
+            .collect()
+    };
+    let all = flows(&indexer, &HistoryFilter::default());
+    assert_eq!(
+        all,
+        vec![
+            (0, 0, funding.compute_txid(), true),
+            (1, 1, payment.compute_txid(), false),
+            (1, 2, refund.compute_txid(), true),
+            (2, 0, coinbase(2, script(1), 50).compute_txid(), true),
+        ]
+    );
+    let block_1 = HistoryFilter {
+        start_height: Some(1),
+        end_height: Some(1),
+        ..HistoryFilter::default()
+    };
+    assert_eq!(flows(&indexer, &block_1), all[1..3].to_vec());
+
+    // rolled back flows leave the history
+    indexer.rollback(0).unwrap();
+    assert_eq!(
+        flows(&indexer, &HistoryFilter::default()),
+        all[..1].to_vec()
+    );
+}
+
+#[test]
+fn history_follows_blocks_and_transactions_rocks_db() {
+    history_follows_blocks_and_transactions::<RocksDbIndexer>();
+}
+
+#[test]
+fn history_follows_blocks_and_transactions_sled_db() {
+    history_follows_blocks_and_transactions::<SledDbIndexer>();
+}
+
+fn rollbacks_restore_repeated_coinbase_txids<I: Indexer>() {
+    let (mut indexer, _dir) = open_indexer::<I>();
+    // coinbases of before bip30 could repeat the transaction of an earlier one
+    let repeated = coinbase(0, script(1), 50);
+    let txid = repeated.compute_txid();
+    index_block(&mut indexer, vec![repeated.clone()]);
+    let block_1 = next_block(&indexer, vec![repeated.clone()]);
+    let mut block_2 = block_1.clone();
+    block_2.height = 2;
+    block_2.header.prev_blockhash = block_1.header.block_hash();
+    indexer.update_blocks(&[block_1, block_2]).unwrap();
+    let height = |indexer: &I| indexer.get_tx_location(&txid).unwrap().unwrap().height;
+    assert_eq!(height(&indexer), 2);
+
+    indexer.rollback(1).unwrap();
+    assert_eq!(height(&indexer), 1);
+    indexer.rollback(0).unwrap();
+    assert_eq!(height(&indexer), 0);
+}
+
+#[test]
+fn rollbacks_restore_repeated_coinbase_txids_rocks_db() {
+    rollbacks_restore_repeated_coinbase_txids::<RocksDbIndexer>();
+}
+
+#[test]
+fn rollbacks_restore_repeated_coinbase_txids_sled_db() {
+    rollbacks_restore_repeated_coinbase_txids::<SledDbIndexer>();
+}
+
+fn script_utxos_follow_spends_and_rollbacks<I: Indexer>() {
+    let (mut indexer, _dir) = open_indexer::<I>();
+    let funding = coinbase(0, script(1), 50);
+    index_block(&mut indexer, vec![funding.clone()]);
+    let payment = spend(
+        OutPoint::new(funding.compute_txid(), 0),
+        vec![(script(2), 20), (script(1), 30)],
+    );
+    index_block(
+        &mut indexer,
+        vec![coinbase(1, script(3), 50), payment.clone()],
+    );
+
+    let utxos = |indexer: &I, seed: u8| -> Vec<(Txid, usize, u64)> {
+        indexer
+            .get_script_utxos(&ScriptHash::new(&script(seed)))
+            .unwrap()
+            .into_iter()
+            .map(|(outpoint, value)| (outpoint.tx_id, outpoint.index, value))
+            .collect()
+    };
+    assert_eq!(utxos(&indexer, 1), vec![(payment.compute_txid(), 1, 30)]);
+    assert_eq!(utxos(&indexer, 2), vec![(payment.compute_txid(), 0, 20)]);
+
+    indexer.rollback(0).unwrap();
+    assert_eq!(utxos(&indexer, 1), vec![(funding.compute_txid(), 0, 50)]);
+    assert!(utxos(&indexer, 2).is_empty());
+}
+
+#[test]
+fn script_utxos_follow_spends_and_rollbacks_rocks_db() {
+    script_utxos_follow_spends_and_rollbacks::<RocksDbIndexer>();
+}
+
+#[test]
+fn script_utxos_follow_spends_and_rollbacks_sled_db() {
+    script_utxos_follow_spends_and_rollbacks::<SledDbIndexer>();
+}
+
+// Flow rows do not record their script, so a migrated db cannot tell the outputs of a script apart
+// from those of other scripts of its address
+#[test]
+fn migrated_dbs_refuse_script_queries() {
+    let (mut indexer, dir) = open_indexer::<SledDbIndexer>();
+    index_block(&mut indexer, vec![coinbase(0, script(1), 50)]);
+    indexer.flush().unwrap();
+    drop(indexer);
+    let db_path = dir.path().join("db");
+    let db_path = db_path.to_str().unwrap();
+    {
+        let db = retry(|| sled::open(db_path));
+        db.open_tree(META_CF)
+            .unwrap()
+            .insert(
+                SCHEMA_VERSION_KEY,
+                &encode_schema_version(SCRIPT_HISTORY_SCHEMA_VERSION),
+            )
+            .unwrap();
+        db.flush().unwrap();
+    }
+    retry(|| SledDbIndexer::migrate(2, db_path));
+
+    let indexer = reopen_indexer::<SledDbIndexer>(&dir);
+    assert_eq!(indexer.get_balance(&address(&script(1))).unwrap(), 50);
+    match indexer.get_script_utxos(&ScriptHash::new(&script(1))) {
+        Err(IndexerError::MissingScriptHistory { height }) => assert_eq!(height, 0),
+        result => panic!("expected a missing script history, got {:?}", result),
+    }
+}
@@ -0,0 +1,141 @@
+use bitcoin::hashes::Hash;
+use bitcoin::{BlockHash, Network, OutPoint};
+use common::{block, coinbase, script, spend};
+use index_btc::codec::{
+    address_key, address_prefix, encode_input_value, encode_output_value, history_key, outpoint_key,
+};
+use index_btc::model::{Flow, HeaderRecord, IndexedBlock, Spend, SumTx, TxLocation, UndoRecord};
+
+mod common;
+
+// Records of a chain from genesis whose block times are given by height
+fn chain(times: &[u32]) -> Vec<Option<HeaderRecord>> {
+    let mut records: Vec<Option<HeaderRecord>> = Vec::new();
+    for (height, time) in times.iter().enumerate() {
+        let mut header = block(BlockHash::all_zeros(), height as u64, 0, vec![]).header;
+        header.time = *time;
+        let record = HeaderRecord::new(header, height as u64, &records);
+        records.push(Some(record));
+    }
+    records
+}
+
+#[test]
+fn median_time_is_taken_over_the_last_eleven_blocks() {
+    let records = chain(&[
+        100, 90, 300, 110, 120, 130, 140, 150, 160, 170, 180, 190, 95,
+    ]);
+    let median_times: Vec<u32> = records
+        .iter()
+        .map(|record| record.as_ref().unwrap().median_time.unwrap())
+        .collect();
+    // genesis is its own median, blocks below 11 take all the blocks down to genesis
+    assert_eq!(median_times[0], 100);
+    assert_eq!(median_times[1], 100);
+    assert_eq!(median_times[2], 100);
+    assert_eq!(median_times[3], 110);
+    assert_eq!(median_times[10], 140);
+    // genesis leaves the window
+    assert_eq!(median_times[11], 150);
+    assert_eq!(median_times[12], 150);
+}
+
+#[test]
+fn chainwork_sums_the_work_from_genesis() {
+    let records = chain(&[100, 200, 300]);
+    let work = records[0].as_ref().unwrap().header.work();
+    assert_eq!(records[0].as_ref().unwrap().chainwork, Some(work));
+    assert_eq!(
+        records[2].as_ref().unwrap().chainwork,
+        Some(work + work + work)
+    );
+}
+
+#[test]
+fn unknown_headers_below_leave_the_record_unknown() {
+    let records = chain(&[100, 200, 300]);
+    let header = records[2].as_ref().unwrap().header;
+    // the block below was indexed without its header
+    let record = HeaderRecord::new(header, 3, &[records[0].clone(), records[1].clone(), None]);
+    assert!(record.median_time.is_none() && record.chainwork.is_none());
+    // blocks further below than the median time span do not matter to it
+    let mut below = vec![None];
+    below.extend(chain(&[100; 11]).into_iter().skip(1));
+    let record = HeaderRecord::new(header, 11, &below);
+    assert_eq!(record.median_time, Some(100));
+    assert!(record.chainwork.is_some());
+}
+
+#[test]
+fn write_size_counts_the_encoded_rows() {
+    // a mainnet p2wpkh spend, whose addresses are as long as the typical one assumed for inputs
+    let (height, time, position) = (800_000, 1_700_000_000, 1_000);
+    let funding = SumTx::new(coinbase(0, script(1), 50), Network::Bitcoin);
+    let payment = SumTx::new(
+        spend(OutPoint::new(funding.txid, 0), vec![(script(2), 40)]),
+        Network::Bitcoin,
+    );
+    let (spent, utxo) = (&funding.outs[0], &payment.outs[0]);
+    // bytes of the flow, history and script history rows of a flow, with its flow and script keys
+    let rows = |address: &str, script_hash: &[u8], flow: &Flow, outpoint: &OutPoint, value| {
+        let index = outpoint.vout as usize;
+        let flow_key = address_key(address, flow, &outpoint.txid, index);
+        let history_key =
+            |prefix: &[u8]| history_key(prefix, height, position, flow, &outpoint.txid, index);
+        let keys = [
+            flow_key.clone(),
+            history_key(&address_prefix(address)),
+            history_key(script_hash),
+        ];
+        let size = keys.iter().map(|key| key.len() + value).sum::<usize>();
+        (size, flow_key, keys[2].clone())
+    };
+
+    let output_value = encode_output_value(utxo.value, height, time, position).len();
+    let (output_size, output_key, output_script_key) = rows(
+        &utxo.address,
+        &utxo.script_hash.unwrap().0,
+        &Flow::O,
+        &OutPoint::new(payment.txid, 0),
+        output_value,
+    );
+    let cache_key = outpoint_key(&payment.txid, 0);
+    let script_hash_row = 32 + utxo.address.len();
+    let output_size = output_size + cache_key.len() + utxo.to_bytes().len() + script_hash_row;
+
+    let spend = Spend {
+        tx_id: payment.txid,
+        vin: 0,
+        height,
+    };
+    let input_value = encode_input_value(spent.value, &spend, time, position).len();
+    let (input_size, input_key, input_script_key) = rows(
+        &spent.address,
+        &spent.script_hash.unwrap().0,
+        &Flow::I,
+        &OutPoint::new(funding.txid, 0),
+        input_value,
+    );
+    let location = TxLocation { height, position };
+    let tx_size = 32 + location.to_bytes().len();
+
+    let undo = UndoRecord {
+        address_keys: vec![output_key, input_key],
+        cache_keys: vec![cache_key],
+        spent_utxos: vec![(outpoint_key(&funding.txid, 0), spent.to_bytes())],
+        txids: vec![payment.txid],
+        script_history_keys: vec![output_script_key, input_script_key],
+        ..Default::default()
+    };
+    let undo_size = undo.to_bytes().len() - UndoRecord::default().to_bytes().len();
+
+    let indexed = IndexedBlock {
+        height,
+        header: block(BlockHash::all_zeros(), height, 0, vec![]).header,
+        sum_txs: vec![payment],
+    };
+    assert_eq!(
+        indexed.write_size(),
+        output_size + input_size + tx_size + undo_size
+    );
+}
@@ -0,0 +1,174 @@
+use bitcoin::hashes::Hash;
+use bitcoin::transaction::OutPoint;
+use bitcoin::{Block, BlockHash, Network};
+use common::{address, block, coinbase, open_indexer, script, spend};
+use index_btc::indexer::Indexer;
+use index_btc::rocksdb::RocksDbIndexer;
+use index_btc::sleddb::SledDbIndexer;
+use index_btc::source::{BlockSource, MemorySource};
+use index_btc::sync::{self, SyncError, SyncSettings, SyncStats};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+mod common;
+
+// The returned sender must outlive syncing, a closed shutdown channel reads as a shutdown request
+fn sync_settings(end_height: u64) -> (SyncSettings, watch::Sender<bool>) {
+    let (shutdown_sender, shutdown) = watch::channel(false);
+    let settings = SyncSettings {
+        parallelism: 1,
+        network: Network::Regtest,
+        zmq_url: None,
+        end_height: Some(end_height),
+        group_commit_blocks: 2,
+        group_commit_bytes: 32 * 1024 * 1024,
+        shutdown,
+    };
+    (settings, shutdown_sender)
+}
+
+// Genesis pays 1, block 1 pays 2 and moves the genesis coins to 3 and 1, block 2 pays 4 and moves 3's coins to 4
+fn chain() -> Vec<Block> {
+    let genesis = block(
+        BlockHash::all_zeros(),
+        0,
+        0,
+        vec![coinbase(0, script(1), 50)],
+    );
+    let genesis_coinbase = OutPoint::new(genesis.txdata[0].compute_txid(), 0);
+    let block_1_spend = spend(genesis_coinbase, vec![(script(3), 30), (script(1), 20)]);
+    let block_1 = block(
+        genesis.block_hash(),
+        1,
+        0,
+        vec![coinbase(1, script(2), 50), block_1_spend.clone()],
+    );
+    let block_2 = block(
+        block_1.block_hash(),
+        2,
+        0,
+        vec![
+            coinbase(2, script(4), 50),
+            spend(
+                OutPoint::new(block_1_spend.compute_txid(), 0),
+                vec![(script(4), 30)],
+            ),
+        ],
+    );
+    vec![genesis, block_1, block_2]
+}
+
+fn balance<I: Indexer>(indexer: &I, seed: u8) -> u64 {
+    indexer.get_balance(&address(&script(seed))).unwrap()
+}
+
+async fn syncs_and_follows_a_reorg<I: Indexer>() {
+    let (mut indexer, _dir) = open_indexer::<I>();
+    let blocks = chain();
+    let block_1_hash = blocks[1].block_hash();
+    let source = Arc::new(MemorySource::new(blocks));
+    let (settings, _shutdown) = sync_settings(2);
+    let mut stats = SyncStats::new();
+
+    let next_height = sync::sync_blocks(&source, &mut indexer, &settings, 0, 2, &mut stats)
+        .await
+        .unwrap();
+    assert_eq!(next_height, 3);
+    assert_eq!(indexer.get_last_height(), 2);
+    assert_eq!(
+        indexer.get_block_hash(2).unwrap(),
+        Some(source.get_block_hash(2).unwrap())
+    );
+    assert_eq!(balance(&indexer, 1), 20);
+    assert_eq!(balance(&indexer, 2), 50);
+    assert_eq!(balance(&indexer, 3), 0);
+    assert_eq!(balance(&indexer, 4), 80);
+
+    // a longer branch replaces block 2, its spend of 3's coins is undone
+    let block_2 = block(block_1_hash, 2, 1, vec![coinbase(2, script(5), 50)]);
+    let block_3 = block(block_2.block_hash(), 3, 0, vec![coinbase(3, script(6), 50)]);
+    source.push(block_2.clone());
+    source.push(block_3.clone());
+    let (settings, _shutdown) = sync_settings(3);
+    sync::follow_tip(&source, &mut indexer, &settings, next_height, &mut stats)
+        .await
+        .unwrap();
+    assert_eq!(indexer.get_last_height(), 3);
+    assert_eq!(
+        indexer.get_block_hash(2).unwrap(),
+        Some(block_2.block_hash())
+    );
+    assert_eq!(
+        indexer.get_block_hash(3).unwrap(),
+        Some(block_3.block_hash())
+    );
+    assert_eq!(balance(&indexer, 1), 20);
+    assert_eq!(balance(&indexer, 3), 30);
+    assert_eq!(balance(&indexer, 4), 0);
+    assert_eq!(balance(&indexer, 5), 50);
+    assert_eq!(balance(&indexer, 6), 50);
+}
+
+async fn starts_at_most_above_the_index<I: Indexer>() {
+    let (mut indexer, _dir) = open_indexer::<I>();
+    let source = Arc::new(MemorySource::new(chain()));
+    let (settings, _shutdown) = sync_settings(2);
+    let mut stats = SyncStats::new();
+    // an empty db starts at genesis
+    assert_eq!(sync::start_height(&mut indexer, None).unwrap(), 0);
+    sync::sync_blocks(&source, &mut indexer, &settings, 0, 2, &mut stats)
+        .await
+        .unwrap();
+    let genesis = indexer.get_header(0).unwrap().unwrap();
+    assert_eq!(genesis.chainwork, Some(genesis.header.work()));
+    assert!(indexer
+        .get_header(2)
+        .unwrap()
+        .unwrap()
+        .median_time
+        .is_some());
+
+    assert!(matches!(
+        sync::start_height(&mut indexer, Some(4)),
+        Err(SyncError::StartHeight {
+            start_height: 4,
+            next_height: 3
+        })
+    ));
+    assert!(sync::start_height(&mut indexer, Some(0)).is_err());
+    assert_eq!(indexer.get_last_height(), 2);
+    assert_eq!(sync::start_height(&mut indexer, None).unwrap(), 3);
+    assert_eq!(sync::start_height(&mut indexer, Some(3)).unwrap(), 3);
+
+    // starting lower rolls back the blocks above, which are then synced again
+    assert_eq!(sync::start_height(&mut indexer, Some(2)).unwrap(), 2);
+    assert_eq!(indexer.get_last_height(), 1);
+    assert_eq!(indexer.get_block_hash(2).unwrap(), None);
+    assert_eq!(balance(&indexer, 3), 30);
+    assert_eq!(balance(&indexer, 4), 0);
+    sync::sync_blocks(&source, &mut indexer, &settings, 2, 2, &mut stats)
+        .await
+        .unwrap();
+    assert_eq!(balance(&indexer, 3), 0);
+    assert_eq!(balance(&indexer, 4), 80);
+}
+
+#[tokio::test]
+async fn syncs_and_follows_a_reorg_rocks_db() {
+    syncs_and_follows_a_reorg::<RocksDbIndexer>().await;
+}
+
+#[tokio::test]
+async fn syncs_and_follows_a_reorg_sled_db() {
+    syncs_and_follows_a_reorg::<SledDbIndexer>().await;
+}
+
+#[tokio::test]
+async fn starts_at_most_above_the_index_rocks_db() {
+    starts_at_most_above_the_index::<RocksDbIndexer>().await;
+}
+
+#[tokio::test]
+async fn starts_at_most_above_the_index_sled_db() {
+    starts_at_most_above_the_index::<SledDbIndexer>().await;
+}
@@ -0,0 +1,187 @@
+use bitcoin::transaction::OutPoint;
+use common::{address, coinbase, index_block, open_indexer, script, spend};
+use futures::{SinkExt, StreamExt};
+use index_btc::indexer::Indexer;
+use index_btc::rocksdb::RocksDbIndexer;
+use index_btc::sleddb::SledDbIndexer;
+use index_btc::websocket;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+mod common;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// Url of the websocket endpoint served on a free port
+async fn serve<I>(indexer: I) -> String
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, websocket::router(indexer)).await });
+    url
+}
+
+async fn send(socket: &mut Socket, request: Value) {
+    socket
+        .send(Message::Text(request.to_string()))
+        .await
+        .unwrap();
+}
+
+async fn receive(socket: &mut Socket) -> Value {
+    let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+        .await
+        .expect("no message pushed")
+        .unwrap()
+        .unwrap();
+    serde_json::from_str(message.to_text().unwrap()).unwrap()
+}
+
+// Address notifications of a commit by address, they come in no particular order
+async fn receive_addresses(socket: &mut Socket, count: usize) -> HashMap<String, Value> {
+    let mut notifications = HashMap::new();
+    for _ in 0..count {
+        let notification = receive(socket).await;
+        assert_eq!(notification["type"], json!("address"));
+        let address = notification["address"].as_str().unwrap().to_string();
+        notifications.insert(address, notification);
+    }
+    notifications
+}
+
+async fn pushes_commits_and_rollbacks<I>()
+where
+    I: Indexer + Clone + Send + Sync + 'static,
+{
+    let (mut indexer, _dir) = open_indexer::<I>();
+    let funding = coinbase(0, script(1), 50);
+    let funding_txid = funding.compute_txid();
+    index_block(&mut indexer, vec![funding]);
+    let genesis_hash = indexer.get_block_hash(0).unwrap().unwrap();
+    let (payer, payee) = (address(&script(1)), address(&script(2)));
+    let (mut socket, _) = connect_async(serve(indexer.clone()).await).await.unwrap();
+
+    send(
+        &mut socket,
+        json!({ "action": "subscribe", "addresses": [payer, payee], "blocks": true }),
+    )
+    .await;
+    assert_eq!(
+        receive(&mut socket).await,
+        json!({
+            "type": "subscribed",
+            "height": 0,
+            "blocks": true,
+            "balances": { payer.clone(): 50, payee.clone(): 0 },
+        })
+    );
+
+    // the change goes to an address nobody subscribed to
+    let payment = spend(
+        OutPoint::new(funding_txid, 0),
+        vec![(script(2), 30), (script(3), 20)],
+    );
+    let payment_txid = payment.compute_txid();
+    index_block(&mut indexer, vec![coinbase(1, script(3), 50), payment]);
+    let hash = indexer.get_block_hash(1).unwrap().unwrap();
+    let time = indexer.get_header(1).unwrap().unwrap().header.time;
+    assert_eq!(
+        receive(&mut socket).await,
+        json!({ "type": "tip", "height": 1, "hash": hash.to_string() })
+    );
+    let notifications = receive_addresses(&mut socket, 2).await;
+    assert_eq!(
+        notifications[&payer],
+        json!({
+            "type": "address",
+            "address": payer,
+            "height": 1,
+            "balance": 0,
+            "delta": -50,
+            "flows": [{
+                "flow": "I",
+                "txid": funding_txid.to_string(),
+                "index": 0,
+                "value": 50,
+                "height": 1,
+                "time": time,
+                "spent_by": { "txid": payment_txid.to_string(), "vin": 0, "height": 1 },
+            }],
+        })
+    );
+    assert_eq!(
+        notifications[&payee],
+        json!({
+            "type": "address",
+            "address": payee,
+            "height": 1,
+            "balance": 30,
+            "delta": 30,
+            "flows": [{
+                "flow": "O",
+                "txid": payment_txid.to_string(),
+                "index": 0,
+                "value": 30,
+                "height": 1,
+                "time": time,
+                "spent_by": null,
+            }],
+        })
+    );
+
+    indexer.rollback(0).unwrap();
+    assert_eq!(
+        receive(&mut socket).await,
+        json!({ "type": "rollback", "height": 0, "hash": genesis_hash.to_string() })
+    );
+    let notifications = receive_addresses(&mut socket, 2).await;
+    assert_eq!(
+        (
+            &notifications[&payer]["balance"],
+            &notifications[&payer]["delta"]
+        ),
+        (&json!(50), &json!(50))
+    );
+    assert_eq!(
+        (
+            &notifications[&payee]["balance"],
+            &notifications[&payee]["delta"]
+        ),
+        (&json!(0), &json!(-30))
+    );
+    assert_eq!(notifications[&payee]["flows"], json!([]));
+
+    // once unsubscribed only the tip is pushed
+    send(
+        &mut socket,
+        json!({ "action": "unsubscribe", "addresses": [payer, payee] }),
+    )
+    .await;
+    assert_eq!(receive(&mut socket).await["type"], json!("unsubscribed"));
+    index_block(
+        &mut indexer,
+        vec![spend(OutPoint::new(funding_txid, 0), vec![(script(2), 50)])],
+    );
+    assert_eq!(receive(&mut socket).await["type"], json!("tip"));
+    send(&mut socket, json!({ "action": "unknown" })).await;
+    assert_eq!(
+        receive(&mut socket).await,
+        json!({ "type": "error", "error": "Action must be subscribe or unsubscribe" })
+    );
+}
+
+#[tokio::test]
+async fn pushes_commits_and_rollbacks_rocks_db() {
+    pushes_commits_and_rollbacks::<RocksDbIndexer>().await;
+}
+
+#[tokio::test]
+async fn pushes_commits_and_rollbacks_sled_db() {
+    pushes_commits_and_rollbacks::<SledDbIndexer>().await;
+}
@@ -0,0 +1,100 @@
+use bitcoin::hashes::Hash;
+use bitcoin::{Block, BlockHash};
+use common::{block, coinbase, script};
+use index_btc::zmq::{self, BlockNotification};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use zeromq::{PubSocket, Socket, SocketSend, ZmqMessage};
+
+mod common;
+
+// Frames as bitcoin-core publishes them : topic, body and a little endian sequence number
+fn message(topic: &str, body: Vec<u8>, sequence: u32) -> ZmqMessage {
+    let mut message = ZmqMessage::from(topic);
+    message.push_back(body.into());
+    message.push_back(sequence.to_le_bytes().to_vec().into());
+    message
+}
+
+fn raw_block(block: &Block, sequence: u32) -> ZmqMessage {
+    message("rawblock", bitcoin::consensus::serialize(block), sequence)
+}
+
+fn hash_block(block: &Block, sequence: u32) -> ZmqMessage {
+    let mut hash = block.block_hash().to_byte_array().to_vec();
+    hash.reverse();
+    message("hashblock", hash, sequence)
+}
+
+// Subscriptions only take effect once the subscriber is connected, so messages are published until one arrives
+async fn publish_until_received(
+    publisher: &mut PubSocket,
+    message: ZmqMessage,
+    notifications: &mut mpsc::Receiver<BlockNotification>,
+) -> BlockNotification {
+    for _ in 0..100 {
+        publisher.send(message.clone()).await.unwrap();
+        if let Ok(notification) =
+            tokio::time::timeout(Duration::from_millis(100), notifications.recv()).await
+        {
+            return notification.expect("zmq subscription closed");
+        }
+    }
+    panic!("no notification received");
+}
+
+#[test]
+fn parses_notifications() {
+    let block = block(
+        BlockHash::all_zeros(),
+        0,
+        0,
+        vec![coinbase(0, script(1), 50)],
+    );
+
+    let notification = zmq::parse_notification(&raw_block(&block, 0)).unwrap();
+    assert!(matches!(notification, Some(BlockNotification::RawBlock(raw)) if raw == block));
+    let notification = zmq::parse_notification(&hash_block(&block, 0)).unwrap();
+    assert!(matches!(notification, Some(BlockNotification::HashBlock)));
+    let notification = zmq::parse_notification(&message("rawtx", vec![0], 0)).unwrap();
+    assert!(notification.is_none());
+    assert!(zmq::parse_notification(&message("rawblock", vec![0, 1], 0)).is_err());
+    assert!(zmq::parse_notification(&ZmqMessage::from("rawblock")).is_err());
+}
+
+#[tokio::test]
+async fn ignores_hash_blocks_once_raw_blocks_are_published() {
+    let mut publisher = PubSocket::new();
+    let endpoint = publisher.bind("tcp://127.0.0.1:0").await.unwrap();
+    let mut notifications = zmq::subscribe_blocks(&endpoint.to_string()).await.unwrap();
+    let block_0 = block(
+        BlockHash::all_zeros(),
+        0,
+        0,
+        vec![coinbase(0, script(1), 50)],
+    );
+    let block_1 = block(block_0.block_hash(), 1, 0, vec![coinbase(1, script(2), 50)]);
+
+    // a node publishing hash blocks only is followed by them
+    let notification =
+        publish_until_received(&mut publisher, hash_block(&block_0, 0), &mut notifications).await;
+    assert!(matches!(notification, BlockNotification::HashBlock));
+    while let Ok(Some(_)) =
+        tokio::time::timeout(Duration::from_millis(200), notifications.recv()).await
+    {}
+
+    let notification =
+        publish_until_received(&mut publisher, raw_block(&block_0, 0), &mut notifications).await;
+    assert!(matches!(notification, BlockNotification::RawBlock(raw) if raw == block_0));
+    while let Ok(Some(_)) =
+        tokio::time::timeout(Duration::from_millis(200), notifications.recv()).await
+    {}
+
+    publisher.send(hash_block(&block_1, 1)).await.unwrap();
+    publisher.send(raw_block(&block_1, 1)).await.unwrap();
+    let notification = tokio::time::timeout(Duration::from_secs(5), notifications.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(notification, BlockNotification::RawBlock(raw) if raw == block_1));
+}